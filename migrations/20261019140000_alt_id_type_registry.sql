@@ -0,0 +1,17 @@
+-- Add migration script here
+CREATE TYPE alt_id_format AS ENUM ('any', 'numeric', 'hexadecimal', 'alphanumeric');
+CREATE TYPE alt_id_case AS ENUM ('preserve', 'lower', 'upper');
+CREATE TABLE alt_id_types(
+    name TEXT PRIMARY KEY,
+    description TEXT NOT NULL,
+    format alt_id_format NOT NULL DEFAULT 'any',
+    pattern TEXT,
+    case_normalization alt_id_case NOT NULL DEFAULT 'preserve',
+    is_unique BOOLEAN NOT NULL DEFAULT false,
+    create_time TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+-- Register the fields that are already in use so existing kiosks keep working
+INSERT INTO alt_id_types (name, description, create_time)
+    SELECT DISTINCT jsonb_object_keys(alt_id_fields), 'Imported from existing alt ID fields', now()
+    FROM users WHERE jsonb_typeof(alt_id_fields) = 'object';
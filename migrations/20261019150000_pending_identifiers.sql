@@ -0,0 +1,11 @@
+-- Add migration script here
+CREATE TABLE pending_identifiers(
+    id SERIAL PRIMARY KEY,
+    alt_id_field TEXT NOT NULL,
+    alt_id_value TEXT NOT NULL,
+    device_token_uuid UUID REFERENCES tokens (uuid),
+    scan_time TIMESTAMP WITH TIME ZONE NOT NULL,
+    claimed_user_uuid UUID REFERENCES users (uuid),
+    claim_time TIMESTAMP WITH TIME ZONE
+);
+CREATE INDEX pending_identifiers_unclaimed_index ON pending_identifiers (alt_id_field, alt_id_value) WHERE claimed_user_uuid IS NULL;
@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE users ADD COLUMN groups TEXT[] NOT NULL DEFAULT '{}';
+CREATE INDEX users_groups_index ON users USING GIN (groups);
@@ -0,0 +1,10 @@
+-- Add migration script here
+ALTER TABLE attendance ADD COLUMN location TEXT;
+ALTER TABLE attendance ADD COLUMN event TEXT;
+ALTER TABLE attendance ADD COLUMN device_token_uuid UUID REFERENCES tokens (uuid);
+CREATE INDEX attendance_in_time_index ON attendance (in_time);
+CREATE INDEX attendance_user_uuid_in_time_index ON attendance (user_uuid, in_time);
+
+-- Unknown scans keep where they came from so the back-filled attendance does too
+ALTER TABLE pending_identifiers ADD COLUMN location TEXT;
+ALTER TABLE pending_identifiers ADD COLUMN event TEXT;
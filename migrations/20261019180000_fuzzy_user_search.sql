@@ -0,0 +1,20 @@
+-- Add migration script here
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+CREATE EXTENSION IF NOT EXISTS unaccent;
+
+-- unaccent() is only STABLE (it depends on the dictionary), so it can't go in an index by itself
+CREATE OR REPLACE FUNCTION immutable_unaccent(TEXT) RETURNS TEXT AS $$
+    SELECT public.unaccent('public.unaccent', $1)
+$$ LANGUAGE sql IMMUTABLE PARALLEL SAFE STRICT;
+
+-- Everything a person might type to find a user, lowercased and without accents
+CREATE OR REPLACE FUNCTION user_search_text(full_name TEXT, email TEXT, alt_id_fields JSONB) RETURNS TEXT AS $$
+    SELECT lower(immutable_unaccent(
+        full_name || ' ' || email || ' ' ||
+        coalesce((SELECT string_agg(value, ' ') FROM jsonb_each_text(
+            CASE WHEN jsonb_typeof(alt_id_fields) = 'object' THEN alt_id_fields ELSE '{}'::jsonb END
+        )), '')
+    ))
+$$ LANGUAGE sql IMMUTABLE PARALLEL SAFE;
+
+CREATE INDEX users_search_text_index ON users USING GIN (user_search_text(full_name, email, alt_id_fields) gin_trgm_ops);
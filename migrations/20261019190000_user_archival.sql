@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE users ADD COLUMN archive_time TIMESTAMP WITH TIME ZONE;
+ALTER TABLE users ADD COLUMN archive_reason TEXT;
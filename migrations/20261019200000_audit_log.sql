@@ -0,0 +1,12 @@
+-- Add migration script here
+CREATE TABLE audit_log(
+    id SERIAL PRIMARY KEY,
+    action TEXT NOT NULL,
+    actor_token_uuid UUID REFERENCES tokens (uuid),
+    -- Not a foreign key, since entries have to outlive users that get erased
+    subject_user_uuid UUID,
+    details JSONB,
+    create_time TIMESTAMP WITH TIME ZONE NOT NULL
+);
+CREATE INDEX audit_log_subject_user_uuid_index ON audit_log (subject_user_uuid);
+CREATE INDEX audit_log_create_time_index ON audit_log (create_time);
@@ -0,0 +1,2 @@
+-- Add migration script here
+CREATE INDEX users_full_name_trgm_index ON users USING GIN (lower(immutable_unaccent(full_name)) gin_trgm_ops);
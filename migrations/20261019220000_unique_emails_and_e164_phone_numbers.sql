@@ -0,0 +1,32 @@
+-- Add migration script here
+
+-- E.164 numbers can be up to 15 digits plus the leading +
+ALTER TABLE users ALTER COLUMN phone_number TYPE VARCHAR(16);
+
+-- Numbers stored before the server normalized them are converted to E.164 at startup
+-- (normalize_stored_phone_numbers), with the same parser and default region as everything else.
+
+DO $$
+DECLARE
+    conflict RECORD;
+    conflict_count INTEGER := 0;
+BEGIN
+    FOR conflict IN
+        SELECT lower(email) AS email, string_agg(uuid::TEXT, ', ') AS uuids
+        FROM users WHERE archive_time IS NULL
+        GROUP BY lower(email) HAVING COUNT(*) > 1
+    LOOP
+        RAISE WARNING 'Users % share the e-mail %', conflict.uuids, conflict.email;
+        conflict_count := conflict_count + 1;
+    END LOOP;
+
+    IF conflict_count > 0 THEN
+        RAISE EXCEPTION '% e-mail addresses belong to more than one active user. Merge or archive the duplicates (the possibleDuplicates query lists them) and run the migration again.', conflict_count;
+    END IF;
+END $$;
+
+-- Archived users are left out so merged duplicates and graduated members don't hold on to addresses
+CREATE UNIQUE INDEX users_email_unique_index ON users (lower(email)) WHERE archive_time IS NULL;
+
+-- Unique e-mails cover everything this did
+ALTER TABLE users DROP CONSTRAINT attendance_unique_constraint;
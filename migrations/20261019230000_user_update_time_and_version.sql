@@ -0,0 +1,13 @@
+-- Add migration script here
+ALTER TABLE users ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
+
+-- Every change to a user goes through here, so update_time and version can't be forgotten
+CREATE OR REPLACE FUNCTION touch_user() RETURNS TRIGGER AS $$
+BEGIN
+    NEW.update_time := now();
+    NEW.version := OLD.version + 1;
+    RETURN NEW;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER users_touch BEFORE UPDATE ON users FOR EACH ROW EXECUTE FUNCTION touch_user();
@@ -0,0 +1,16 @@
+-- Add migration script here
+-- Tells the server about sign-ins and sign-outs so it can push them to subscribers.
+-- NOTIFY waits for the transaction to commit, so nobody hears about a scan that got rolled back.
+CREATE OR REPLACE FUNCTION notify_attendance_event() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        PERFORM pg_notify('attendance_events', json_build_object('id', NEW.id, 'kind', 'SIGN_IN')::text);
+    ELSIF OLD.out_time IS NULL AND NEW.out_time IS NOT NULL THEN
+        PERFORM pg_notify('attendance_events', json_build_object('id', NEW.id, 'kind', 'SIGN_OUT')::text);
+    END IF;
+    RETURN NEW;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER attendance_notify AFTER INSERT OR UPDATE OF out_time ON attendance
+    FOR EACH ROW EXECUTE FUNCTION notify_attendance_event();
@@ -0,0 +1,3 @@
+-- Add migration script here
+-- presentNow only looks at entries nobody has signed out of yet, which is a tiny slice of the table
+CREATE INDEX attendance_open_location_in_time_index ON attendance (location, in_time) WHERE out_time IS NULL;
@@ -0,0 +1,31 @@
+-- Add migration script here
+-- A roll call snapshots who was signed in when it started, so people can be checked off at the
+-- assembly point even after the attendance rows change
+CREATE TABLE roll_calls(
+    id SERIAL PRIMARY KEY,
+    location TEXT,
+    started_by_token_uuid UUID REFERENCES tokens (uuid),
+    start_time TIMESTAMP WITH TIME ZONE NOT NULL,
+    end_time TIMESTAMP WITH TIME ZONE
+);
+
+CREATE TABLE roll_call_entries(
+    roll_call_id INTEGER NOT NULL REFERENCES roll_calls (id) ON DELETE CASCADE,
+    user_uuid UUID NOT NULL REFERENCES users (uuid) ON DELETE CASCADE,
+    in_time TIMESTAMP WITH TIME ZONE NOT NULL,
+    location TEXT,
+    accounted_time TIMESTAMP WITH TIME ZONE,
+    accounted_by_token_uuid UUID REFERENCES tokens (uuid),
+    PRIMARY KEY (roll_call_id, user_uuid)
+);
+
+-- Same idea as attendance_notify, so every marshal's list stays in sync
+CREATE OR REPLACE FUNCTION notify_roll_call_event() RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM pg_notify('roll_call_events', json_build_object('roll_call_id', NEW.roll_call_id, 'user_uuid', NEW.user_uuid)::text);
+    RETURN NEW;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER roll_call_entries_notify AFTER UPDATE OF accounted_time ON roll_call_entries
+    FOR EACH ROW EXECUTE FUNCTION notify_roll_call_event();
@@ -0,0 +1,75 @@
+-- Add migration script here
+CREATE TABLE webhooks(
+    id SERIAL PRIMARY KEY,
+    url TEXT NOT NULL,
+    description TEXT NOT NULL DEFAULT '',
+    -- Like 'attendance.created'. See WebhookEventType.
+    event_types TEXT[] NOT NULL,
+    -- Used to sign every delivery, so receivers can tell it really came from us
+    secret TEXT NOT NULL,
+    active BOOLEAN NOT NULL DEFAULT TRUE,
+    create_time TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+-- The outbox. Rows go in in the same transaction as the change they describe, so a committed
+-- change always gets delivered (eventually), and a rolled-back one never does.
+CREATE TYPE webhook_delivery_status AS ENUM ('pending', 'delivered', 'failed');
+CREATE TABLE webhook_deliveries(
+    id BIGSERIAL PRIMARY KEY,
+    webhook_id INTEGER NOT NULL REFERENCES webhooks (id) ON DELETE CASCADE,
+    event_type TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    status webhook_delivery_status NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_time TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    last_attempt_time TIMESTAMP WITH TIME ZONE,
+    last_response_status INTEGER,
+    last_error TEXT,
+    delivered_time TIMESTAMP WITH TIME ZONE,
+    create_time TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+CREATE INDEX webhook_deliveries_due_index ON webhook_deliveries (next_attempt_time) WHERE status = 'pending';
+CREATE INDEX webhook_deliveries_webhook_id_index ON webhook_deliveries (webhook_id, id);
+
+CREATE OR REPLACE FUNCTION enqueue_webhook_event(event_type TEXT, data JSONB) RETURNS VOID AS $$
+BEGIN
+    INSERT INTO webhook_deliveries (webhook_id, event_type, payload)
+    SELECT id, event_type, jsonb_build_object('event', event_type, 'time', now(), 'data', data)
+    FROM webhooks WHERE active AND event_type = ANY(event_types);
+END
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION attendance_webhook_events() RETURNS TRIGGER AS $$
+DECLARE
+    data JSONB := jsonb_build_object(
+        'attendance', to_jsonb(NEW),
+        'user', (SELECT jsonb_build_object('uuid', uuid, 'full_name', full_name, 'email', email, 'groups', groups)
+                 FROM users WHERE uuid = NEW.user_uuid));
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        PERFORM enqueue_webhook_event('attendance.created', data);
+    ELSIF OLD.out_time IS NULL AND NEW.out_time IS NOT NULL THEN
+        PERFORM enqueue_webhook_event('attendance.closed', data);
+    END IF;
+    RETURN NEW;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER attendance_webhooks AFTER INSERT OR UPDATE OF out_time ON attendance
+    FOR EACH ROW EXECUTE FUNCTION attendance_webhook_events();
+
+CREATE OR REPLACE FUNCTION user_webhook_events() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        PERFORM enqueue_webhook_event('user.created', jsonb_build_object('user', to_jsonb(NEW)));
+    ELSIF OLD.archive_time IS NULL AND NEW.archive_time IS NOT NULL THEN
+        PERFORM enqueue_webhook_event('user.archived', jsonb_build_object('user', to_jsonb(NEW)));
+    ELSE
+        PERFORM enqueue_webhook_event('user.updated', jsonb_build_object('user', to_jsonb(NEW)));
+    END IF;
+    RETURN NEW;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER user_webhooks AFTER INSERT OR UPDATE ON users
+    FOR EACH ROW EXECUTE FUNCTION user_webhook_events();
@@ -0,0 +1,100 @@
+-- Add migration script here
+CREATE TYPE notification_kind AS ENUM ('sign_out', 'weekly_summary');
+
+-- {{placeholders}} are filled in from the notification's data when it's sent. See
+-- NotificationKind::placeholders for which ones each kind has.
+CREATE TABLE notification_templates(
+    kind notification_kind PRIMARY KEY,
+    subject TEXT NOT NULL,
+    body TEXT NOT NULL,
+    update_time TIMESTAMP WITH TIME ZONE
+);
+INSERT INTO notification_templates (kind, subject, body) VALUES
+    ('sign_out', '{{full_name}} signed out',
+'{{full_name}} signed out of {{location}} at {{out_time}}, after arriving at {{in_time}} ({{hours}} hours).
+
+To stop getting these e-mails, visit {{unsubscribe_url}}
+'),
+    ('weekly_summary', '{{member_count}} members attended less than {{threshold_hours}} hours this week',
+'Hi {{recipient_name}},
+
+These members attended less than {{threshold_hours}} hours between {{period_start}} and {{period_end}}:
+
+{{members}}
+
+To stop getting these e-mails, visit {{unsubscribe_url}}
+');
+
+-- Nobody gets anything unless they (or an administrator) ask for it
+CREATE TABLE notification_preferences(
+    user_uuid UUID PRIMARY KEY REFERENCES users (uuid) ON DELETE CASCADE,
+    -- Send this user's own e-mail a notice whenever they sign out
+    sign_out_notices BOOLEAN NOT NULL DEFAULT FALSE,
+    -- Parents and guardians, who always get this user's sign out notices
+    guardian_emails TEXT[] NOT NULL DEFAULT '{}',
+    -- For mentors: the Sunday list of members below the attendance threshold
+    weekly_summary BOOLEAN NOT NULL DEFAULT FALSE,
+    -- Only list members in these groups in the weekly summary. Empty means everyone.
+    summary_groups TEXT[] NOT NULL DEFAULT '{}'
+);
+
+-- Addresses that unsubscribed. These win over any preference.
+CREATE TABLE notification_opt_outs(
+    email TEXT PRIMARY KEY CHECK (email = lower(email)),
+    opt_out_time TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+-- The outbox, like webhook_deliveries
+CREATE TYPE notification_status AS ENUM ('pending', 'sent', 'failed');
+CREATE TABLE notifications(
+    id BIGSERIAL PRIMARY KEY,
+    kind notification_kind NOT NULL,
+    recipient TEXT NOT NULL,
+    -- Placeholder values for the template
+    data JSONB NOT NULL,
+    -- What was actually sent, once it has been
+    subject TEXT,
+    body TEXT,
+    status notification_status NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_time TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    last_error TEXT,
+    sent_time TIMESTAMP WITH TIME ZONE,
+    create_time TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    -- The unsubscribe link in the e-mail opts out whoever this was sent to
+    unsubscribe_token UUID NOT NULL UNIQUE DEFAULT gen_random_uuid()
+);
+CREATE INDEX notifications_due_index ON notifications (next_attempt_time) WHERE status = 'pending';
+
+-- So the weekly summary goes out once a week, even with more than one server running
+CREATE TABLE weekly_summary_runs(
+    week_start DATE PRIMARY KEY,
+    run_time TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+CREATE OR REPLACE FUNCTION attendance_sign_out_notifications() RETURNS TRIGGER AS $$
+BEGIN
+    IF OLD.out_time IS NULL AND NEW.out_time IS NOT NULL THEN
+        INSERT INTO notifications (kind, recipient, data)
+        SELECT 'sign_out', recipient, jsonb_build_object(
+            'full_name', u.full_name,
+            'location', coalesce(NEW.location, 'the building'),
+            'in_time', to_char(NEW.in_time AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI "UTC"'),
+            'out_time', to_char(NEW.out_time AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI "UTC"'),
+            'hours', round((extract(epoch FROM NEW.out_time - NEW.in_time) / 3600)::NUMERIC, 1)::TEXT)
+        FROM users u
+        JOIN notification_preferences p ON p.user_uuid = u.uuid
+        CROSS JOIN LATERAL (
+            SELECT DISTINCT lower(email) AS recipient FROM unnest(
+                p.guardian_emails || CASE WHEN p.sign_out_notices THEN ARRAY[u.email] ELSE '{}' END
+            ) AS email
+        ) recipients
+        WHERE u.uuid = NEW.user_uuid
+        AND recipient NOT IN (SELECT email FROM notification_opt_outs);
+    END IF;
+    RETURN NEW;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER attendance_sign_out_notifications AFTER UPDATE OF out_time ON attendance
+    FOR EACH ROW EXECUTE FUNCTION attendance_sign_out_notifications();
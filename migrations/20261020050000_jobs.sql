@@ -0,0 +1,61 @@
+-- Add migration script here
+CREATE TYPE job_kind AS ENUM ('weekly_summary', 'close_open_sessions', 'expire_tokens', 'prune_logs');
+
+CREATE TABLE jobs(
+    kind job_kind PRIMARY KEY,
+    -- Five field cron expression (minute hour day-of-month month day-of-week), in UTC
+    schedule TEXT NOT NULL,
+    enabled BOOLEAN NOT NULL DEFAULT TRUE,
+    -- Worked out by the server from the schedule. NULL until then.
+    next_run_time TIMESTAMP WITH TIME ZONE
+);
+INSERT INTO jobs (kind, schedule) VALUES
+    ('weekly_summary', '0 18 * * 0'),
+    ('close_open_sessions', '0 4 * * *'),
+    ('expire_tokens', '15 3 * * *'),
+    ('prune_logs', '30 3 * * *');
+
+CREATE TYPE job_run_status AS ENUM ('running', 'succeeded', 'failed');
+CREATE TABLE job_runs(
+    id BIGSERIAL PRIMARY KEY,
+    kind job_kind NOT NULL,
+    -- Set when an administrator ran it by hand
+    triggered_by_token_uuid UUID REFERENCES tokens (uuid),
+    status job_run_status NOT NULL DEFAULT 'running',
+    -- What it did, or why it failed
+    result TEXT,
+    start_time TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    end_time TIMESTAMP WITH TIME ZONE
+);
+CREATE INDEX job_runs_kind_index ON job_runs (kind, id);
+
+-- The weekly_summary job replaces this
+DROP TABLE weekly_summary_runs;
+
+-- Sessions nobody signed out of get closed by close_open_sessions, without counting any time
+ALTER TABLE attendance ADD COLUMN auto_closed BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Parents don't need to hear about those
+CREATE OR REPLACE FUNCTION attendance_sign_out_notifications() RETURNS TRIGGER AS $$
+BEGIN
+    IF OLD.out_time IS NULL AND NEW.out_time IS NOT NULL AND NOT NEW.auto_closed THEN
+        INSERT INTO notifications (kind, recipient, data)
+        SELECT 'sign_out', recipient, jsonb_build_object(
+            'full_name', u.full_name,
+            'location', coalesce(NEW.location, 'the building'),
+            'in_time', to_char(NEW.in_time AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI "UTC"'),
+            'out_time', to_char(NEW.out_time AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI "UTC"'),
+            'hours', round((extract(epoch FROM NEW.out_time - NEW.in_time) / 3600)::NUMERIC, 1)::TEXT)
+        FROM users u
+        JOIN notification_preferences p ON p.user_uuid = u.uuid
+        CROSS JOIN LATERAL (
+            SELECT DISTINCT lower(email) AS recipient FROM unnest(
+                p.guardian_emails || CASE WHEN p.sign_out_notices THEN ARRAY[u.email] ELSE '{}' END
+            ) AS email
+        ) recipients
+        WHERE u.uuid = NEW.user_uuid
+        AND recipient NOT IN (SELECT email FROM notification_opt_outs);
+    END IF;
+    RETURN NEW;
+END
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,41 @@
+-- Add migration script here
+-- Meetings and other things on the team calendar. Most come from importing the calendar's ICS
+-- file, and they go back out in the /calendar feed.
+CREATE TABLE events(
+    id SERIAL PRIMARY KEY,
+    -- The iCalendar UID. Imports match on it (and recurrence_id), so importing the same file
+    -- again updates events instead of duplicating them.
+    uid TEXT NOT NULL,
+    -- For a changed occurrence of a recurring event, its RECURRENCE-ID line as it was imported.
+    -- Empty for everything else.
+    recurrence_id TEXT NOT NULL DEFAULT '',
+    summary TEXT NOT NULL,
+    description TEXT NOT NULL DEFAULT '',
+    location TEXT,
+    start_time TIMESTAMP WITH TIME ZONE NOT NULL,
+    end_time TIMESTAMP WITH TIME ZONE NOT NULL CHECK (end_time >= start_time),
+    -- All day events cover whole days, whatever time zone you're in
+    all_day BOOLEAN NOT NULL DEFAULT FALSE,
+    -- The time zone the imported times were in, like America/Toronto. The feed gives times in
+    -- it so recurring events stay at the same local time across daylight saving changes.
+    time_zone TEXT,
+    -- RRULE, RDATE and EXDATE lines, exactly as imported. These aren't expanded into
+    -- occurrences; they're only passed along in the feed.
+    recurrence TEXT[] NOT NULL DEFAULT '{}',
+    cancelled BOOLEAN NOT NULL DEFAULT FALSE,
+    -- iCalendar's revision number. Calendar apps ignore updates that don't raise it.
+    sequence INTEGER NOT NULL DEFAULT 0,
+    create_time TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    update_time TIMESTAMP WITH TIME ZONE,
+    UNIQUE (uid, recurrence_id)
+);
+CREATE INDEX events_start_time_index ON events (start_time);
+
+-- Secret links to the /calendar feed. Calendar apps can't send a Token header, so the key goes
+-- in the URL instead, and each person or app gets their own so it can be revoked.
+CREATE TABLE calendar_feed_keys(
+    key UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    description TEXT NOT NULL,
+    create_time TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    last_access_time TIMESTAMP WITH TIME ZONE
+);
@@ -0,0 +1,8 @@
+-- Add migration script here
+-- QR code badges carry a serial number and only the newest one works, so a lost badge can be
+-- replaced. Users without a row here are still on their first badge (serial 0).
+CREATE TABLE badges(
+    user_uuid UUID PRIMARY KEY REFERENCES users (uuid) ON DELETE CASCADE,
+    serial INTEGER NOT NULL DEFAULT 0,
+    reissue_time TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
@@ -0,0 +1,20 @@
+-- Add migration script here
+-- Rotating codes shown on a screen at a location or event. Signing in with one means you could
+-- see the screen in the last minute or so, which a QR code texted to someone at home doesn't.
+CREATE TABLE checkin_codes(
+    id SERIAL PRIMARY KEY,
+    description TEXT NOT NULL,
+    -- Copied onto attendance for everyone who signs in with the code
+    location TEXT,
+    event TEXT,
+    -- TOTP style: the code is an HMAC of the current time step with this, so it never leaves
+    -- the server. 32 random bytes.
+    secret BYTEA NOT NULL DEFAULT uuid_send(gen_random_uuid()) || uuid_send(gen_random_uuid()),
+    period_seconds INTEGER NOT NULL DEFAULT 30 CHECK (period_seconds BETWEEN 10 AND 600),
+    digits INTEGER NOT NULL DEFAULT 6 CHECK (digits BETWEEN 4 AND 8),
+    -- For the page that shows the code. Screens can't send a Token header, so like the calendar
+    -- feed the key goes in the URL.
+    display_key UUID NOT NULL UNIQUE DEFAULT gen_random_uuid(),
+    create_time TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    CHECK (location IS NOT NULL OR event IS NOT NULL)
+);
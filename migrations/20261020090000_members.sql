@@ -0,0 +1,23 @@
+-- Add migration script here
+-- Members get their own tokens, to see their own hours and send in excuses
+ALTER TYPE token_capability ADD VALUE 'member';
+
+-- The user a member token belongs to. It's in the token itself too, this is just so admins can
+-- tell whose it is.
+ALTER TABLE tokens ADD COLUMN user_uuid UUID REFERENCES users (uuid) ON DELETE SET NULL;
+
+CREATE TYPE excuse_status AS ENUM ('pending', 'approved', 'rejected');
+-- "I can't make it on Saturday", sent in by members and approved by an administrator
+CREATE TABLE excuses(
+    id SERIAL PRIMARY KEY,
+    user_uuid UUID NOT NULL REFERENCES users (uuid) ON DELETE CASCADE,
+    -- The day they'll miss (or missed)
+    date DATE NOT NULL,
+    reason TEXT NOT NULL,
+    status excuse_status NOT NULL DEFAULT 'pending',
+    create_time TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    review_time TIMESTAMP WITH TIME ZONE,
+    reviewed_by_token_uuid UUID REFERENCES tokens (uuid),
+    review_note TEXT
+);
+CREATE INDEX excuses_user_uuid_index ON excuses (user_uuid, date);
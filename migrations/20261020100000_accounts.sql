@@ -0,0 +1,25 @@
+-- Add migration script here
+-- People (mentors, mostly) who log in to the dashboard themselves instead of being handed a token
+CREATE TABLE accounts(
+    uuid UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    -- Always lowercase
+    email TEXT NOT NULL UNIQUE,
+    full_name TEXT NOT NULL,
+    -- What the tokens they get from logging in can do
+    capability token_capability NOT NULL,
+    -- For member accounts, the member they are
+    user_uuid UUID REFERENCES users (uuid) ON DELETE CASCADE,
+    -- Argon2id, as a PHC string. They can't log in until an administrator sets one.
+    password_hash TEXT,
+    disabled BOOLEAN NOT NULL DEFAULT FALSE,
+    create_time TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    last_login_time TIMESTAMP WITH TIME ZONE,
+    CHECK ((capability = 'member') = (user_uuid IS NOT NULL))
+);
+
+-- Every login gets its own token, like a device does. The access tokens handed out for it only
+-- last a few minutes, and the refresh token (only its SHA-256 is kept) gets new ones until the
+-- token expires or is revoked.
+ALTER TABLE tokens ADD COLUMN account_uuid UUID REFERENCES accounts (uuid) ON DELETE SET NULL;
+ALTER TABLE tokens ADD COLUMN refresh_token_hash BYTEA UNIQUE;
+ALTER TABLE tokens ADD COLUMN revoke_time TIMESTAMP WITH TIME ZONE;
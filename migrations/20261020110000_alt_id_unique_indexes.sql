@@ -0,0 +1,42 @@
+-- Add migration script here
+-- Unique alt ID types get a unique index on users, so two requests checking at the same time
+-- can't both give out the same value. The index follows is_unique as it's changed.
+CREATE FUNCTION alt_id_unique_index_name(name TEXT) RETURNS TEXT AS $$
+    SELECT 'users_alt_id_' || md5(name)
+$$ LANGUAGE sql IMMUTABLE;
+
+CREATE FUNCTION sync_alt_id_unique_index() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'UPDATE' AND OLD.is_unique = NEW.is_unique THEN
+        RETURN NULL;
+    END IF;
+    IF TG_OP <> 'INSERT' THEN
+        EXECUTE format('DROP INDEX IF EXISTS %I', alt_id_unique_index_name(OLD.name));
+    END IF;
+    IF TG_OP <> 'DELETE' AND NEW.is_unique THEN
+        EXECUTE format('CREATE UNIQUE INDEX %I ON users ((alt_id_fields->>%L))',
+            alt_id_unique_index_name(NEW.name), NEW.name);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER alt_id_types_unique_index AFTER INSERT OR UPDATE OF is_unique OR DELETE ON alt_id_types
+    FOR EACH ROW EXECUTE FUNCTION sync_alt_id_unique_index();
+
+-- Types that are already unique. Values that were given out twice have to be fixed (with
+-- mergeUsers, usually) before this can run.
+DO $$
+DECLARE
+    alt_id_type RECORD;
+BEGIN
+    FOR alt_id_type IN SELECT name FROM alt_id_types WHERE is_unique LOOP
+        BEGIN
+            EXECUTE format('CREATE UNIQUE INDEX %I ON users ((alt_id_fields->>%L))',
+                alt_id_unique_index_name(alt_id_type.name), alt_id_type.name);
+        EXCEPTION WHEN unique_violation THEN
+            RAISE EXCEPTION 'More than one user has the same value for unique alt ID field ''%''', alt_id_type.name;
+        END;
+    END LOOP;
+END;
+$$;
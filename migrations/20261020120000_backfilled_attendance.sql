@@ -0,0 +1,16 @@
+-- Add migration script here
+-- Attendance filled in from scans made before a card was enrolled. Those scans are old news,
+-- so subscribers, webhooks and e-mail don't hear about them.
+ALTER TABLE attendance ADD COLUMN backfilled BOOLEAN NOT NULL DEFAULT FALSE;
+
+DROP TRIGGER attendance_notify ON attendance;
+CREATE TRIGGER attendance_notify AFTER INSERT OR UPDATE OF out_time ON attendance
+    FOR EACH ROW WHEN (NOT NEW.backfilled) EXECUTE FUNCTION notify_attendance_event();
+
+DROP TRIGGER attendance_webhooks ON attendance;
+CREATE TRIGGER attendance_webhooks AFTER INSERT OR UPDATE OF out_time ON attendance
+    FOR EACH ROW WHEN (NOT NEW.backfilled) EXECUTE FUNCTION attendance_webhook_events();
+
+DROP TRIGGER attendance_sign_out_notifications ON attendance;
+CREATE TRIGGER attendance_sign_out_notifications AFTER UPDATE OF out_time ON attendance
+    FOR EACH ROW WHEN (NOT NEW.backfilled) EXECUTE FUNCTION attendance_sign_out_notifications();
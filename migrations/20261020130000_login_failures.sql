@@ -0,0 +1,9 @@
+-- Add migration script here
+-- Failed logins, by the e-mail that was tried, so passwords can't be guessed without limit.
+-- E-mails without an account are counted the same way, so a lockout doesn't give away who has one.
+CREATE TABLE login_failures(
+    id SERIAL PRIMARY KEY,
+    email TEXT NOT NULL,
+    attempt_time TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+CREATE INDEX login_failures_email_index ON login_failures (email, attempt_time);
@@ -0,0 +1,29 @@
+-- Add migration script here
+-- Nobody signed out of sessions close_open_sessions closed, so subscribers and webhooks don't hear
+-- about a sign-out either
+CREATE OR REPLACE FUNCTION notify_attendance_event() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        PERFORM pg_notify('attendance_events', json_build_object('id', NEW.id, 'kind', 'SIGN_IN')::text);
+    ELSIF OLD.out_time IS NULL AND NEW.out_time IS NOT NULL AND NOT NEW.auto_closed THEN
+        PERFORM pg_notify('attendance_events', json_build_object('id', NEW.id, 'kind', 'SIGN_OUT')::text);
+    END IF;
+    RETURN NEW;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION attendance_webhook_events() RETURNS TRIGGER AS $$
+DECLARE
+    data JSONB := jsonb_build_object(
+        'attendance', to_jsonb(NEW),
+        'user', (SELECT jsonb_build_object('uuid', uuid, 'full_name', full_name, 'email', email, 'groups', groups)
+                 FROM users WHERE uuid = NEW.user_uuid));
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        PERFORM enqueue_webhook_event('attendance.created', data);
+    ELSIF OLD.out_time IS NULL AND NEW.out_time IS NOT NULL AND NOT NEW.auto_closed THEN
+        PERFORM enqueue_webhook_event('attendance.closed', data);
+    END IF;
+    RETURN NEW;
+END
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,30 @@
+-- Add migration script here
+-- What receivers get to see about a user. Alt IDs and internal bookkeeping (update_time, version)
+-- stay out.
+CREATE OR REPLACE FUNCTION user_webhook_fields(u users) RETURNS JSONB AS $$
+    SELECT jsonb_build_object('uuid', u.uuid, 'full_name', u.full_name, 'email', u.email,
+        'phone_number', u.phone_number, 'groups', u.groups, 'archive_time', u.archive_time)
+$$ LANGUAGE sql STABLE;
+
+CREATE OR REPLACE FUNCTION user_webhook_events() RETURNS TRIGGER AS $$
+DECLARE
+    changes JSONB;
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        PERFORM enqueue_webhook_event('user.created', jsonb_build_object('user', user_webhook_fields(NEW)));
+    ELSIF OLD.archive_time IS NULL AND NEW.archive_time IS NOT NULL THEN
+        PERFORM enqueue_webhook_event('user.archived', jsonb_build_object('user', user_webhook_fields(NEW)));
+    ELSE
+        -- Only the fields that changed, so an update that doesn't touch any of them sends nothing
+        SELECT jsonb_object_agg(new_field.key, new_field.value) INTO changes
+        FROM jsonb_each(user_webhook_fields(NEW)) new_field
+        JOIN jsonb_each(user_webhook_fields(OLD)) old_field USING (key)
+        WHERE new_field.value IS DISTINCT FROM old_field.value;
+
+        IF changes IS NOT NULL THEN
+            PERFORM enqueue_webhook_event('user.updated', jsonb_build_object('uuid', NEW.uuid, 'changes', changes));
+        END IF;
+    END IF;
+    RETURN NEW;
+END
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,42 @@
+-- Add migration script here
+-- Who a notification is about (the member for sign out notices, the mentor for summaries), so
+-- eraseUser can find them. Older rows are found by address instead.
+ALTER TABLE notifications ADD COLUMN user_uuid UUID;
+CREATE INDEX notifications_user_uuid_index ON notifications (user_uuid);
+
+-- eraseUser turns this on for its transaction, so the data it's removing isn't sent out again
+CREATE OR REPLACE FUNCTION enqueue_webhook_event(event_type TEXT, data JSONB) RETURNS VOID AS $$
+BEGIN
+    IF current_setting('attendance_rs.suppress_webhooks', true) = 'on' THEN
+        RETURN;
+    END IF;
+    INSERT INTO webhook_deliveries (webhook_id, event_type, payload)
+    SELECT id, event_type, jsonb_build_object('event', event_type, 'time', now(), 'data', data)
+    FROM webhooks WHERE active AND event_type = ANY(event_types);
+END
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION attendance_sign_out_notifications() RETURNS TRIGGER AS $$
+BEGIN
+    IF OLD.out_time IS NULL AND NEW.out_time IS NOT NULL AND NOT NEW.auto_closed THEN
+        INSERT INTO notifications (kind, recipient, data, user_uuid)
+        SELECT 'sign_out', recipient, jsonb_build_object(
+            'full_name', u.full_name,
+            'location', coalesce(NEW.location, 'the building'),
+            'in_time', to_char(NEW.in_time AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI "UTC"'),
+            'out_time', to_char(NEW.out_time AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI "UTC"'),
+            'hours', round((extract(epoch FROM NEW.out_time - NEW.in_time) / 3600)::NUMERIC, 1)::TEXT),
+            u.uuid
+        FROM users u
+        JOIN notification_preferences p ON p.user_uuid = u.uuid
+        CROSS JOIN LATERAL (
+            SELECT DISTINCT lower(email) AS recipient FROM unnest(
+                p.guardian_emails || CASE WHEN p.sign_out_notices THEN ARRAY[u.email] ELSE '{}' END
+            ) AS email
+        ) recipients
+        WHERE u.uuid = NEW.user_uuid
+        AND recipient NOT IN (SELECT email FROM notification_opt_outs);
+    END IF;
+    RETURN NEW;
+END
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,121 @@
+// Accounts for people who log in with an e-mail and password, and the refresh tokens that both
+// logins and long-lived device tokens use. Each login is a token like any other, except the JWTs
+// for it only last a few minutes, and the refresh token is what gets new ones.
+
+use actix_web::{error::BlockingError, web};
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use sqlx::postgres::PgPool;
+
+// How long a JWT from a refresh token lasts. A stolen one isn't much use for long.
+pub const ACCESS_TOKEN_MINUTES: i64 = 15;
+// How long a login lasts without being used. Every refresh starts this over.
+pub const REFRESH_TOKEN_DAYS: i64 = 30;
+const MIN_PASSWORD_LENGTH: usize = 10;
+// After this many wrong passwords for an e-mail, it can't log in until the oldest of them is
+// LOGIN_LOCKOUT_MINUTES old
+const MAX_FAILED_LOGINS: i64 = 5;
+pub const LOGIN_LOCKOUT_MINUTES: i32 = 15;
+
+// Argon2id with a random salt, as a PHC string ($argon2id$v=19$...). Argon2 is slow on purpose,
+// so it runs on the blocking thread pool instead of holding up a worker.
+pub async fn hash_password(password: String) -> Result<String, String> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(format!(
+            "Passwords need to be at least {} characters long",
+            MIN_PASSWORD_LENGTH
+        ));
+    }
+    web::block(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| match e {
+        BlockingError::Error(e) => e,
+        BlockingError::Canceled => "Hashing the password was canceled".to_string(),
+    })
+}
+
+pub async fn verify_password(password: String, password_hash: Option<String>) -> bool {
+    web::block(move || -> Result<bool, ()> {
+        Ok(match password_hash.as_deref().map(PasswordHash::new) {
+            Some(Ok(hash)) => Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok(),
+            Some(Err(_)) => false,
+            // Hash it anyway, so nobody can tell from how long it took that there's no such account
+            None => {
+                let salt = SaltString::generate(&mut OsRng);
+                let _ = Argon2::default().hash_password(password.as_bytes(), &salt);
+                false
+            }
+        })
+    })
+    .await
+    .unwrap_or(false)
+}
+
+// Refuses before the password is even checked, so guessing stops working after a few tries
+pub async fn check_login_attempts(pool: &PgPool, email: &str) -> Result<(), async_graphql::Error> {
+    let failures = sqlx::query!(
+        r#"SELECT count(*) as "count!" FROM login_failures
+        WHERE email = lower($1) AND attempt_time > now() - make_interval(mins => $2)"#,
+        email.trim(),
+        LOGIN_LOCKOUT_MINUTES
+    )
+    .fetch_one(pool)
+    .await?
+    .count;
+    if failures >= MAX_FAILED_LOGINS {
+        return Err(async_graphql::Error::new(format!(
+            "Too many wrong passwords. Please try again in {} minutes.",
+            LOGIN_LOCKOUT_MINUTES
+        )));
+    }
+    Ok(())
+}
+
+pub async fn record_login_attempt(
+    pool: &PgPool,
+    email: &str,
+    succeeded: bool,
+) -> Result<(), sqlx::Error> {
+    if succeeded {
+        sqlx::query!(
+            "DELETE FROM login_failures WHERE email = lower($1)",
+            email.trim()
+        )
+        .execute(pool)
+        .await?;
+    } else {
+        sqlx::query!(
+            "INSERT INTO login_failures (email) VALUES (lower($1))",
+            email.trim()
+        )
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
+// A new refresh token, and what gets stored for it
+pub fn new_refresh_token() -> (String, Vec<u8>) {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let refresh_token = hex::encode(bytes);
+    let hash = hash_refresh_token(&refresh_token);
+    (refresh_token, hash)
+}
+
+// Refresh tokens are random enough that a plain hash is fine, and we can look them up by it
+pub fn hash_refresh_token(refresh_token: &str) -> Vec<u8> {
+    Sha256::digest(refresh_token.trim().as_bytes()).to_vec()
+}
@@ -0,0 +1,745 @@
+// iCalendar (RFC 5545) import and the /calendar.ics feed, both on top of the events table
+
+use crate::graphql_schema::record_audit;
+use crate::import::ImportAction;
+use crate::tables::Event;
+use actix_web::{web, HttpResponse};
+use async_graphql::*;
+use chrono::{Duration, LocalResult, NaiveDate, NaiveDateTime, TimeZone};
+use chrono_tz::Tz;
+use serde::Deserialize;
+use sqlx::{
+    postgres::PgPool,
+    types::{
+        chrono::{DateTime, Utc},
+        Uuid,
+    },
+};
+use std::{collections::HashMap, sync::Arc};
+
+// Lines in the feed are folded to this many bytes, not counting the line break
+const MAX_LINE_LENGTH: usize = 75;
+
+#[derive(SimpleObject)]
+pub struct CalendarImportResult {
+    pub uid: String,
+    pub summary: String,
+    pub start_time: Option<DateTime<Utc>>,
+    // Never Conflict. Invalid events are skipped and the rest are still imported.
+    pub action: ImportAction,
+    // What's wrong with the event, or what an update changes
+    pub messages: Vec<String>,
+}
+
+#[derive(SimpleObject)]
+pub struct CalendarImportReport {
+    pub events: Vec<CalendarImportResult>,
+    pub creates: i32,
+    pub updates: i32,
+    pub unchanged: i32,
+    pub invalid: i32,
+    // False for dry runs
+    pub applied: bool,
+}
+
+// A property like DTSTART;TZID=America/Toronto:20211020T180000
+struct Property {
+    name: String,
+    params: HashMap<String, String>,
+    value: String,
+    // The whole line, unfolded, for passing along as-is
+    line: String,
+}
+
+// An event as read from the file, before it's matched against what's already stored
+struct ImportedEvent {
+    uid: String,
+    recurrence_id: String,
+    summary: String,
+    description: String,
+    location: Option<String>,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    all_day: bool,
+    time_zone: Option<Tz>,
+    recurrence: Vec<String>,
+    cancelled: bool,
+    sequence: i32,
+}
+
+// Continuation lines start with a space or a tab
+fn unfold(data: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in data.lines() {
+        match (
+            line.strip_prefix(' ').or_else(|| line.strip_prefix('\t')),
+            lines.last_mut(),
+        ) {
+            (Some(continuation), Some(last)) => last.push_str(continuation),
+            _ if line.trim().is_empty() => {}
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+fn parse_property(line: &str) -> Option<Property> {
+    // The value starts at the first colon that isn't inside a quoted parameter
+    let mut in_quotes = false;
+    let colon = line.char_indices().find_map(|(index, c)| match c {
+        '"' => {
+            in_quotes = !in_quotes;
+            None
+        }
+        ':' if !in_quotes => Some(index),
+        _ => None,
+    })?;
+
+    let mut parts = line[..colon].split(';');
+    let name = parts.next()?.trim().to_uppercase();
+    let params = parts
+        .filter_map(|param| param.split_once('='))
+        .map(|(key, value)| (key.to_uppercase(), value.trim_matches('"').to_string()))
+        .collect();
+
+    Some(Property {
+        name,
+        params,
+        value: line[colon + 1..].to_string(),
+        line: line.to_string(),
+    })
+}
+
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') | Some('N') => unescaped.push('\n'),
+                Some(escaped) => unescaped.push(escaped),
+                None => {}
+            }
+        } else {
+            unescaped.push(c);
+        }
+    }
+    unescaped
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+}
+
+// Returns the time, whether it's a whole day and the time zone it was in. Times without a TZID
+// or a Z on the end are in the calendar's own time zone.
+fn parse_time(
+    property: &Property,
+    default_tz: Option<Tz>,
+) -> Result<(DateTime<Utc>, bool, Option<Tz>), String> {
+    let value = property.value.trim();
+    let invalid = || format!("{} {} is not a valid date or time", property.name, value);
+
+    if property.params.get("VALUE").map(String::as_str) == Some("DATE") || value.len() == 8 {
+        let date = NaiveDate::parse_from_str(value, "%Y%m%d").map_err(|_| invalid())?;
+        return Ok((Utc.from_utc_date(&date).and_hms(0, 0, 0), true, None));
+    }
+
+    if let Some(utc) = value.strip_suffix('Z') {
+        let time = NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S").map_err(|_| invalid())?;
+        return Ok((Utc.from_utc_datetime(&time), false, None));
+    }
+
+    let time = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").map_err(|_| invalid())?;
+    let tz = match property.params.get("TZID") {
+        // Some calendars put a / in front to mean "a name from the usual database"
+        Some(tzid) => tzid
+            .trim_start_matches('/')
+            .parse::<Tz>()
+            .map_err(|_| format!("{} is not a time zone we know", tzid))?,
+        None => match default_tz {
+            Some(tz) => tz,
+            None => return Ok((Utc.from_utc_datetime(&time), false, None)),
+        },
+    };
+    match tz.from_local_datetime(&time) {
+        LocalResult::Single(time) | LocalResult::Ambiguous(time, _) => {
+            Ok((time.with_timezone(&Utc), false, Some(tz)))
+        }
+        // Skipped by the clocks going forward, so it happens an hour later
+        LocalResult::None => match tz.from_local_datetime(&(time + Duration::hours(1))) {
+            LocalResult::Single(time) | LocalResult::Ambiguous(time, _) => {
+                Ok((time.with_timezone(&Utc), false, Some(tz)))
+            }
+            LocalResult::None => Err(invalid()),
+        },
+    }
+}
+
+// Like PT1H30M or P1D
+fn parse_duration(value: &str) -> Option<Duration> {
+    let (negative, value) = match value.trim().strip_prefix('-') {
+        Some(value) => (true, value),
+        None => (false, value.trim().trim_start_matches('+')),
+    };
+    let mut duration = Duration::zero();
+    let mut number = String::new();
+    let mut in_time = false;
+    for c in value.strip_prefix('P')?.chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            'T' => in_time = true,
+            unit => {
+                let amount: i64 = number.parse().ok()?;
+                number.clear();
+                duration = duration
+                    + match (unit, in_time) {
+                        ('W', false) => Duration::weeks(amount),
+                        ('D', false) => Duration::days(amount),
+                        ('H', true) => Duration::hours(amount),
+                        ('M', true) => Duration::minutes(amount),
+                        ('S', true) => Duration::seconds(amount),
+                        _ => return None,
+                    };
+            }
+        }
+    }
+    if !number.is_empty() {
+        return None;
+    }
+    Some(if negative { -duration } else { duration })
+}
+
+// Every VEVENT in the file, or why it couldn't be read. Anything nested in an event (like
+// VALARM reminders) is ignored.
+fn parse_calendar(data: &str) -> Result<Vec<Result<ImportedEvent, CalendarImportResult>>> {
+    let mut components: Vec<String> = Vec::new();
+    let mut default_tz = None;
+    let mut event_properties: Vec<Property> = Vec::new();
+    let mut events = Vec::new();
+
+    for line in unfold(data) {
+        let property = match parse_property(&line) {
+            Some(property) => property,
+            None => continue,
+        };
+        match property.name.as_str() {
+            "BEGIN" => {
+                let component = property.value.trim().to_uppercase();
+                if component == "VEVENT" {
+                    event_properties.clear();
+                }
+                components.push(component);
+            }
+            "END" => {
+                let component = components.pop();
+                if component.as_deref() == Some("VEVENT") {
+                    events.push(read_event(&event_properties, default_tz));
+                }
+            }
+            "X-WR-TIMEZONE" if components.last().map(String::as_str) == Some("VCALENDAR") => {
+                default_tz = property.value.trim().parse::<Tz>().ok();
+            }
+            _ if components.last().map(String::as_str) == Some("VEVENT") => {
+                event_properties.push(property)
+            }
+            _ => {}
+        }
+    }
+
+    if !components.is_empty() || data.trim().is_empty() {
+        return Err(Error::new(
+            "This doesn't look like a complete iCalendar file",
+        ));
+    }
+    Ok(events)
+}
+
+fn read_event(
+    properties: &[Property],
+    default_tz: Option<Tz>,
+) -> Result<ImportedEvent, CalendarImportResult> {
+    let find = |name: &str| properties.iter().find(|property| property.name == name);
+    let text = |name: &str| find(name).map(|property| unescape(&property.value));
+
+    let uid = text("UID").unwrap_or_default();
+    let summary = text("SUMMARY").unwrap_or_default();
+    let invalid = |message: String, start_time: Option<DateTime<Utc>>| CalendarImportResult {
+        uid: uid.clone(),
+        summary: summary.clone(),
+        start_time,
+        action: ImportAction::Invalid,
+        messages: vec![message],
+    };
+
+    if uid.is_empty() {
+        return Err(invalid("The event has no UID".to_string(), None));
+    }
+    let (start_time, all_day, time_zone) = match find("DTSTART") {
+        Some(property) => parse_time(property, default_tz).map_err(|e| invalid(e, None))?,
+        None => return Err(invalid("The event has no DTSTART".to_string(), None)),
+    };
+    let end_time = match (find("DTEND"), find("DURATION")) {
+        (Some(property), _) => {
+            parse_time(property, default_tz)
+                .map_err(|e| invalid(e, Some(start_time)))?
+                .0
+        }
+        (None, Some(property)) => match parse_duration(&property.value) {
+            Some(duration) => start_time + duration,
+            None => {
+                return Err(invalid(
+                    format!("DURATION {} is not a valid duration", property.value),
+                    Some(start_time),
+                ))
+            }
+        },
+        // What RFC 5545 says to assume
+        (None, None) if all_day => start_time + Duration::days(1),
+        (None, None) => start_time,
+    };
+    if end_time < start_time {
+        return Err(invalid(
+            "The event ends before it starts".to_string(),
+            Some(start_time),
+        ));
+    }
+
+    Ok(ImportedEvent {
+        recurrence_id: find("RECURRENCE-ID")
+            .map(|property| property.line.clone())
+            .unwrap_or_default(),
+        summary: if summary.is_empty() {
+            "(No title)".to_string()
+        } else {
+            summary.clone()
+        },
+        description: text("DESCRIPTION").unwrap_or_default(),
+        location: text("LOCATION").filter(|location| !location.is_empty()),
+        start_time,
+        end_time,
+        all_day,
+        time_zone,
+        recurrence: properties
+            .iter()
+            .filter(|property| matches!(property.name.as_str(), "RRULE" | "RDATE" | "EXDATE"))
+            .map(|property| property.line.clone())
+            .collect(),
+        cancelled: text("STATUS").map(|status| status.trim().to_uppercase())
+            == Some("CANCELLED".to_string()),
+        sequence: text("SEQUENCE")
+            .and_then(|sequence| sequence.trim().parse().ok())
+            .unwrap_or(0),
+        uid,
+    })
+}
+
+// What an update would change, in words
+fn describe_changes(existing: &Event, imported: &ImportedEvent) -> Vec<String> {
+    let mut changes = Vec::new();
+    if existing.summary != imported.summary {
+        changes.push(format!(
+            "summary: {} -> {}",
+            existing.summary, imported.summary
+        ));
+    }
+    if existing.description != imported.description {
+        changes.push("description changed".to_string());
+    }
+    if existing.location != imported.location {
+        changes.push(format!(
+            "location: {} -> {}",
+            existing.location.as_deref().unwrap_or("(none)"),
+            imported.location.as_deref().unwrap_or("(none)")
+        ));
+    }
+    if existing.start_time != imported.start_time || existing.end_time != imported.end_time {
+        changes.push(format!(
+            "time: {} to {} -> {} to {}",
+            existing.start_time, existing.end_time, imported.start_time, imported.end_time
+        ));
+    }
+    if existing.all_day != imported.all_day {
+        changes.push(format!(
+            "all day: {} -> {}",
+            existing.all_day, imported.all_day
+        ));
+    }
+    let time_zone = imported.time_zone.map(|tz| tz.name().to_string());
+    if existing.time_zone != time_zone {
+        changes.push(format!(
+            "time zone: {} -> {}",
+            existing.time_zone.as_deref().unwrap_or("UTC"),
+            time_zone.as_deref().unwrap_or("UTC")
+        ));
+    }
+    if existing.recurrence != imported.recurrence {
+        changes.push("recurrence changed".to_string());
+    }
+    if existing.cancelled != imported.cancelled {
+        changes.push(if imported.cancelled {
+            "cancelled".to_string()
+        } else {
+            "no longer cancelled".to_string()
+        });
+    }
+    changes
+}
+
+// Creates or updates an event for every VEVENT in the file, matched on UID. Events that aren't
+// in the file are left alone, so importing part of a calendar is fine.
+pub async fn import_calendar(
+    pool: &PgPool,
+    data: &str,
+    dry_run: bool,
+    actor: Option<Uuid>,
+) -> Result<CalendarImportReport> {
+    let parsed = parse_calendar(data)?;
+    let mut tx = pool.begin().await?;
+
+    let mut results = Vec::new();
+    for event in parsed {
+        let event = match event {
+            Ok(event) => event,
+            Err(result) => {
+                results.push(result);
+                continue;
+            }
+        };
+
+        let existing = sqlx::query_as!(
+            Event,
+            "SELECT * FROM events WHERE uid=$1 AND recurrence_id=$2 FOR UPDATE",
+            event.uid,
+            event.recurrence_id
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+
+        let (action, messages) = match &existing {
+            None => (ImportAction::Create, Vec::new()),
+            Some(existing) => {
+                let changes = describe_changes(existing, &event);
+                if changes.is_empty() {
+                    (ImportAction::Unchanged, changes)
+                } else {
+                    (ImportAction::Update, changes)
+                }
+            }
+        };
+
+        if !dry_run {
+            match (&existing, action) {
+                (None, _) => {
+                    sqlx::query!(
+                        "INSERT INTO events (uid, recurrence_id, summary, description, location, start_time, end_time,
+                        all_day, time_zone, recurrence, cancelled, sequence)
+                        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)",
+                        event.uid,
+                        event.recurrence_id,
+                        event.summary,
+                        event.description,
+                        event.location,
+                        event.start_time,
+                        event.end_time,
+                        event.all_day,
+                        event.time_zone.map(|tz| tz.name()),
+                        &event.recurrence,
+                        event.cancelled,
+                        event.sequence
+                    )
+                    .execute(&mut tx)
+                    .await?;
+                }
+                // Subscribers only pick up changes with a higher SEQUENCE, so make sure it goes up
+                (Some(existing), ImportAction::Update) => {
+                    sqlx::query!(
+                        "UPDATE events SET (summary, description, location, start_time, end_time, all_day,
+                        time_zone, recurrence, cancelled, sequence, update_time)
+                        = ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, now()) WHERE id=$11",
+                        event.summary,
+                        event.description,
+                        event.location,
+                        event.start_time,
+                        event.end_time,
+                        event.all_day,
+                        event.time_zone.map(|tz| tz.name()),
+                        &event.recurrence,
+                        event.cancelled,
+                        event.sequence.max(existing.sequence + 1),
+                        existing.id
+                    )
+                    .execute(&mut tx)
+                    .await?;
+                }
+                _ => {}
+            }
+        }
+
+        results.push(CalendarImportResult {
+            uid: event.uid,
+            summary: event.summary,
+            start_time: Some(event.start_time),
+            action,
+            messages,
+        });
+    }
+
+    let count = |action| {
+        results
+            .iter()
+            .filter(|result| result.action == action)
+            .count() as i32
+    };
+    let report = CalendarImportReport {
+        creates: count(ImportAction::Create),
+        updates: count(ImportAction::Update),
+        unchanged: count(ImportAction::Unchanged),
+        invalid: count(ImportAction::Invalid),
+        events: results,
+        applied: !dry_run,
+    };
+
+    if !dry_run {
+        record_audit(
+            &mut tx,
+            actor,
+            "import_calendar",
+            None,
+            serde_json::json!({
+                "creates": report.creates,
+                "updates": report.updates,
+                "invalid": report.invalid,
+            }),
+        )
+        .await?;
+        tx.commit().await?;
+    }
+
+    Ok(report)
+}
+
+// Adds a line to the feed, folded so no line is longer than MAX_LINE_LENGTH bytes
+fn push_line(ics: &mut String, line: &str) {
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > MAX_LINE_LENGTH {
+            ics.push_str("\r\n ");
+            // The space counts too
+            length = 1;
+        }
+        ics.push(c);
+        length += c.len_utf8();
+    }
+    ics.push_str("\r\n");
+}
+
+// Times go out in the event's own time zone when it has one, since RRULE repeats them at the
+// same local time and EXDATEs have to line up with that
+fn format_time(time: DateTime<Utc>, event: &Event) -> String {
+    if event.all_day {
+        return format!(";VALUE=DATE:{}", time.format("%Y%m%d"));
+    }
+    match event
+        .time_zone
+        .as_deref()
+        .and_then(|tz| tz.parse::<Tz>().ok())
+    {
+        Some(tz) => format!(
+            ";TZID={}:{}",
+            tz.name(),
+            time.with_timezone(&tz).format("%Y%m%dT%H%M%S")
+        ),
+        None => format!(":{}", time.format("%Y%m%dT%H%M%SZ")),
+    }
+}
+
+fn write_calendar(events: &[Event]) -> String {
+    let mut ics = String::new();
+    push_line(&mut ics, "BEGIN:VCALENDAR");
+    push_line(&mut ics, "VERSION:2.0");
+    push_line(&mut ics, "PRODID:-//RCSC//attendance-rs//EN");
+    push_line(&mut ics, "CALSCALE:GREGORIAN");
+    push_line(&mut ics, "METHOD:PUBLISH");
+    push_line(&mut ics, "X-WR-CALNAME:RCSC meetings");
+
+    for event in events {
+        push_line(&mut ics, "BEGIN:VEVENT");
+        push_line(&mut ics, &format!("UID:{}", event.uid));
+        if !event.recurrence_id.is_empty() {
+            push_line(&mut ics, &event.recurrence_id);
+        }
+        push_line(
+            &mut ics,
+            &format!(
+                "DTSTAMP:{}",
+                event
+                    .update_time
+                    .unwrap_or(event.create_time)
+                    .format("%Y%m%dT%H%M%SZ")
+            ),
+        );
+        push_line(
+            &mut ics,
+            &format!("DTSTART{}", format_time(event.start_time, event)),
+        );
+        push_line(
+            &mut ics,
+            &format!("DTEND{}", format_time(event.end_time, event)),
+        );
+        for line in &event.recurrence {
+            push_line(&mut ics, line);
+        }
+        push_line(&mut ics, &format!("SUMMARY:{}", escape(&event.summary)));
+        if !event.description.is_empty() {
+            push_line(
+                &mut ics,
+                &format!("DESCRIPTION:{}", escape(&event.description)),
+            );
+        }
+        if let Some(location) = &event.location {
+            push_line(&mut ics, &format!("LOCATION:{}", escape(location)));
+        }
+        push_line(&mut ics, &format!("SEQUENCE:{}", event.sequence));
+        push_line(
+            &mut ics,
+            if event.cancelled {
+                "STATUS:CANCELLED"
+            } else {
+                "STATUS:CONFIRMED"
+            },
+        );
+        push_line(&mut ics, "END:VEVENT");
+    }
+
+    push_line(&mut ics, "END:VCALENDAR");
+    ics
+}
+
+#[derive(Deserialize)]
+pub struct CalendarFeedParams {
+    key: String,
+}
+
+// Calendar apps poll this. The key is from createCalendarFeedKey.
+pub async fn calendar_feed(
+    pool: web::Data<Arc<PgPool>>,
+    params: web::Query<CalendarFeedParams>,
+) -> HttpResponse {
+    let key = match Uuid::parse_str(&params.key) {
+        Ok(key) => key,
+        Err(_) => return HttpResponse::Unauthorized().body("This calendar link is not valid"),
+    };
+
+    match sqlx::query!(
+        "UPDATE calendar_feed_keys SET last_access_time = now() WHERE key=$1 RETURNING key",
+        key
+    )
+    .fetch_optional(&***pool)
+    .await
+    {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::Unauthorized().body("This calendar link is not valid"),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    }
+
+    match sqlx::query_as!(Event, "SELECT * FROM events ORDER BY start_time, id")
+        .fetch_all(&***pool)
+        .await
+    {
+        Ok(events) => HttpResponse::Ok()
+            .content_type("text/calendar; charset=utf-8")
+            .body(write_calendar(&events)),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unfold_lines() {
+        let data = "BEGIN:VEVENT\r\nDESCRIPTION:Bring your\r\n  safety glasses\r\n\tand gloves\r\n\r\nSUMMARY:Build\nEND:VEVENT\n";
+        assert_eq!(
+            unfold(data),
+            [
+                "BEGIN:VEVENT",
+                "DESCRIPTION:Bring your safety glassesand gloves",
+                "SUMMARY:Build",
+                "END:VEVENT"
+            ]
+        );
+        // A continuation with nothing to continue is kept as its own line
+        assert_eq!(unfold(" SUMMARY:Build"), [" SUMMARY:Build"]);
+        assert!(unfold("\r\n\r\n").is_empty());
+    }
+
+    #[test]
+    fn parse_properties() {
+        let property = parse_property("dtstart;tzid=America/Toronto:20211020T180000").unwrap();
+        assert_eq!(property.name, "DTSTART");
+        assert_eq!(property.params["TZID"], "America/Toronto");
+        assert_eq!(property.value, "20211020T180000");
+        assert_eq!(
+            property.line,
+            "dtstart;tzid=America/Toronto:20211020T180000"
+        );
+
+        // Colons in quoted parameters and in the value don't end the name
+        let property =
+            parse_property(r#"ATTENDEE;CN="Lovelace: Ada";ROLE=CHAIR:mailto:ada@example.com"#)
+                .unwrap();
+        assert_eq!(property.name, "ATTENDEE");
+        assert_eq!(property.params["CN"], "Lovelace: Ada");
+        assert_eq!(property.params["ROLE"], "CHAIR");
+        assert_eq!(property.value, "mailto:ada@example.com");
+
+        let property = parse_property("SUMMARY:").unwrap();
+        assert_eq!(property.name, "SUMMARY");
+        assert!(property.params.is_empty());
+        assert_eq!(property.value, "");
+
+        assert!(parse_property("NOT A PROPERTY").is_none());
+    }
+
+    #[test]
+    fn parse_durations() {
+        let cases = [
+            ("PT1H30M", Some(Duration::minutes(90))),
+            ("P1D", Some(Duration::days(1))),
+            ("P2W", Some(Duration::weeks(2))),
+            ("P1DT2H3M4S", Some(Duration::seconds(93784))),
+            ("-PT15M", Some(Duration::minutes(-15))),
+            ("+PT15M", Some(Duration::minutes(15))),
+            (" PT0S ", Some(Duration::zero())),
+            // H, M and S only come after the T, and D and W only before it
+            ("P1H", None),
+            ("PT1D", None),
+            ("P1Y", None),
+            ("PT", Some(Duration::zero())),
+            ("PT1", None),
+            ("PTH", None),
+            ("1H", None),
+            ("", None),
+        ];
+        for (value, duration) in cases.iter() {
+            assert_eq!(parse_duration(value), *duration, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn escape_round_trip() {
+        let text = "Room 101; bring laptops, chargers\nand a \\ backslash";
+        assert_eq!(
+            escape(text),
+            r"Room 101\; bring laptops\, chargers\nand a \\ backslash"
+        );
+        assert_eq!(unescape(&escape(text)), text);
+        assert_eq!(unescape("Line one\\NLine two\\"), "Line one\nLine two");
+    }
+}
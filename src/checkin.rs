@@ -0,0 +1,199 @@
+// Rotating check-in codes. They work like TOTP (RFC 6238, with SHA-256): a screen at the
+// location shows the current code, and people type it in to sign themselves in.
+
+use crate::export::xml_escape;
+use crate::tables::CheckinCode;
+use actix_web::{web, HttpResponse};
+use async_graphql::*;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use sqlx::{
+    postgres::PgPool,
+    types::{
+        chrono::{DateTime, Utc},
+        Uuid,
+    },
+};
+use std::sync::Arc;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// The code from this many periods ago still works too, for people who were still typing when it
+// changed. Anything older is stale.
+const GRACE_PERIODS: i64 = 1;
+
+fn step_at(checkin_code: &CheckinCode, time: DateTime<Utc>) -> i64 {
+    time.timestamp()
+        .div_euclid(checkin_code.period_seconds.into())
+}
+
+// HOTP (RFC 4226) for the given time step
+fn code_at(checkin_code: &CheckinCode, step: i64) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(&checkin_code.secret).expect("HMAC takes keys of any length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let number = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    let digits = checkin_code.digits as usize;
+    format!(
+        "{:0digits$}",
+        number % 10u32.pow(digits as u32),
+        digits = digits
+    )
+}
+
+// Finds which screen a typed in code is from. Codes are only checked against the last couple
+// of periods, so one that was texted to someone a few minutes ago doesn't work any more.
+pub async fn find_checkin_code(pool: &PgPool, code: &str) -> Result<CheckinCode> {
+    // People copy the space in the middle too
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    let now = Utc::now();
+
+    let mut matching: Vec<CheckinCode> =
+        sqlx::query_as!(CheckinCode, "SELECT * FROM checkin_codes")
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .filter(|checkin_code| {
+                let step = step_at(checkin_code, now);
+                (step - GRACE_PERIODS..=step).any(|step| code_at(checkin_code, step) == code)
+            })
+            .collect();
+
+    match matching.len() {
+        0 => Err(Error::new(
+            "This code is wrong or has expired. Please use the one on the screen now.",
+        )),
+        1 => Ok(matching.remove(0)),
+        // Two screens showing the same code at once is unlikely, but we can't tell them apart
+        _ => Err(Error::new(
+            "This code is shown in more than one place right now. Please wait for the next one.",
+        )),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CheckinDisplayParams {
+    key: String,
+}
+
+// GET /checkin, the page to put on the screen. It reloads itself when the code changes.
+pub async fn checkin_display(
+    pool: web::Data<Arc<PgPool>>,
+    params: web::Query<CheckinDisplayParams>,
+) -> HttpResponse {
+    let key = match Uuid::parse_str(&params.key) {
+        Ok(key) => key,
+        Err(_) => return HttpResponse::Unauthorized().body("This check-in link is not valid"),
+    };
+
+    let checkin_code = match sqlx::query_as!(
+        CheckinCode,
+        "SELECT * FROM checkin_codes WHERE display_key=$1",
+        key
+    )
+    .fetch_optional(&***pool)
+    .await
+    {
+        Ok(Some(checkin_code)) => checkin_code,
+        Ok(None) => return HttpResponse::Unauthorized().body("This check-in link is not valid"),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    let now = Utc::now();
+    let period = i64::from(checkin_code.period_seconds);
+    let code = code_at(&checkin_code, step_at(&checkin_code, now));
+    // Split in half so it's easier to read from the back of the room
+    let (first, second) = code.split_at(code.len() / 2);
+    let place = match (&checkin_code.location, &checkin_code.event) {
+        (Some(location), Some(event)) => format!("{} at {}", event, location),
+        (Some(place), None) | (None, Some(place)) => place.clone(),
+        (None, None) => String::new(),
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        // Caches would show a stale code
+        .header("Cache-Control", "no-store")
+        .body(format!(
+            "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+            <meta http-equiv=\"refresh\" content=\"{}\"><title>{}</title><style>\
+            body {{ font-family: sans-serif; text-align: center; margin-top: 15vh; }}\
+            .code {{ font-size: 20vw; font-weight: bold; letter-spacing: 0.05em; }}\
+            .place {{ font-size: 4vw; color: #555; }}\
+            </style></head><body><div class=\"place\">Sign in to {}</div>\
+            <div class=\"code\">{} {}</div></body></html>",
+            period - now.timestamp().rem_euclid(period),
+            xml_escape(&checkin_code.description),
+            xml_escape(&place),
+            first,
+            second
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn checkin_code(digits: i32) -> CheckinCode {
+        CheckinCode {
+            id: 1,
+            description: String::new(),
+            location: None,
+            event: None,
+            secret: b"12345678901234567890123456789012".to_vec(),
+            period_seconds: 30,
+            digits,
+            display_key: Uuid::nil(),
+            create_time: Utc::now(),
+        }
+    }
+
+    // The SHA-256 test vectors from RFC 6238, appendix B
+    #[test]
+    fn rfc_6238_vectors() {
+        let checkin_code = checkin_code(8);
+        let cases = [
+            (59, "46119246"),
+            (1111111109, "68084774"),
+            (1111111111, "67062674"),
+            (1234567890, "91819424"),
+            (2000000000, "90698825"),
+            (20000000000, "77737706"),
+        ];
+        for (time, code) in cases.iter() {
+            let step = step_at(&checkin_code, Utc.timestamp(*time, 0));
+            assert_eq!(code_at(&checkin_code, step), *code, "{}", time);
+        }
+    }
+
+    #[test]
+    fn shorter_codes_keep_leading_zeros() {
+        // The last 6 digits of the 8 digit codes above
+        let checkin_code = checkin_code(6);
+        let step = step_at(&checkin_code, Utc.timestamp(2000000000, 0));
+        assert_eq!(code_at(&checkin_code, step), "698825");
+
+        let codes: Vec<String> = (0..200).map(|step| code_at(&checkin_code, step)).collect();
+        assert!(codes.iter().all(|code| code.len() == 6));
+        assert!(codes.iter().any(|code| code.starts_with('0')));
+    }
+
+    #[test]
+    fn steps_change_every_period() {
+        let checkin_code = checkin_code(6);
+        assert_eq!(step_at(&checkin_code, Utc.timestamp(0, 0)), 0);
+        assert_eq!(step_at(&checkin_code, Utc.timestamp(29, 0)), 0);
+        assert_eq!(step_at(&checkin_code, Utc.timestamp(30, 0)), 1);
+        assert_eq!(step_at(&checkin_code, Utc.timestamp(-1, 0)), -1);
+    }
+}
@@ -0,0 +1,170 @@
+// Fans events out to subscribers. Postgres tells us about them (see the attendance_notify and
+// roll_call_entries_notify triggers), so every kiosk and every code path shows up without
+// having to remember to publish anything.
+
+use crate::tables::{Attendance, AttendanceEvent, AttendanceEventKind, RollCallEntry};
+use futures::{channel::mpsc, Stream};
+use lazy_static::lazy_static;
+use log::{error, warn};
+use serde::Deserialize;
+use sqlx::postgres::{PgListener, PgNotification, PgPool};
+use sqlx::types::Uuid;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+const ATTENDANCE_CHANNEL: &str = "attendance_events";
+const ROLL_CALL_CHANNEL: &str = "roll_call_events";
+
+struct Subscribers<T>(Mutex<Vec<mpsc::UnboundedSender<T>>>);
+
+impl<T: Clone> Subscribers<T> {
+    fn new() -> Self {
+        Subscribers(Mutex::new(Vec::new()))
+    }
+
+    fn subscribe(&self) -> mpsc::UnboundedReceiver<T> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.0.lock().unwrap().push(sender);
+        receiver
+    }
+
+    fn publish(&self, event: T) {
+        // Sends fail once a subscription has ended, so this is also where those get cleaned up
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|sender| sender.unbounded_send(event.clone()).is_ok());
+    }
+}
+
+lazy_static! {
+    static ref ATTENDANCE_SUBSCRIBERS: Subscribers<AttendanceEvent> = Subscribers::new();
+    static ref ROLL_CALL_SUBSCRIBERS: Subscribers<RollCallEntry> = Subscribers::new();
+}
+
+// What the triggers send
+#[derive(Deserialize)]
+struct AttendanceNotification {
+    id: i32,
+    kind: AttendanceEventKind,
+}
+
+#[derive(Deserialize)]
+struct RollCallNotification {
+    roll_call_id: i32,
+    user_uuid: String,
+}
+
+pub fn subscribe_to_attendance() -> impl Stream<Item = AttendanceEvent> {
+    ATTENDANCE_SUBSCRIBERS.subscribe()
+}
+
+pub fn subscribe_to_roll_calls() -> impl Stream<Item = RollCallEntry> {
+    ROLL_CALL_SUBSCRIBERS.subscribe()
+}
+
+// Runs for as long as the server does. The listener gets its own connection so it doesn't
+// take one from the pool for good.
+pub async fn listen_for_events(pg_connection_str: String, pool: Arc<PgPool>) {
+    loop {
+        if let Err(e) = forward_events(&pg_connection_str, &pool).await {
+            error!(
+                "Stopped listening for events, retrying in 5 seconds. Error: {}",
+                e
+            );
+        }
+        actix_web::rt::time::delay_for(Duration::from_secs(5)).await;
+    }
+}
+
+async fn forward_events(pg_connection_str: &str, pool: &PgPool) -> Result<(), sqlx::Error> {
+    let mut listener = PgListener::connect(pg_connection_str).await?;
+    listener
+        .listen_all(vec![ATTENDANCE_CHANNEL, ROLL_CALL_CHANNEL])
+        .await?;
+
+    loop {
+        let notification = listener.recv().await?;
+        match notification.channel() {
+            ATTENDANCE_CHANNEL => forward_attendance_event(&notification, pool).await?,
+            ROLL_CALL_CHANNEL => forward_roll_call_event(&notification, pool).await?,
+            channel => warn!("Ignoring an event on unexpected channel {}", channel),
+        }
+    }
+}
+
+async fn forward_attendance_event(
+    notification: &PgNotification,
+    pool: &PgPool,
+) -> Result<(), sqlx::Error> {
+    let notification: AttendanceNotification = match serde_json::from_str(notification.payload()) {
+        Ok(notification) => notification,
+        Err(e) => {
+            warn!("Ignoring a malformed attendance event: {}", e);
+            return Ok(());
+        }
+    };
+
+    // The entry could be gone already (an erased user, say), in which case there's nothing to say
+    let attendance = match sqlx::query_as!(
+        Attendance,
+        "SELECT * FROM attendance WHERE id=$1",
+        notification.id
+    )
+    .fetch_optional(pool)
+    .await?
+    {
+        Some(attendance) => attendance,
+        None => return Ok(()),
+    };
+    let groups = sqlx::query!(
+        "SELECT groups FROM users WHERE uuid=$1",
+        attendance.user_uuid
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|record| record.groups)
+    .unwrap_or_default();
+
+    ATTENDANCE_SUBSCRIBERS.publish(AttendanceEvent {
+        kind: notification.kind,
+        attendance,
+        groups,
+    });
+    Ok(())
+}
+
+async fn forward_roll_call_event(
+    notification: &PgNotification,
+    pool: &PgPool,
+) -> Result<(), sqlx::Error> {
+    let (roll_call_id, user_uuid) =
+        match serde_json::from_str::<RollCallNotification>(notification.payload()) {
+            Ok(notification) => match Uuid::parse_str(&notification.user_uuid) {
+                Ok(user_uuid) => (notification.roll_call_id, user_uuid),
+                Err(e) => {
+                    warn!("Ignoring a roll call event with a bad user UUID: {}", e);
+                    return Ok(());
+                }
+            },
+            Err(e) => {
+                warn!("Ignoring a malformed roll call event: {}", e);
+                return Ok(());
+            }
+        };
+
+    if let Some(entry) = sqlx::query_as!(
+        RollCallEntry,
+        "SELECT * FROM roll_call_entries WHERE roll_call_id=$1 AND user_uuid=$2",
+        roll_call_id,
+        user_uuid
+    )
+    .fetch_optional(pool)
+    .await?
+    {
+        ROLL_CALL_SUBSCRIBERS.publish(entry);
+    }
+    Ok(())
+}
@@ -0,0 +1,732 @@
+// Spreadsheet exports, served beside /graphql. Rows are sent as they come back from the
+// database, so exporting a whole season doesn't have to fit in memory.
+
+use crate::tables::{TokenCapability, ACCESS_DENIED_MESSAGE};
+use actix_web::{error::ErrorInternalServerError, web, web::Bytes, HttpRequest, HttpResponse};
+use chrono::Duration;
+use futures::{channel::mpsc, SinkExt, TryStreamExt};
+use log::error;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::{
+    postgres::PgPool,
+    types::chrono::{DateTime, NaiveDate, Utc},
+};
+use std::{cell::RefCell, error::Error, io::Write, rc::Rc, sync::Arc};
+use zip::write::{SimpleFileOptions, StreamWriter};
+use zip::{CompressionMethod, ZipWriter};
+
+// Output is sent to the client in chunks of about this many bytes
+const CHUNK_SIZE: usize = 64 * 1024;
+
+type ExportResult = Result<(), Box<dyn Error>>;
+// Start and end of a date range, either of which can be left open
+type TimeRange = (Option<DateTime<Utc>>, Option<DateTime<Utc>>);
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    // One JSON object per line
+    Jsonl,
+    Xlsx,
+}
+
+impl ExportFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Jsonl => "application/x-ndjson",
+            ExportFormat::Xlsx => {
+                "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"
+            }
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Jsonl => "jsonl",
+            ExportFormat::Xlsx => "xlsx",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ExportParams {
+    #[serde(default)]
+    format: ExportFormat,
+    // Whole days in UTC like 2021-09-30. Both ends are included.
+    from: Option<String>,
+    to: Option<String>,
+    #[serde(default)]
+    include_archived: bool,
+}
+
+// The writers below all write into this, and we hand whatever has piled up to the client
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+enum Encoder {
+    Csv(csv::Writer<SharedBuffer>),
+    Jsonl(SharedBuffer),
+    Xlsx(ZipWriter<StreamWriter<SharedBuffer>>),
+}
+
+struct Export {
+    encoder: Encoder,
+    headers: Vec<String>,
+    buffer: SharedBuffer,
+    sender: mpsc::Sender<Result<Bytes, actix_web::Error>>,
+}
+
+impl Export {
+    // Writes the header and returns the response that the rows will be streamed into
+    fn start(
+        format: ExportFormat,
+        name: &str,
+        headers: Vec<String>,
+    ) -> Result<(Export, HttpResponse), Box<dyn Error>> {
+        let buffer = SharedBuffer::default();
+        let encoder = match format {
+            ExportFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(buffer.clone());
+                writer.write_record(&headers)?;
+                Encoder::Csv(writer)
+            }
+            ExportFormat::Jsonl => Encoder::Jsonl(buffer.clone()),
+            ExportFormat::Xlsx => {
+                let mut zip = ZipWriter::new_stream(buffer.clone());
+                let options =
+                    SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+                for (path, contents) in xlsx_parts(name) {
+                    zip.start_file(path, options)?;
+                    zip.write_all(contents.as_bytes())?;
+                }
+                zip.start_file("xl/worksheets/sheet1.xml", options)?;
+                zip.write_all(XLSX_SHEET_START.as_bytes())?;
+                let header_row: Vec<Value> = headers.iter().map(|header| json!(header)).collect();
+                zip.write_all(xlsx_row(&header_row).as_bytes())?;
+                Encoder::Xlsx(zip)
+            }
+        };
+
+        let (sender, receiver) = mpsc::channel(4);
+        let response = HttpResponse::Ok()
+            .content_type(format.content_type())
+            .header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}.{}\"", name, format.extension()),
+            )
+            .streaming(receiver);
+
+        Ok((
+            Export {
+                encoder,
+                headers,
+                buffer,
+                sender,
+            },
+            response,
+        ))
+    }
+
+    // `row` has one value per header, in the same order
+    async fn write_row(&mut self, row: Vec<Value>) -> ExportResult {
+        match &mut self.encoder {
+            Encoder::Csv(writer) => {
+                writer.write_record(row.iter().map(csv_cell))?;
+                writer.flush()?;
+            }
+            Encoder::Jsonl(buffer) => {
+                // Written by hand so the keys stay in column order
+                let fields = self
+                    .headers
+                    .iter()
+                    .zip(&row)
+                    .map(|(header, value)| format!("{}:{}", json!(header), value))
+                    .collect::<Vec<_>>();
+                writeln!(buffer, "{{{}}}", fields.join(","))?;
+            }
+            Encoder::Xlsx(zip) => zip.write_all(xlsx_row(&row).as_bytes())?,
+        }
+
+        if self.buffer.0.borrow().len() >= CHUNK_SIZE {
+            self.send_buffer().await?;
+        }
+        Ok(())
+    }
+
+    async fn send_buffer(&mut self) -> ExportResult {
+        let chunk = std::mem::take(&mut *self.buffer.0.borrow_mut());
+        if !chunk.is_empty() {
+            self.sender.send(Ok(Bytes::from(chunk))).await?;
+        }
+        Ok(())
+    }
+
+    // Finishes the file, or cuts the download off if something went wrong so a partial
+    // export can't be mistaken for a complete one
+    async fn finish(mut self, result: ExportResult) {
+        let result = match result {
+            Ok(()) => self.finish_encoder().await,
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            error!("Export failed part way through: {}", e);
+            let _ = self
+                .sender
+                .send(Err(ErrorInternalServerError(e.to_string())))
+                .await;
+        }
+    }
+
+    async fn finish_encoder(&mut self) -> ExportResult {
+        match std::mem::replace(&mut self.encoder, Encoder::Jsonl(self.buffer.clone())) {
+            Encoder::Csv(mut writer) => writer.flush()?,
+            Encoder::Jsonl(_) => {}
+            Encoder::Xlsx(mut zip) => {
+                zip.write_all(XLSX_SHEET_END.as_bytes())?;
+                zip.finish()?;
+            }
+        }
+        self.send_buffer().await
+    }
+}
+
+// Only viewers (and administrators) get to read attendance, same as through /graphql
+pub(crate) fn authorize(request: &HttpRequest) -> Result<(), HttpResponse> {
+    match crate::decode_token(request) {
+        Ok(claims)
+            if claims.cap == TokenCapability::Viewer
+                || claims.cap == TokenCapability::Administrator =>
+        {
+            Ok(())
+        }
+        Ok(_) => Err(HttpResponse::Forbidden().body(ACCESS_DENIED_MESSAGE)),
+        Err(e) => Err(HttpResponse::Unauthorized().body(e)),
+    }
+}
+
+// Turns `from` and `to` into a half-open range of times
+fn date_range(params: &ExportParams) -> Result<TimeRange, HttpResponse> {
+    let parse = |date: &Option<String>, days_after: i64| match date {
+        Some(date) => match NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+            Ok(date) => Ok(Some(
+                DateTime::<Utc>::from_utc(date.and_hms(0, 0, 0), Utc) + Duration::days(days_after),
+            )),
+            Err(_) => {
+                Err(HttpResponse::BadRequest()
+                    .body(format!("{} is not a date like 2021-09-30", date)))
+            }
+        },
+        None => Ok(None),
+    };
+
+    Ok((parse(&params.from, 0)?, parse(&params.to, 1)?))
+}
+
+fn time_value(time: Option<DateTime<Utc>>) -> Value {
+    match time {
+        Some(time) => json!(time.to_rfc3339()),
+        None => Value::Null,
+    }
+}
+
+fn start_failed(e: Box<dyn Error>) -> HttpResponse {
+    error!("Could not start export: {}", e);
+    HttpResponse::InternalServerError().body(e.to_string())
+}
+
+// The columns match what `import-users` reads, so an export can be edited and imported again
+pub async fn export_users(
+    pool: web::Data<Arc<PgPool>>,
+    request: HttpRequest,
+    params: web::Query<ExportParams>,
+) -> HttpResponse {
+    if let Err(response) = authorize(&request) {
+        return response;
+    }
+    let pool = Arc::clone(pool.get_ref());
+
+    let alt_id_types = match sqlx::query!("SELECT name FROM alt_id_types ORDER BY name")
+        .fetch_all(&*pool)
+        .await
+    {
+        Ok(records) => records
+            .into_iter()
+            .map(|record| record.name)
+            .collect::<Vec<_>>(),
+        Err(e) => return start_failed(e.into()),
+    };
+    let mut headers: Vec<String> = vec!["uuid", "full_name", "email", "phone_number", "groups"]
+        .into_iter()
+        .map(String::from)
+        .collect();
+    headers.extend(alt_id_types.iter().map(|name| format!("alt_id.{}", name)));
+    headers.extend(
+        vec![
+            "create_time",
+            "update_time",
+            "archive_time",
+            "archive_reason",
+        ]
+        .into_iter()
+        .map(String::from),
+    );
+
+    let (mut export, response) = match Export::start(params.format, "users", headers) {
+        Ok(started) => started,
+        Err(e) => return start_failed(e),
+    };
+    let include_archived = params.include_archived;
+
+    actix_web::rt::spawn(async move {
+        let result: ExportResult = async {
+            let mut users = sqlx::query!(
+                "SELECT * FROM users WHERE ($1 OR archive_time IS NULL) ORDER BY full_name, uuid",
+                include_archived
+            )
+            .fetch(&*pool);
+
+            while let Some(user) = users.try_next().await? {
+                let mut row = vec![
+                    json!(user.uuid.to_hyphenated().to_string()),
+                    json!(user.full_name),
+                    json!(user.email),
+                    json!(user.phone_number),
+                    json!(user.groups),
+                ];
+                for name in &alt_id_types {
+                    row.push(match &user.alt_id_fields {
+                        Some(fields) => fields.get(name).cloned().unwrap_or(Value::Null),
+                        None => Value::Null,
+                    });
+                }
+                row.push(time_value(Some(user.create_time)));
+                row.push(time_value(user.update_time));
+                row.push(time_value(user.archive_time));
+                row.push(json!(user.archive_reason));
+
+                export.write_row(row).await?;
+            }
+            Ok(())
+        }
+        .await;
+        export.finish(result).await;
+    });
+
+    response
+}
+
+pub async fn export_attendance(
+    pool: web::Data<Arc<PgPool>>,
+    request: HttpRequest,
+    params: web::Query<ExportParams>,
+) -> HttpResponse {
+    if let Err(response) = authorize(&request) {
+        return response;
+    }
+    let (from, to) = match date_range(&params) {
+        Ok(range) => range,
+        Err(response) => return response,
+    };
+    let pool = Arc::clone(pool.get_ref());
+
+    let headers = vec![
+        "id",
+        "user_uuid",
+        "full_name",
+        "email",
+        "in_time",
+        "out_time",
+        "hours",
+        "location",
+        "event",
+        "device",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect();
+    let (mut export, response) = match Export::start(params.format, "attendance", headers) {
+        Ok(started) => started,
+        Err(e) => return start_failed(e),
+    };
+
+    actix_web::rt::spawn(async move {
+        let result: ExportResult = async {
+            let mut entries = sqlx::query!(
+                r#"SELECT a.id, a.user_uuid, u.full_name, u.email, a.in_time, a.out_time,
+                round((EXTRACT(EPOCH FROM a.out_time - a.in_time) / 3600)::numeric, 2)::float8 AS hours,
+                a.location, a.event, t.description AS "device?"
+                FROM attendance a
+                JOIN users u ON u.uuid = a.user_uuid
+                LEFT JOIN tokens t ON t.uuid = a.device_token_uuid
+                WHERE ($1::timestamptz IS NULL OR a.in_time >= $1)
+                AND ($2::timestamptz IS NULL OR a.in_time < $2)
+                ORDER BY a.in_time, a.id"#,
+                from,
+                to
+            )
+            .fetch(&*pool);
+
+            while let Some(entry) = entries.try_next().await? {
+                export
+                    .write_row(vec![
+                        json!(entry.id),
+                        json!(entry.user_uuid.to_hyphenated().to_string()),
+                        json!(entry.full_name),
+                        json!(entry.email),
+                        time_value(Some(entry.in_time)),
+                        time_value(entry.out_time),
+                        json!(entry.hours),
+                        json!(entry.location),
+                        json!(entry.event),
+                        json!(entry.device),
+                    ])
+                    .await?;
+            }
+            Ok(())
+        }
+        .await;
+        export.finish(result).await;
+    });
+
+    response
+}
+
+// One row per user with their totals for the range. Active users who never came still
+// get a row, so they stand out.
+pub async fn export_summary(
+    pool: web::Data<Arc<PgPool>>,
+    request: HttpRequest,
+    params: web::Query<ExportParams>,
+) -> HttpResponse {
+    if let Err(response) = authorize(&request) {
+        return response;
+    }
+    let (from, to) = match date_range(&params) {
+        Ok(range) => range,
+        Err(response) => return response,
+    };
+    let pool = Arc::clone(pool.get_ref());
+
+    let headers = vec![
+        "user_uuid",
+        "full_name",
+        "email",
+        "groups",
+        "sessions",
+        "hours",
+        "first_in_time",
+        "last_in_time",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect();
+    let (mut export, response) = match Export::start(params.format, "summary", headers) {
+        Ok(started) => started,
+        Err(e) => return start_failed(e),
+    };
+    let include_archived = params.include_archived;
+
+    actix_web::rt::spawn(async move {
+        let result: ExportResult = async {
+            let mut summaries = sqlx::query!(
+                r#"SELECT u.uuid, u.full_name, u.email, u.groups, COUNT(a.id) AS "sessions!",
+                round(COALESCE(SUM(EXTRACT(EPOCH FROM a.out_time - a.in_time)), 0)::numeric / 3600, 2)::float8 AS "hours!",
+                MIN(a.in_time) AS first_in_time, MAX(a.in_time) AS last_in_time
+                FROM users u
+                LEFT JOIN attendance a ON a.user_uuid = u.uuid
+                AND ($1::timestamptz IS NULL OR a.in_time >= $1)
+                AND ($2::timestamptz IS NULL OR a.in_time < $2)
+                GROUP BY u.uuid
+                HAVING COUNT(a.id) > 0 OR u.archive_time IS NULL OR $3
+                ORDER BY u.full_name, u.uuid"#,
+                from,
+                to,
+                include_archived
+            )
+            .fetch(&*pool);
+
+            while let Some(summary) = summaries.try_next().await? {
+                export
+                    .write_row(vec![
+                        json!(summary.uuid.to_hyphenated().to_string()),
+                        json!(summary.full_name),
+                        json!(summary.email),
+                        json!(summary.groups),
+                        json!(summary.sessions),
+                        json!(summary.hours),
+                        time_value(summary.first_in_time),
+                        time_value(summary.last_in_time),
+                    ])
+                    .await?;
+            }
+            Ok(())
+        }
+        .await;
+        export.finish(result).await;
+    });
+
+    response
+}
+
+// How a value looks in a CSV or text cell. Lists (like groups) are separated with
+// semicolons, which is also what the importer expects.
+fn cell_text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(text) => text.clone(),
+        Value::Array(items) => items.iter().map(cell_text).collect::<Vec<_>>().join(";"),
+        other => other.to_string(),
+    }
+}
+
+// Spreadsheet programs run CSV text starting with one of these as a formula, so a member
+// named `=HYPERLINK(...)` would become a live link. A leading ' makes them show it as text.
+// Numbers are left alone so negative values still sort and sum.
+fn csv_cell(value: &Value) -> String {
+    let text = cell_text(value);
+    match value {
+        Value::String(_) | Value::Array(_) if text.starts_with(&['=', '+', '-', '@'][..]) => {
+            format!("'{}", text)
+        }
+        _ => text,
+    }
+}
+
+// The smallest workbook Excel and LibreOffice will open: one sheet, with inline strings
+// so we don't need a shared string table (which would have to be written before the rows)
+fn xlsx_parts(sheet_name: &str) -> Vec<(&'static str, String)> {
+    vec![
+        (
+            "[Content_Types].xml",
+            r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types"><Default Extension="rels" ContentType="application/vnd.openxmlformats-package.relationships+xml"/><Default Extension="xml" ContentType="application/xml"/><Override PartName="/xl/workbook.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.sheet.main+xml"/><Override PartName="/xl/worksheets/sheet1.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.worksheet+xml"/></Types>"#.to_string(),
+        ),
+        (
+            "_rels/.rels",
+            r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"><Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/officeDocument" Target="xl/workbook.xml"/></Relationships>"#.to_string(),
+        ),
+        (
+            "xl/workbook.xml",
+            format!(
+                r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<workbook xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main" xmlns:r="http://schemas.openxmlformats.org/officeDocument/2006/relationships"><sheets><sheet name="{}" sheetId="1" r:id="rId1"/></sheets></workbook>"#,
+                xml_escape(sheet_name)
+            ),
+        ),
+        (
+            "xl/_rels/workbook.xml.rels",
+            r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"><Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/worksheet" Target="worksheets/sheet1.xml"/></Relationships>"#.to_string(),
+        ),
+    ]
+}
+
+const XLSX_SHEET_START: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<worksheet xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main"><sheetData>"#;
+const XLSX_SHEET_END: &str = "</sheetData></worksheet>";
+
+fn xlsx_row(row: &[Value]) -> String {
+    let mut xml = String::from("<row>");
+    for value in row {
+        match value {
+            // Empty cells still need to be there, or everything after them shifts left
+            Value::Null => xml.push_str("<c/>"),
+            Value::Number(number) => xml.push_str(&format!("<c><v>{}</v></c>", number)),
+            Value::Bool(boolean) => {
+                xml.push_str(&format!("<c t=\"b\"><v>{}</v></c>", *boolean as u8))
+            }
+            // Always an inline string, never a formula, whatever the text starts with
+            other => xml.push_str(&format!(
+                "<c t=\"inlineStr\"><is><t xml:space=\"preserve\">{}</t></is></c>",
+                xml_escape(&cell_text(other))
+            )),
+        }
+    }
+    xml.push_str("</row>");
+    xml
+}
+
+pub(crate) fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        match character {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            // XML can't hold most control characters at all
+            '\t' | '\n' | '\r' => escaped.push(character),
+            _ if character.is_control() => {}
+            _ => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn xml_escape_cases() {
+        let cases = [
+            ("plain", "plain"),
+            ("Tom & Jerry", "Tom &amp; Jerry"),
+            ("<b>\"hi\"</b>", "&lt;b&gt;&quot;hi&quot;&lt;/b&gt;"),
+            ("it's", "it's"),
+            ("a\tb\r\nc", "a\tb\r\nc"),
+            // Control characters XML can't hold are dropped
+            ("bell\u{7}\u{0}", "bell"),
+            ("café ✓", "café ✓"),
+        ];
+        for (text, escaped) in cases.iter() {
+            assert_eq!(xml_escape(text), *escaped, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn csv_cell_formulas() {
+        let cases = [
+            (
+                json!("=HYPERLINK(\"http://evil.example\",\"Click\")"),
+                "'=HYPERLINK(\"http://evil.example\",\"Click\")",
+            ),
+            (json!("+1 555 0100"), "'+1 555 0100"),
+            (json!("-2+3"), "'-2+3"),
+            (json!("@SUM(A1)"), "'@SUM(A1)"),
+            (json!(["=1", "x"]), "'=1;x"),
+            (json!("Ada = 1"), "Ada = 1"),
+            (json!(-2.5), "-2.5"),
+            (Value::Null, ""),
+        ];
+        for (value, text) in cases.iter() {
+            assert_eq!(csv_cell(value), *text, "{:?}", value);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn csv_export_neutralises_formulas() {
+        let (mut export, mut response) = Export::start(
+            ExportFormat::Csv,
+            "members",
+            vec!["Name".to_string(), "Hours".to_string()],
+        )
+        .unwrap();
+        let result = export
+            .write_row(vec![
+                json!("=HYPERLINK(\"http://evil.example\")"),
+                json!(-1),
+            ])
+            .await;
+        export.finish(result).await;
+        let body = test::load_stream(response.take_body()).await.unwrap();
+        assert_eq!(
+            std::str::from_utf8(&body).unwrap(),
+            "Name,Hours\n\"'=HYPERLINK(\"\"http://evil.example\"\")\",-1\n"
+        );
+    }
+
+    #[test]
+    fn xlsx_row_cells() {
+        assert_eq!(
+            xlsx_row(&[
+                json!("A & B"),
+                Value::Null,
+                json!(2.5),
+                json!(true),
+                json!(["x", "y"]),
+            ]),
+            concat!(
+                "<row>",
+                r#"<c t="inlineStr"><is><t xml:space="preserve">A &amp; B</t></is></c>"#,
+                "<c/>",
+                "<c><v>2.5</v></c>",
+                r#"<c t="b"><v>1</v></c>"#,
+                r#"<c t="inlineStr"><is><t xml:space="preserve">x;y</t></is></c>"#,
+                "</row>"
+            )
+        );
+    }
+
+    #[actix_rt::test]
+    async fn xlsx_export() {
+        let (mut export, mut response) = Export::start(
+            ExportFormat::Xlsx,
+            "Q&A",
+            vec!["Name".to_string(), "Hours".to_string()],
+        )
+        .unwrap();
+        assert_eq!(
+            response.headers().get("Content-Disposition").unwrap(),
+            "attachment; filename=\"Q&A.xlsx\""
+        );
+
+        export
+            .write_row(vec![json!("<Ada>"), json!(4)])
+            .await
+            .unwrap();
+        let result = export
+            .write_row(vec![
+                json!("=HYPERLINK(\"http://evil.example\")"),
+                json!(-1),
+            ])
+            .await;
+        export.finish(result).await;
+        let body = test::load_stream(response.take_body()).await.unwrap();
+
+        let mut zip = zip::ZipArchive::new(Cursor::new(body.to_vec())).unwrap();
+        let mut names: Vec<&str> = zip.file_names().collect();
+        names.sort_unstable();
+        assert_eq!(
+            names,
+            [
+                "[Content_Types].xml",
+                "_rels/.rels",
+                "xl/_rels/workbook.xml.rels",
+                "xl/workbook.xml",
+                "xl/worksheets/sheet1.xml"
+            ]
+        );
+
+        let mut read = |name: &str| {
+            let mut contents = String::new();
+            zip.by_name(name)
+                .unwrap()
+                .read_to_string(&mut contents)
+                .unwrap();
+            contents
+        };
+        assert!(read("xl/workbook.xml").contains(r#"<sheet name="Q&amp;A" sheetId="1""#));
+        assert_eq!(
+            read("xl/worksheets/sheet1.xml"),
+            format!(
+                "{}{}{}{}{}",
+                XLSX_SHEET_START,
+                xlsx_row(&[json!("Name"), json!("Hours")]),
+                r#"<row><c t="inlineStr"><is><t xml:space="preserve">&lt;Ada&gt;</t></is></c><c><v>4</v></c></row>"#,
+                // A formula-looking name stays a plain string cell, with no <f> element
+                r#"<row><c t="inlineStr"><is><t xml:space="preserve">=HYPERLINK(&quot;http://evil.example&quot;)</t></is></c><c><v>-1</v></c></row>"#,
+                XLSX_SHEET_END
+            )
+        );
+    }
+}
@@ -1,151 +1,1080 @@
 // Database magic happens HERE
 
+use crate::accounts;
+use crate::calendar::{self, CalendarImportReport};
+use crate::checkin;
+use crate::import::{ImportFormat, ImportReport};
 use crate::tables::*;
-use crate::PRIVATE_KEY;
+use crate::{jobs, notifications};
+use crate::{PRIVATE_KEY, WEEKLY_SUMMARY_THRESHOLD_HOURS};
+use async_graphql::connection::{self, Connection, Edge};
 use async_graphql::*;
-use async_graphql::{guard::Guard, validators::Email, Context, Result};
+use async_graphql::{
+    guard::Guard,
+    validators::{Email, InputValueValidator},
+    Context, Result,
+};
+use chrono::{Duration, SecondsFormat};
+use futures::{Stream, StreamExt};
 use jsonwebtoken::{Algorithm, EncodingKey, Header};
 use log::debug;
 use sqlx::{
-    postgres::PgPool,
+    postgres::{PgConnection, PgPool},
     types::{
-        chrono::{DateTime, Utc},
+        chrono::{DateTime, NaiveDate, Utc},
         Uuid,
     },
 };
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 pub struct Query;
 pub struct Mutation;
+pub struct Subscription;
+
+// Used when a connection query doesn't ask for a page size, and to cap the ones that do
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+// How close a search has to be to a word in someone's name, e-mail or alt IDs to count.
+// 0.3 is low enough that "smtih" still finds "Smith".
+const SEARCH_SIMILARITY_THRESHOLD: f32 = 0.3;
+// Whole-name similarity for possible duplicates. "Jon Doe" and "Jonathan Doe" come out at 0.5.
+const DUPLICATE_NAME_SIMILARITY_THRESHOLD: f32 = 0.5;
+// Someone can sign out for anything under this many hours after signing in (a scan after that
+// signs them in again). An entry left open for this long is a forgotten sign-out, so that
+// person doesn't count as present anymore.
+pub(crate) const SIGN_OUT_WINDOW_HOURS: i32 = 4;
+// Stops occupancy from being asked for a whole year in one-minute buckets
+const MAX_OCCUPANCY_BUCKETS: i64 = 2000;
 
 // TODO implement a function to modify a user to have an altIdField
 #[Object]
 impl Query {
-    // TODO consolidate user stuff into one findUser and attendance stuff into one findAttendance
     // The collector gets this list since they might need to associate a new ID method
     // with a specific pre-existing user.
     #[graphql(guard(or(
         CapabilityGuard(capability = "TokenCapability::Collector"),
         CapabilityGuard(capability = "TokenCapability::Viewer")
     )))]
-    async fn users(&self, ctx: &Context<'_>) -> Result<Vec<User>> {
+    async fn find_users(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<UserFilter>,
+        order_by: Option<UserOrderBy>,
+        first: Option<i32>,
+        after: Option<String>,
+    ) -> Result<Connection<UserCursor, User, ConnectionFields>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let filter = filter.unwrap_or_default();
+
+        let uuid = match &filter.uuid {
+            Some(uuid) => Some(Uuid::parse_str(uuid)?),
+            None => None,
+        };
+        // Stored numbers are in E.164, so "(555) 555-5555" should still find "+15555555555"
+        let phone_number = filter.phone_number.as_ref().map(|phone_number| {
+            normalize_phone_number(phone_number).unwrap_or_else(|_| phone_number.clone())
+        });
+        // Look up alt IDs the same way log_attendance does, so "ab12" finds "AB12"
+        let (alt_id_field, alt_id_value) = match &filter.alt_id {
+            Some(alt_id) => {
+                let value = fetch_alt_id_type(pool, &alt_id.field)
+                    .await?
+                    .normalize(&alt_id.value)?;
+                (Some(alt_id.field.clone()), Some(value))
+            }
+            None => (None, None),
+        };
+        let (order_field, descending) = match order_by {
+            Some(order_by) => (
+                match order_by.field {
+                    UserOrderField::FullName => "full_name",
+                    UserOrderField::Email => "email",
+                    UserOrderField::CreateTime => "create_time",
+                },
+                order_by.direction == OrderDirection::Desc,
+            ),
+            None => ("full_name", false),
+        };
+        // % and _ are only wildcards when we put them there
+        let name = filter.name.as_ref().map(|name| escape_like(name));
+
+        connection::query(
+            after,
+            None,
+            first,
+            None,
+            |after: Option<UserCursor>, _, first, _| async move {
+                let limit = first.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+                // Creation times are compared as times, everything else as text
+                let (after_key, after_time) = match (&after, order_field) {
+                    (Some(after), "create_time") => (
+                        None,
+                        Some(
+                            DateTime::parse_from_rfc3339(&after.key)
+                                .map_err(|_| async_graphql::Error::new("Invalid cursor"))?
+                                .with_timezone(&Utc),
+                        ),
+                    ),
+                    (Some(after), _) => (Some(after.key.clone()), None),
+                    (None, _) => (None, None),
+                };
+                let after_uuid = after.as_ref().map(|after| after.uuid);
+
+                let total_count = sqlx::query!(
+                    "SELECT COUNT(*) FROM users
+                    WHERE ($1::uuid IS NULL OR uuid = $1)
+                    AND ($2::text IS NULL OR full_name ILIKE '%' || $2 || '%')
+                    AND ($3::text IS NULL OR lower(email) = lower($3))
+                    AND ($4::text IS NULL OR phone_number = $4)
+                    AND ($5::text IS NULL OR alt_id_fields->>$5 = $6)
+                    AND ($7::text IS NULL OR $7 = ANY(groups))
+                    AND ($8::timestamptz IS NULL OR create_time >= $8)
+                    AND ($9::timestamptz IS NULL OR create_time <= $9)
+                    AND ($10::bool IS NULL OR (archive_time IS NULL) = $10)",
+                    uuid,
+                    name,
+                    filter.email,
+                    phone_number,
+                    alt_id_field,
+                    alt_id_value,
+                    filter.group,
+                    filter.created_after,
+                    filter.created_before,
+                    filter.active
+                )
+                .fetch_one(&**pool)
+                .await?
+                .count
+                .unwrap_or(0);
+
+                // We grab one extra row to find out if there is a next page
+                let users = sqlx::query_as!(
+                    User,
+                    "SELECT * FROM users
+                    WHERE ($1::uuid IS NULL OR uuid = $1)
+                    AND ($2::text IS NULL OR full_name ILIKE '%' || $2 || '%')
+                    AND ($3::text IS NULL OR lower(email) = lower($3))
+                    AND ($4::text IS NULL OR phone_number = $4)
+                    AND ($5::text IS NULL OR alt_id_fields->>$5 = $6)
+                    AND ($7::text IS NULL OR $7 = ANY(groups))
+                    AND ($8::timestamptz IS NULL OR create_time >= $8)
+                    AND ($9::timestamptz IS NULL OR create_time <= $9)
+                    AND ($10::bool IS NULL OR (archive_time IS NULL) = $10)
+                    AND ($14::uuid IS NULL OR CASE
+                        WHEN $11 = 'full_name' AND NOT $12 THEN full_name > $15 OR (full_name = $15 AND uuid > $14)
+                        WHEN $11 = 'full_name' AND $12 THEN full_name < $15 OR (full_name = $15 AND uuid > $14)
+                        WHEN $11 = 'email' AND NOT $12 THEN email > $15 OR (email = $15 AND uuid > $14)
+                        WHEN $11 = 'email' AND $12 THEN email < $15 OR (email = $15 AND uuid > $14)
+                        WHEN $11 = 'create_time' AND NOT $12 THEN create_time > $16 OR (create_time = $16 AND uuid > $14)
+                        ELSE create_time < $16 OR (create_time = $16 AND uuid > $14)
+                    END)
+                    ORDER BY
+                        CASE WHEN $11 = 'full_name' AND NOT $12 THEN full_name END ASC,
+                        CASE WHEN $11 = 'full_name' AND $12 THEN full_name END DESC,
+                        CASE WHEN $11 = 'email' AND NOT $12 THEN email END ASC,
+                        CASE WHEN $11 = 'email' AND $12 THEN email END DESC,
+                        CASE WHEN $11 = 'create_time' AND NOT $12 THEN create_time END ASC,
+                        CASE WHEN $11 = 'create_time' AND $12 THEN create_time END DESC,
+                        uuid
+                    LIMIT $13",
+                    uuid,
+                    name,
+                    filter.email,
+                    phone_number,
+                    alt_id_field,
+                    alt_id_value,
+                    filter.group,
+                    filter.created_after,
+                    filter.created_before,
+                    filter.active,
+                    order_field,
+                    descending,
+                    limit as i64 + 1,
+                    after_uuid,
+                    after_key,
+                    after_time
+                )
+                .fetch_all(&**pool)
+                .await?;
+
+                let mut connection = Connection::with_additional_fields(
+                    after.is_some(),
+                    users.len() > limit,
+                    ConnectionFields { total_count },
+                );
+                connection.append(users.into_iter().take(limit).map(|user| {
+                    let key = match order_field {
+                        "email" => user.email.clone(),
+                        "create_time" => user
+                            .create_time
+                            .to_rfc3339_opts(SecondsFormat::Micros, true),
+                        _ => user.full_name.clone(),
+                    };
+                    Edge::new(
+                        UserCursor {
+                            key,
+                            uuid: user.uuid,
+                        },
+                        user,
+                    )
+                }));
+                Ok(connection)
+            },
+        )
+        .await
+    }
+
+    // For "find yourself" screens: ignores case and accents, tolerates typos, and
+    // puts the best matches first
+    #[graphql(guard(or(
+        CapabilityGuard(capability = "TokenCapability::Collector"),
+        CapabilityGuard(capability = "TokenCapability::Viewer")
+    )))]
+    async fn search_users(
+        &self,
+        ctx: &Context<'_>,
+        query: String,
+        first: Option<i32>,
+        include_archived: Option<bool>,
+    ) -> Result<Vec<UserSearchResult>> {
         let pool = ctx.data::<Arc<PgPool>>()?;
+        let limit = first
+            .map(|first| first.max(0) as usize)
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .min(MAX_PAGE_SIZE);
+
+        if query.trim().is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // The threshold only applies to this transaction. Setting it instead of comparing
+        // scores in the WHERE clause lets Postgres use the trigram index.
+        let mut tx = pool.begin().await?;
+        sqlx::query!(
+            "SELECT set_config('pg_trgm.word_similarity_threshold', $1, true)",
+            SEARCH_SIMILARITY_THRESHOLD.to_string()
+        )
+        .fetch_one(&mut tx)
+        .await?;
+
+        let results = sqlx::query!(
+            r#"SELECT *, word_similarity(lower(immutable_unaccent($1)), user_search_text(full_name, email, alt_id_fields)) AS "score!"
+            FROM users
+            WHERE lower(immutable_unaccent($1)) <% user_search_text(full_name, email, alt_id_fields)
+            AND ($3 OR archive_time IS NULL)
+            ORDER BY "score!" DESC, full_name
+            LIMIT $2"#,
+            query.trim(),
+            limit as i64,
+            include_archived.unwrap_or(false)
+        )
+        .fetch_all(&mut tx)
+        .await?;
+        tx.commit().await?;
 
-        Ok(sqlx::query_as!(User, "SELECT * FROM users")
-            .fetch_all(&**pool)
-            .await?)
+        Ok(results
+            .into_iter()
+            .map(|result| UserSearchResult {
+                user: User {
+                    uuid: result.uuid,
+                    full_name: result.full_name,
+                    email: result.email,
+                    phone_number: result.phone_number,
+                    create_time: result.create_time,
+                    update_time: result.update_time,
+                    alt_id_fields: result.alt_id_fields,
+                    groups: result.groups,
+                    archive_time: result.archive_time,
+                    archive_reason: result.archive_reason,
+                    version: result.version,
+                },
+                score: result.score,
+            })
+            .collect())
     }
 
+    // Pairs of users who share an e-mail or phone number, or have very similar names. The
+    // likeliest come first.
     #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Viewer")))]
-    async fn user_by_full_name_search(
+    async fn possible_duplicates(
         &self,
         ctx: &Context<'_>,
-        full_name: String,
-    ) -> Result<Vec<User>> {
+        include_archived: Option<bool>,
+        first: Option<i32>,
+        after: Option<String>,
+    ) -> Result<Connection<DuplicateCursor, DuplicateCandidate, ConnectionFields>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let include_archived = include_archived.unwrap_or(false);
+
+        connection::query(
+            after,
+            None,
+            first,
+            None,
+            |after: Option<DuplicateCursor>, _, first, _| async move {
+                let limit = first.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+
+                // Same trick as search_users, so the name comparison can use the trigram index
+                let mut tx = pool.begin().await?;
+                sqlx::query!(
+                    "SELECT set_config('pg_trgm.similarity_threshold', $1, true)",
+                    DUPLICATE_NAME_SIMILARITY_THRESHOLD.to_string()
+                )
+                .fetch_one(&mut tx)
+                .await?;
+
+                let total_count = sqlx::query!(
+                    "SELECT COUNT(*) FROM users first JOIN users second ON first.uuid < second.uuid
+                    WHERE (lower(first.email) = lower(second.email)
+                        OR first.phone_number = second.phone_number
+                        OR lower(immutable_unaccent(first.full_name)) % lower(immutable_unaccent(second.full_name)))
+                    AND ($1 OR (first.archive_time IS NULL AND second.archive_time IS NULL))",
+                    include_archived
+                )
+                .fetch_one(&mut tx)
+                .await?
+                .count
+                .unwrap_or(0);
+
+                // Sorted so that everything goes ascending, which lets the cursor be compared as
+                // one row. We grab one extra to find out if there is a next page.
+                let candidates = sqlx::query_as!(
+                    DuplicateCandidate,
+                    r#"SELECT first_uuid AS "first_uuid!", second_uuid AS "second_uuid!", same_email AS "same_email!",
+                        same_phone_number AS "same_phone_number!", name_similarity AS "name_similarity!"
+                    FROM (
+                        SELECT first.uuid AS first_uuid, second.uuid AS second_uuid,
+                            lower(first.email) = lower(second.email) AS same_email,
+                            COALESCE(first.phone_number = second.phone_number, false) AS same_phone_number,
+                            similarity(lower(immutable_unaccent(first.full_name)), lower(immutable_unaccent(second.full_name))) AS name_similarity
+                        FROM users first JOIN users second ON first.uuid < second.uuid
+                        WHERE (lower(first.email) = lower(second.email)
+                            OR first.phone_number = second.phone_number
+                            OR lower(immutable_unaccent(first.full_name)) % lower(immutable_unaccent(second.full_name)))
+                        AND ($1 OR (first.archive_time IS NULL AND second.archive_time IS NULL))
+                    ) candidates
+                    WHERE $2::uuid IS NULL
+                    OR (NOT same_email, NOT same_phone_number, -name_similarity, first_uuid, second_uuid)
+                        > (NOT $3, NOT $4, -$5::real, $2, $6)
+                    ORDER BY NOT same_email, NOT same_phone_number, -name_similarity, first_uuid, second_uuid
+                    LIMIT $7"#,
+                    include_archived,
+                    after.as_ref().map(|after| after.first_uuid),
+                    after.as_ref().map(|after| after.same_email),
+                    after.as_ref().map(|after| after.same_phone_number),
+                    after.as_ref().map(|after| after.name_similarity),
+                    after.as_ref().map(|after| after.second_uuid),
+                    limit as i64 + 1
+                )
+                .fetch_all(&mut tx)
+                .await?;
+                tx.commit().await?;
+
+                let mut connection = Connection::with_additional_fields(
+                    after.is_some(),
+                    candidates.len() > limit,
+                    ConnectionFields { total_count },
+                );
+                connection.append(
+                    candidates
+                        .into_iter()
+                        .take(limit)
+                        .map(|candidate| Edge::new(DuplicateCursor::new(&candidate), candidate)),
+                );
+                Ok(connection)
+            },
+        )
+        .await
+    }
+
+    // Kiosks need this to know which alt ID fields they are allowed to send
+    #[graphql(guard(or(
+        CapabilityGuard(capability = "TokenCapability::Collector"),
+        CapabilityGuard(capability = "TokenCapability::Viewer")
+    )))]
+    async fn alt_id_types(&self, ctx: &Context<'_>) -> Result<Vec<AltIdType>> {
         let pool = ctx.data::<Arc<PgPool>>()?;
 
         Ok(sqlx::query_as!(
-            User,
-            "SELECT * FROM users where full_name LIKE $1",
-            "%".to_string() + &full_name + "%"
+            AltIdType,
+            r#"SELECT name, description, format as "format: AltIdFormat", pattern,
+            case_normalization as "case_normalization: AltIdCase", is_unique, create_time
+            FROM alt_id_types ORDER BY name"#
+        )
+        .fetch_all(&**pool)
+        .await?)
+    }
+
+    #[graphql(guard(or(
+        CapabilityGuard(capability = "TokenCapability::Collector"),
+        CapabilityGuard(capability = "TokenCapability::Viewer")
+    )))]
+    async fn pending_identifiers(
+        &self,
+        ctx: &Context<'_>,
+        include_claimed: Option<bool>,
+    ) -> Result<Vec<PendingIdentifier>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        Ok(sqlx::query_as!(
+            PendingIdentifier,
+            "SELECT * FROM pending_identifiers WHERE $1 OR claimed_user_uuid IS NULL ORDER BY scan_time",
+            include_claimed.unwrap_or(false)
         )
         .fetch_all(&**pool)
         .await?)
     }
 
+    // Everyone signed in right now, oldest sign-in first. This is the fire drill headcount.
     #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Viewer")))]
-    async fn user_by_full_name_match(
+    async fn present_now(
         &self,
         ctx: &Context<'_>,
-        full_name: String,
-    ) -> Result<Vec<User>> {
+        location: Option<String>,
+    ) -> Result<Vec<Attendance>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        Ok(sqlx::query_as!(
+            Attendance,
+            "SELECT * FROM attendance WHERE out_time IS NULL AND in_time > now() - make_interval(hours => $1)
+            AND ($2::text IS NULL OR location = $2) ORDER BY in_time",
+            SIGN_OUT_WINDOW_HOURS,
+            location
+        )
+        .fetch_all(&**pool)
+        .await?)
+    }
+
+    // How many people were in between start and end, in buckets of `bucket` minutes.
+    // People who never signed out count until the sign-out window runs out.
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Viewer")))]
+    async fn occupancy(
+        &self,
+        ctx: &Context<'_>,
+        location: Option<String>,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        #[graphql(default = 60)] bucket: i32,
+    ) -> Result<Vec<OccupancyBucket>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        if bucket <= 0 {
+            return Err(async_graphql::Error::new(
+                "bucket must be at least one minute",
+            ));
+        }
+        if end <= start {
+            return Err(async_graphql::Error::new("end must be after start"));
+        }
+        if (end - start).num_minutes() / i64::from(bucket) >= MAX_OCCUPANCY_BUCKETS {
+            return Err(async_graphql::Error::new(format!(
+                "That would be more than {} buckets. Use a bigger bucket or a shorter range.",
+                MAX_OCCUPANCY_BUCKETS
+            )));
+        }
+
+        // One pass over every sign-in (+1) and sign-out (-1) in time order, keeping a running
+        // count. Each bucket's start goes in too, after anything at the same moment, so the
+        // count there is who was in when it started. The peak in a bucket is the highest
+        // count at its start or right after someone arrives.
+        Ok(sqlx::query_as!(
+            OccupancyBucket,
+            r#"WITH stays AS (
+                SELECT in_time, COALESCE(out_time, LEAST(in_time + make_interval(hours => $4), now())) AS out_time
+                FROM attendance
+                WHERE ($5::text IS NULL OR location = $5) AND in_time < $2
+                AND COALESCE(out_time, in_time + make_interval(hours => $4)) > $1
+            ), changes AS (
+                SELECT out_time AS time, -1 AS change, 0 AS ord FROM stays
+                UNION ALL SELECT in_time, 1, 1 FROM stays
+                UNION ALL SELECT bucket_start, 0, 2
+                FROM generate_series($1, $2 - interval '1 microsecond', make_interval(mins => $3)) AS bucket_start
+            ), levels AS (
+                SELECT time, ord, SUM(change) OVER (ORDER BY time, ord ROWS UNBOUNDED PRECEDING) AS level
+                FROM changes
+            ), bucketed AS (
+                SELECT floor(extract(epoch FROM time - $1) / ($3 * 60))::int AS n, ord, level
+                FROM levels WHERE time >= $1 AND time < $2
+            )
+            SELECT $1 + n * make_interval(mins => $3) AS "start!",
+            LEAST($1 + (n + 1) * make_interval(mins => $3), $2) AS "end!",
+            MAX(level) FILTER (WHERE ord = 2) AS "at_start!",
+            MAX(level) FILTER (WHERE ord <> 0) AS "peak!",
+            COUNT(*) FILTER (WHERE ord = 1) AS "arrivals!"
+            FROM bucketed GROUP BY n ORDER BY n"#,
+            start,
+            end,
+            bucket,
+            SIGN_OUT_WINDOW_HOURS,
+            location
+        )
+        .fetch_all(&**pool)
+        .await?)
+    }
+
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Viewer")))]
+    async fn roll_call(&self, ctx: &Context<'_>, id: i32) -> Result<Option<RollCall>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        Ok(
+            sqlx::query_as!(RollCall, "SELECT * FROM roll_calls WHERE id=$1", id)
+                .fetch_optional(&**pool)
+                .await?,
+        )
+    }
+
+    // Newest first. Ended roll calls are left out unless asked for.
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Viewer")))]
+    async fn roll_calls(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = false)] include_ended: bool,
+    ) -> Result<Vec<RollCall>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        Ok(sqlx::query_as!(
+            RollCall,
+            "SELECT * FROM roll_calls WHERE $1 OR end_time IS NULL ORDER BY start_time DESC",
+            include_ended
+        )
+        .fetch_all(&**pool)
+        .await?)
+    }
+
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Viewer")))]
+    async fn find_attendance(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<AttendanceFilter>,
+        order_by: Option<AttendanceOrderBy>,
+        first: Option<i32>,
+        after: Option<String>,
+    ) -> Result<Connection<AttendanceCursor, Attendance, ConnectionFields>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let filter = filter.unwrap_or_default();
+
+        let user_uuids = match &filter.user_uuids {
+            Some(user_uuids) => Some(
+                user_uuids
+                    .iter()
+                    .map(|uuid| Uuid::parse_str(uuid))
+                    .collect::<Result<Vec<Uuid>, _>>()?,
+            ),
+            None => None,
+        };
+        let device_token_uuid = match &filter.device_token_uuid {
+            Some(uuid) => Some(Uuid::parse_str(uuid)?),
+            None => None,
+        };
+        let (order_field, descending) = match order_by {
+            Some(order_by) => (
+                match order_by.field {
+                    AttendanceOrderField::InTime => "in_time",
+                    AttendanceOrderField::OutTime => "out_time",
+                },
+                order_by.direction == OrderDirection::Desc,
+            ),
+            // Most recent first is what every dashboard wants
+            None => ("in_time", true),
+        };
+
+        connection::query(
+            after,
+            None,
+            first,
+            None,
+            |after: Option<AttendanceCursor>, _, first, _| async move {
+                let limit = first.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+                let (after_id, after_time) = match &after {
+                    Some(after) => (Some(after.id), after.time),
+                    None => (None, None),
+                };
+
+                let total_count = sqlx::query!(
+                    "SELECT COUNT(*) FROM attendance
+                    WHERE ($1::uuid[] IS NULL OR user_uuid = ANY($1))
+                    AND ($2::timestamptz IS NULL OR in_time >= $2)
+                    AND ($3::timestamptz IS NULL OR in_time < $3)
+                    AND ($4::text IS NULL OR location = $4)
+                    AND ($5::text IS NULL OR event = $5)
+                    AND ($6::uuid IS NULL OR device_token_uuid = $6)
+                    AND ($7::bool IS NULL OR (out_time IS NULL) = $7)",
+                    user_uuids.as_deref(),
+                    filter.start_time,
+                    filter.end_time,
+                    filter.location,
+                    filter.event,
+                    device_token_uuid,
+                    filter.open
+                )
+                .fetch_one(&**pool)
+                .await?
+                .count
+                .unwrap_or(0);
+
+                // We grab one extra row to find out if there is a next page
+                let attendance = sqlx::query_as!(
+                    Attendance,
+                    "SELECT * FROM attendance
+                    WHERE ($1::uuid[] IS NULL OR user_uuid = ANY($1))
+                    AND ($2::timestamptz IS NULL OR in_time >= $2)
+                    AND ($3::timestamptz IS NULL OR in_time < $3)
+                    AND ($4::text IS NULL OR location = $4)
+                    AND ($5::text IS NULL OR event = $5)
+                    AND ($6::uuid IS NULL OR device_token_uuid = $6)
+                    AND ($7::bool IS NULL OR (out_time IS NULL) = $7)
+                    -- Open sessions have no out time, and sort after the rest going forwards
+                    -- and before them going backwards
+                    AND ($11::int IS NULL OR CASE
+                        WHEN $8 = 'in_time' AND NOT $9 THEN in_time > $12 OR (in_time = $12 AND id > $11)
+                        WHEN $8 = 'in_time' AND $9 THEN in_time < $12 OR (in_time = $12 AND id > $11)
+                        WHEN NOT $9 AND $12 IS NULL THEN out_time IS NULL AND id > $11
+                        WHEN NOT $9 THEN out_time > $12 OR out_time IS NULL OR (out_time = $12 AND id > $11)
+                        WHEN $12 IS NULL THEN out_time IS NOT NULL OR id > $11
+                        ELSE out_time < $12 OR (out_time = $12 AND id > $11)
+                    END)
+                    ORDER BY
+                        CASE WHEN $8 = 'in_time' AND NOT $9 THEN in_time END ASC,
+                        CASE WHEN $8 = 'in_time' AND $9 THEN in_time END DESC,
+                        CASE WHEN $8 = 'out_time' AND NOT $9 THEN out_time END ASC NULLS LAST,
+                        CASE WHEN $8 = 'out_time' AND $9 THEN out_time END DESC NULLS FIRST,
+                        id
+                    LIMIT $10",
+                    user_uuids.as_deref(),
+                    filter.start_time,
+                    filter.end_time,
+                    filter.location,
+                    filter.event,
+                    device_token_uuid,
+                    filter.open,
+                    order_field,
+                    descending,
+                    limit as i64 + 1,
+                    after_id,
+                    after_time
+                )
+                .fetch_all(&**pool)
+                .await?;
+
+                let mut connection = Connection::with_additional_fields(
+                    after.is_some(),
+                    attendance.len() > limit,
+                    ConnectionFields { total_count },
+                );
+                connection.append(attendance.into_iter().take(limit).map(|attendance| {
+                    let time = match order_field {
+                        "out_time" => attendance.out_time,
+                        _ => Some(attendance.in_time),
+                    };
+                    Edge::new(
+                        AttendanceCursor {
+                            time,
+                            id: attendance.id,
+                        },
+                        attendance,
+                    )
+                }));
+                Ok(connection)
+            },
+        )
+        .await
+    }
+
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn audit_log(
+        &self,
+        ctx: &Context<'_>,
+        subject_user_uuid: Option<String>,
+        action: Option<String>,
+        first: Option<i32>,
+        after: Option<String>,
+    ) -> Result<Connection<usize, AuditEntry, ConnectionFields>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let subject_user_uuid = match subject_user_uuid {
+            Some(uuid) => Some(Uuid::parse_str(&uuid)?),
+            None => None,
+        };
+
+        connection::query(
+            after,
+            None,
+            first,
+            None,
+            |after: Option<usize>, _, first, _| async move {
+                let offset = after.map(|after| after + 1).unwrap_or(0);
+                let limit = first.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+
+                let total_count = sqlx::query!(
+                    "SELECT COUNT(*) FROM audit_log
+                    WHERE ($1::uuid IS NULL OR subject_user_uuid = $1)
+                    AND ($2::text IS NULL OR action = $2)",
+                    subject_user_uuid,
+                    action
+                )
+                .fetch_one(&**pool)
+                .await?
+                .count
+                .unwrap_or(0);
+
+                let entries = sqlx::query_as!(
+                    AuditEntry,
+                    "SELECT * FROM audit_log
+                    WHERE ($1::uuid IS NULL OR subject_user_uuid = $1)
+                    AND ($2::text IS NULL OR action = $2)
+                    ORDER BY create_time DESC, id DESC
+                    LIMIT $3 OFFSET $4",
+                    subject_user_uuid,
+                    action,
+                    limit as i64 + 1,
+                    offset as i64
+                )
+                .fetch_all(&**pool)
+                .await?;
+
+                let mut connection = Connection::with_additional_fields(
+                    offset > 0,
+                    entries.len() > limit,
+                    ConnectionFields { total_count },
+                );
+                connection.append(
+                    entries
+                        .into_iter()
+                        .take(limit)
+                        .enumerate()
+                        .map(|(index, entry)| Edge::new(offset + index, entry)),
+                );
+                Ok(connection)
+            },
+        )
+        .await
+    }
+
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn webhooks(&self, ctx: &Context<'_>) -> Result<Vec<Webhook>> {
         let pool = ctx.data::<Arc<PgPool>>()?;
 
         Ok(
-            sqlx::query_as!(User, "SELECT * FROM users where full_name=$1", &full_name)
+            sqlx::query_as!(Webhook, "SELECT * FROM webhooks ORDER BY id")
                 .fetch_all(&**pool)
                 .await?,
         )
     }
 
-    // Restrict to just collectors since the viewers should always be able to use the other thing
-    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Collector")))]
-    async fn user_by_alt_id_field(
+    // Newest first
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn webhook_deliveries(
         &self,
         ctx: &Context<'_>,
-        alt_field: String,
-        alt_value: String,
-    ) -> Result<Option<User>> {
+        webhook_id: Option<i32>,
+        status: Option<WebhookDeliveryStatus>,
+        first: Option<i32>,
+        after: Option<String>,
+    ) -> Result<Connection<usize, WebhookDelivery, ConnectionFields>> {
         let pool = ctx.data::<Arc<PgPool>>()?;
 
-        Ok(Some(
-            sqlx::query_as!(
-                User,
-                "SELECT * FROM users where alt_id_fields->($1) = ($2)",
-                alt_field,
-                serde_json::to_value(alt_value)?
-            )
-            .fetch_one(&**pool)
-            .await?,
-        ))
+        connection::query(
+            after,
+            None,
+            first,
+            None,
+            |after: Option<usize>, _, first, _| async move {
+                let offset = after.map(|after| after + 1).unwrap_or(0);
+                let limit = first.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+
+                let total_count = sqlx::query!(
+                    r#"SELECT COUNT(*) FROM webhook_deliveries
+                    WHERE ($1::int IS NULL OR webhook_id = $1)
+                    AND ($2::webhook_delivery_status IS NULL OR status = $2)"#,
+                    webhook_id,
+                    status as Option<WebhookDeliveryStatus>
+                )
+                .fetch_one(&**pool)
+                .await?
+                .count
+                .unwrap_or(0);
+
+                let deliveries = sqlx::query_as!(
+                    WebhookDelivery,
+                    r#"SELECT id, webhook_id, event_type, payload, status as "status: WebhookDeliveryStatus",
+                    attempts, next_attempt_time, last_attempt_time, last_response_status, last_error,
+                    delivered_time, create_time
+                    FROM webhook_deliveries
+                    WHERE ($1::int IS NULL OR webhook_id = $1)
+                    AND ($2::webhook_delivery_status IS NULL OR status = $2)
+                    ORDER BY id DESC
+                    LIMIT $3 OFFSET $4"#,
+                    webhook_id,
+                    status as Option<WebhookDeliveryStatus>,
+                    limit as i64 + 1,
+                    offset as i64
+                )
+                .fetch_all(&**pool)
+                .await?;
+
+                let mut connection = Connection::with_additional_fields(
+                    offset > 0,
+                    deliveries.len() > limit,
+                    ConnectionFields { total_count },
+                );
+                connection.append(
+                    deliveries
+                        .into_iter()
+                        .take(limit)
+                        .enumerate()
+                        .map(|(index, delivery)| Edge::new(offset + index, delivery)),
+                );
+                Ok(connection)
+            },
+        )
+        .await
+    }
+
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn notification_templates(&self, ctx: &Context<'_>) -> Result<Vec<NotificationTemplate>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        Ok(sqlx::query_as!(
+            NotificationTemplate,
+            r#"SELECT kind as "kind: NotificationKind", subject, body, update_time FROM notification_templates ORDER BY kind"#
+        )
+        .fetch_all(&**pool)
+        .await?)
+    }
+
+    // Every e-mail that was queued, newest first
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn notifications(
+        &self,
+        ctx: &Context<'_>,
+        kind: Option<NotificationKind>,
+        status: Option<NotificationStatus>,
+        recipient: Option<String>,
+        first: Option<i32>,
+        after: Option<String>,
+    ) -> Result<Connection<usize, Notification, ConnectionFields>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        connection::query(
+            after,
+            None,
+            first,
+            None,
+            |after: Option<usize>, _, first, _| async move {
+                let offset = after.map(|after| after + 1).unwrap_or(0);
+                let limit = first.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+
+                let total_count = sqlx::query!(
+                    r#"SELECT COUNT(*) FROM notifications
+                    WHERE ($1::notification_kind IS NULL OR kind = $1)
+                    AND ($2::notification_status IS NULL OR status = $2)
+                    AND ($3::text IS NULL OR recipient = lower($3))"#,
+                    kind as Option<NotificationKind>,
+                    status as Option<NotificationStatus>,
+                    recipient
+                )
+                .fetch_one(&**pool)
+                .await?
+                .count
+                .unwrap_or(0);
+
+                let notifications = sqlx::query_as!(
+                    Notification,
+                    r#"SELECT id, kind as "kind: NotificationKind", recipient, data, subject, body,
+                    status as "status: NotificationStatus", attempts, next_attempt_time, last_error,
+                    sent_time, create_time
+                    FROM notifications
+                    WHERE ($1::notification_kind IS NULL OR kind = $1)
+                    AND ($2::notification_status IS NULL OR status = $2)
+                    AND ($3::text IS NULL OR recipient = lower($3))
+                    ORDER BY id DESC
+                    LIMIT $4 OFFSET $5"#,
+                    kind as Option<NotificationKind>,
+                    status as Option<NotificationStatus>,
+                    recipient,
+                    limit as i64 + 1,
+                    offset as i64
+                )
+                .fetch_all(&**pool)
+                .await?;
+
+                let mut connection = Connection::with_additional_fields(
+                    offset > 0,
+                    notifications.len() > limit,
+                    ConnectionFields { total_count },
+                );
+                connection.append(
+                    notifications
+                        .into_iter()
+                        .take(limit)
+                        .enumerate()
+                        .map(|(index, notification)| Edge::new(offset + index, notification)),
+                );
+                Ok(connection)
+            },
+        )
+        .await
+    }
+
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn jobs(&self, ctx: &Context<'_>) -> Result<Vec<Job>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        Ok(sqlx::query_as!(
+            Job,
+            r#"SELECT kind as "kind: JobKind", schedule, enabled, next_run_time FROM jobs ORDER BY kind"#
+        )
+        .fetch_all(&**pool)
+        .await?)
     }
 
+    // Events that overlap from-to, soonest first. Recurring events are listed by their first
+    // occurrence, so they're included whenever that's before `to`.
     #[graphql(guard(or(
         CapabilityGuard(capability = "TokenCapability::Collector"),
         CapabilityGuard(capability = "TokenCapability::Viewer")
     )))]
-    async fn user_by_uuid(&self, ctx: &Context<'_>, uuid: String) -> Result<Option<User>> {
+    async fn events(
+        &self,
+        ctx: &Context<'_>,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+        #[graphql(default = false)] include_cancelled: bool,
+    ) -> Result<Vec<Event>> {
         let pool = ctx.data::<Arc<PgPool>>()?;
-        let uuid = match Uuid::parse_str(&uuid) {
-            Ok(uuid) => uuid,
-            Err(_) => return Ok(None), // I'll do this since there would be no results for an invalid UUID
-        };
 
-        Ok(Some(
-            sqlx::query_as!(User, "SELECT * FROM users where uuid=$1", uuid)
-                .fetch_one(&**pool)
-                .await?,
-        ))
+        Ok(sqlx::query_as!(
+            Event,
+            "SELECT * FROM events
+            WHERE ($1::timestamptz IS NULL OR end_time > $1 OR recurrence <> '{}')
+            AND ($2::timestamptz IS NULL OR start_time < $2)
+            AND ($3 OR NOT cancelled)
+            ORDER BY start_time, id",
+            from,
+            to,
+            include_cancelled
+        )
+        .fetch_all(&**pool)
+        .await?)
     }
 
-    // This seems really wasteful (less code by consolidating each "user_by" into one thing?)
     #[graphql(guard(or(
         CapabilityGuard(capability = "TokenCapability::Collector"),
         CapabilityGuard(capability = "TokenCapability::Viewer")
     )))]
-    async fn user_by_email(&self, ctx: &Context<'_>, email: String) -> Result<Option<User>> {
+    async fn event(&self, ctx: &Context<'_>, id: i32) -> Result<Option<Event>> {
         let pool = ctx.data::<Arc<PgPool>>()?;
 
-        Ok(Some(
-            sqlx::query_as!(User, "SELECT * FROM users where email=$1", email)
-                .fetch_one(&**pool)
+        Ok(
+            sqlx::query_as!(Event, "SELECT * FROM events WHERE id=$1", id)
+                .fetch_optional(&**pool)
                 .await?,
-        ))
+        )
     }
 
-    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Viewer")))]
-    async fn attendance(&self, ctx: &Context<'_>) -> Result<Vec<Attendance>> {
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn calendar_feed_keys(&self, ctx: &Context<'_>) -> Result<Vec<CalendarFeedKey>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        Ok(sqlx::query_as!(
+            CalendarFeedKey,
+            "SELECT * FROM calendar_feed_keys ORDER BY create_time"
+        )
+        .fetch_all(&**pool)
+        .await?)
+    }
+
+    // Newest first. Leaves out expired and revoked tokens unless includeInactive is set.
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn tokens(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = false)] include_inactive: bool,
+    ) -> Result<Vec<Token>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        Ok(sqlx::query_as!(
+            Token,
+            r#"SELECT uuid, description, initial_valid_time, expiration_time, create_time,
+            capability as "capability: TokenCapability", user_uuid, account_uuid, refresh_token_hash, revoke_time
+            FROM tokens WHERE $1 OR (expiration_time > now() AND revoke_time IS NULL)
+            ORDER BY create_time DESC"#,
+            include_inactive
+        )
+        .fetch_all(&**pool)
+        .await?)
+    }
+
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn accounts(&self, ctx: &Context<'_>) -> Result<Vec<Account>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        Ok(sqlx::query_as!(
+            Account,
+            r#"SELECT uuid, email, full_name, capability as "capability: TokenCapability", user_uuid,
+            password_hash, disabled, create_time, last_login_time FROM accounts ORDER BY email"#
+        )
+        .fetch_all(&**pool)
+        .await?)
+    }
+
+    // The member a Member token belongs to
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Member")))]
+    async fn me(&self, ctx: &Context<'_>) -> Result<Me> {
         let pool = ctx.data::<Arc<PgPool>>()?;
-        Ok(sqlx::query_as!(Attendance, "SELECT * FROM attendance")
-            .fetch_all(&**pool)
-            .await?)
+        let user_uuid = member_user_uuid(ctx)?;
+
+        match sqlx::query!("SELECT full_name FROM users WHERE uuid=$1", user_uuid)
+            .fetch_optional(&**pool)
+            .await?
+        {
+            Some(user) => Ok(Me {
+                uuid: user_uuid,
+                full_name: user.full_name,
+            }),
+            None => Err(async_graphql::Error::new("User not found!")),
+        }
     }
 
+    // Everyone's excuses, like for a mentor to go through the pending ones
     #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Viewer")))]
-    async fn attendance_by_date(
+    async fn excuses(
         &self,
         ctx: &Context<'_>,
-        date: DateTime<Utc>,
-    ) -> Result<Vec<Attendance>> {
+        user_uuid: Option<String>,
+        status: Option<ExcuseStatus>,
+    ) -> Result<Vec<Excuse>> {
         let pool = ctx.data::<Arc<PgPool>>()?;
+        let user_uuid = match user_uuid {
+            Some(user_uuid) => Some(Uuid::parse_str(&user_uuid)?),
+            None => None,
+        };
+
         Ok(sqlx::query_as!(
-            Attendance,
-            "SELECT * FROM attendance WHERE in_time >= $1 AND in_time <= $1",
-            date
+            Excuse,
+            r#"SELECT id, user_uuid, date, reason, status as "status: ExcuseStatus", create_time,
+            review_time, reviewed_by_token_uuid, review_note FROM excuses
+            WHERE ($1::uuid IS NULL OR user_uuid = $1) AND ($2::excuse_status IS NULL OR status = $2)
+            ORDER BY date DESC, id DESC"#,
+            user_uuid,
+            status as Option<ExcuseStatus>
         )
         .fetch_all(&**pool)
         .await?)
     }
+
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn checkin_codes(&self, ctx: &Context<'_>) -> Result<Vec<CheckinCode>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        Ok(
+            sqlx::query_as!(CheckinCode, "SELECT * FROM checkin_codes ORDER BY id")
+                .fetch_all(&**pool)
+                .await?,
+        )
+    }
 }
 
 #[Object]
@@ -158,12 +1087,21 @@ impl Mutation {
         #[graphql(validator(Email))] email: String,
         #[graphql(validator(PhoneNumber))] phone_number: Option<String>,
         alt_id_fields: Option<HashMap<String, String>>,
+        groups: Option<Vec<String>>,
     ) -> Result<User> {
         let pool = ctx.data::<Arc<PgPool>>()?;
 
+        check_email_available(pool, &email, None).await?;
+        let phone_number = match phone_number {
+            Some(phone_number) => Some(normalize_phone_number(&phone_number)?),
+            None => None,
+        };
+
         // We would use map, but it makes it harder to bubble Result errors from the function
         let mapped_alt_id_fields = if let Some(unwrap_alt_id_fields) = alt_id_fields {
-            Some(serde_json::to_value(unwrap_alt_id_fields)?)
+            Some(serde_json::to_value(
+                normalize_alt_id_fields(pool, unwrap_alt_id_fields, None).await?,
+            )?)
         } else {
             None
         };
@@ -176,12 +1114,16 @@ impl Mutation {
             create_time: Utc::now(),
             update_time: None,
             alt_id_fields: mapped_alt_id_fields,
+            groups: groups.unwrap_or_default(),
+            archive_time: None,
+            archive_reason: None,
+            version: 0,
         };
 
         // formatter won't format this for some reason
         new_user.uuid = sqlx::query!(
-            "INSERT INTO users (full_name, email, phone_number, create_time, alt_id_fields) VALUES ($1, $2, $3, $4, $5) RETURNING uuid",
-            new_user.full_name, new_user.email, new_user.phone_number, new_user.create_time, new_user.alt_id_fields)
+            "INSERT INTO users (full_name, email, phone_number, create_time, alt_id_fields, groups) VALUES ($1, $2, $3, $4, $5, $6) RETURNING uuid",
+            new_user.full_name, new_user.email, new_user.phone_number, new_user.create_time, new_user.alt_id_fields, &new_user.groups)
             .fetch_one(&**pool)
             .await?
             .uuid;
@@ -189,7 +1131,23 @@ impl Mutation {
         Ok(new_user)
     }
 
+    // Creates and updates users from a CSV or JSON file. Dry runs only report what would happen;
+    // otherwise everything is written in one transaction, and only if no row has problems.
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn import_users(
+        &self,
+        ctx: &Context<'_>,
+        format: ImportFormat,
+        data: String,
+        #[graphql(default = true)] dry_run: bool,
+    ) -> Result<ImportReport> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        crate::import::import_users(pool, format, &data, dry_run, device_token_uuid(ctx)?).await
+    }
+
     // TODO make a function to *append* alt_id_fields
+    #[allow(clippy::too_many_arguments)]
     #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Collector")))]
     async fn update_user(
         &self,
@@ -199,6 +1157,8 @@ impl Mutation {
         #[graphql(validator(Email))] email: Option<String>,
         #[graphql(validator(PhoneNumber))] phone_number: Option<String>,
         alt_id_fields: Option<HashMap<String, String>>,
+        groups: Option<Vec<String>>,
+        expected_version: Option<i32>,
     ) -> Result<User> {
         let pool = ctx.data::<Arc<PgPool>>()?;
 
@@ -213,34 +1173,139 @@ impl Mutation {
             Some(user) => user,
             None => return Err(async_graphql::Error::new("User to modify not found!")),
         };
-
+        let loaded_version = user.version;
+        if let Some(expected_version) = expected_version {
+            if expected_version != loaded_version {
+                return Err(version_conflict(loaded_version));
+            }
+        }
+
         if let Some(full_name) = full_name {
             user.full_name = full_name;
         }
         if let Some(email) = email {
+            check_email_available(pool, &email, Some(user.uuid)).await?;
             user.email = email;
         }
         if let Some(phone_number_data) = phone_number {
-            user.phone_number = Some(phone_number_data);
+            user.phone_number = Some(normalize_phone_number(&phone_number_data)?);
         }
         if let Some(alt_id_fields_unwrapped) = alt_id_fields {
-            user.alt_id_fields = Some(serde_json::to_value(alt_id_fields_unwrapped)?);
+            user.alt_id_fields = Some(serde_json::to_value(
+                normalize_alt_id_fields(pool, alt_id_fields_unwrapped, Some(user.uuid)).await?,
+            )?);
+        }
+        if let Some(groups) = groups {
+            user.groups = groups;
         }
 
-        sqlx::query!(
-            "UPDATE users SET (full_name, email, phone_number, alt_id_fields) = ($1, $2, $3, $4) WHERE uuid=$5",
+        // Everything above was worked out from loaded_version, so only write if nobody else got
+        // there first. Otherwise we'd put their changes back to what we loaded.
+        match sqlx::query!(
+            "UPDATE users SET (full_name, email, phone_number, alt_id_fields, groups) = ($1, $2, $3, $4, $5) WHERE uuid=$6 AND version=$7 RETURNING update_time, version",
             user.full_name,
             user.email,
             user.phone_number,
             user.alt_id_fields,
+            &user.groups,
+            user.uuid,
+            loaded_version
+        )
+        .fetch_optional(&**pool)
+        .await?
+        {
+            Some(record) => {
+                user.update_time = record.update_time;
+                user.version = record.version;
+            }
+            None => {
+                let current_version = sqlx::query!(
+                    "SELECT version FROM users WHERE uuid=$1",
+                    user.uuid
+                )
+                .fetch_one(&**pool)
+                .await?
+                .version;
+                return Err(version_conflict(current_version));
+            }
+        }
+
+        Ok(user)
+    }
+
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn archive_user(
+        &self,
+        ctx: &Context<'_>,
+        uuid: String,
+        reason: Option<String>,
+    ) -> Result<User> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        let mut user = match sqlx::query_as!(
+            User,
+            "SELECT * FROM users WHERE uuid=$1",
+            Uuid::parse_str(&uuid)?
+        )
+        .fetch_optional(&**pool)
+        .await?
+        {
+            Some(user) if user.archive_time.is_none() => user,
+            Some(_) => return Err(async_graphql::Error::new("User is already archived")),
+            None => return Err(async_graphql::Error::new("User to archive not found!")),
+        };
+
+        user.archive_time = Some(Utc::now());
+        user.archive_reason = reason;
+
+        let record = sqlx::query!(
+            "UPDATE users SET (archive_time, archive_reason) = ($1, $2) WHERE uuid=$3 RETURNING update_time, version",
+            user.archive_time,
+            user.archive_reason,
             user.uuid
         )
-        .execute(&**pool)
+        .fetch_one(&**pool)
+        .await?;
+        user.update_time = record.update_time;
+        user.version = record.version;
+
+        Ok(user)
+    }
+
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn restore_user(&self, ctx: &Context<'_>, uuid: String) -> Result<User> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        let mut user = match sqlx::query_as!(
+            User,
+            "SELECT * FROM users WHERE uuid=$1",
+            Uuid::parse_str(&uuid)?
+        )
+        .fetch_optional(&**pool)
+        .await?
+        {
+            Some(user) if user.archive_time.is_some() => user,
+            Some(_) => return Err(async_graphql::Error::new("User is not archived")),
+            None => return Err(async_graphql::Error::new("User to restore not found!")),
+        };
+        check_email_available(pool, &user.email, Some(user.uuid)).await?;
+
+        user.archive_time = None;
+        user.archive_reason = None;
+
+        let record = sqlx::query!(
+            "UPDATE users SET (archive_time, archive_reason) = (NULL, NULL) WHERE uuid=$1 RETURNING update_time, version",
+            user.uuid
+        )
+        .fetch_one(&**pool)
         .await?;
+        user.update_time = record.update_time;
+        user.version = record.version;
 
         Ok(user)
     }
 
+    #[allow(clippy::too_many_arguments)]
     #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Collector")))]
     async fn log_attendance(
         &self,
@@ -249,135 +1314,2647 @@ impl Mutation {
         email: Option<String>,
         alt_id_field: Option<String>,
         alt_id_value: Option<String>,
+        location: Option<String>,
+        event: Option<String>,
+        // The contents of a scanned QR code badge
+        badge: Option<String>,
     ) -> Result<Attendance> {
         let pool = ctx.data::<Arc<PgPool>>()?;
+        let device_token_uuid = device_token_uuid(ctx)?;
 
-        // Chosen in the order of uuid first, then email, then alt_id values.
+        // Chosen in the order of uuid first, then email, then alt_id values, then badge.
 
         let uuid_parsed = if let Some(uuid_unwrapped) = uuid {
             Uuid::parse_str(&uuid_unwrapped)?
         } else if let Some(email_unwrapped) = email {
             // Query the server to find the uuid
-            sqlx::query!("SELECT uuid FROM users WHERE email=$1", email_unwrapped)
-                .fetch_one(&**pool)
-                .await?
-                .uuid
+            // Archived users can share an e-mail with an active one, so the active one wins
+            match sqlx::query!(
+                "SELECT uuid FROM users WHERE lower(email)=lower($1) ORDER BY archive_time NULLS FIRST LIMIT 1",
+                email_unwrapped
+            )
+            .fetch_optional(&**pool)
+            .await?
+            {
+                Some(record) => record.uuid,
+                None => {
+                    return Err(async_graphql::Error::new(format!(
+                        "No user has the e-mail {}",
+                        email_unwrapped
+                    )))
+                }
+            }
         } else if let (Some(alt_id_field_unwrapped), Some(alt_id_value_unwrapped)) =
             (alt_id_field, alt_id_value)
         {
+            // Scanned values go through the same normalization as the stored ones
+            let alt_id_value_normalized = fetch_alt_id_type(pool, &alt_id_field_unwrapped)
+                .await?
+                .normalize(&alt_id_value_unwrapped)?;
+
             // Kinda copied from find_user_by_alt_id
-            sqlx::query_as!(
+            match sqlx::query_as!(
                 User,
                 "SELECT * FROM users where alt_id_fields->($1) = ($2)",
                 alt_id_field_unwrapped,
-                serde_json::to_value(alt_id_value_unwrapped)?
+                serde_json::to_value(&alt_id_value_normalized)?
             )
-            .fetch_one(&**pool)
+            .fetch_optional(&**pool)
             .await?
-            .uuid
+            {
+                Some(user) => user.uuid,
+                None => {
+                    // Nobody has this card yet, so keep the scan around until someone enrolls it
+                    let pending_id = sqlx::query!(
+                        "INSERT INTO pending_identifiers (alt_id_field, alt_id_value, device_token_uuid, scan_time, location, event) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+                        alt_id_field_unwrapped,
+                        alt_id_value_normalized,
+                        device_token_uuid,
+                        Utc::now(),
+                        location,
+                        event
+                    )
+                    .fetch_one(&**pool)
+                    .await?
+                    .id;
+
+                    return Err(async_graphql::Error::new(format!(
+                        "Unknown {} {}. The scan was saved for enrollment.",
+                        alt_id_field_unwrapped, alt_id_value_normalized
+                    ))
+                    .extend_with(|_, e| e.set("pendingIdentifierId", pending_id)));
+                }
+            }
+        } else if let Some(badge_unwrapped) = badge {
+            crate::badges::verify_badge(pool, &badge_unwrapped).await?
         } else {
             // I'm pretty sure this code will never get executed
             return Err(async_graphql::Error::new(
-                "You must specify either a UUID or a user's e-mail",
+                "You must specify either a UUID, a user's e-mail, an alt ID or a badge",
             ));
         };
 
-        // Check if the user has an entry without an out time
+        debug!("uuid_parsed was {:?}", uuid_parsed);
 
-        if let Some(mut attendance) = sqlx::query_as!(
-            Attendance,
-            "SELECT * FROM attendance WHERE user_uuid=$1 ORDER BY in_time DESC LIMIT 1",
-            uuid_parsed
-        )
-        .fetch_optional(&**pool)
-        .await?
+        match sqlx::query!("SELECT archive_time FROM users WHERE uuid=$1", uuid_parsed)
+            .fetch_optional(&**pool)
+            .await?
         {
-            if let None = attendance.out_time {
-                // We only let people sign out three hours or less after sign in (otherwise we sign in instead of sign out)
-                // TODO make this amount configurable
-                if (Utc::now() - attendance.in_time).num_hours() <= 3 {
-                    // Run an update query, as the user checked in, but not out
-
-                    attendance.out_time = sqlx::query!(
-                        "UPDATE attendance SET out_time=$1 WHERE id=$2 RETURNING out_time",
-                        Utc::now(),
-                        attendance.id
-                    )
-                    .fetch_one(&**pool)
-                    .await?
-                    .out_time;
+            Some(user) if user.archive_time.is_some() => {
+                return Err(async_graphql::Error::new(
+                    "This user is archived and can't sign in or out",
+                ))
+            }
+            Some(_) => {}
+            None => return Err(async_graphql::Error::new("User not found!")),
+        }
 
-                    return Ok(attendance);
-                }
+        record_attendance(
+            &mut *pool.acquire().await?,
+            uuid_parsed,
+            Utc::now(),
+            location,
+            event,
+            device_token_uuid,
+        )
+        .await
+    }
+
+    // Signing in (or out) from your own phone with the code on the screen at a location. The
+    // location and event come from the code, so people can't say they're somewhere else, and
+    // who is signing in comes from the member token, so nobody can read the code out to a
+    // friend who isn't there.
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Member")))]
+    async fn check_in_with_code(&self, ctx: &Context<'_>, code: String) -> Result<Attendance> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let device_token_uuid = device_token_uuid(ctx)?;
+        let user_uuid = member_user_uuid(ctx)?;
+
+        let checkin_code = checkin::find_checkin_code(pool, &code).await?;
+
+        match sqlx::query!("SELECT archive_time FROM users WHERE uuid=$1", user_uuid)
+            .fetch_optional(&**pool)
+            .await?
+        {
+            Some(user) if user.archive_time.is_none() => {}
+            Some(_) => {
+                return Err(async_graphql::Error::new(
+                    "This user is archived and can't sign in or out",
+                ))
             }
+            None => return Err(async_graphql::Error::new("User not found!")),
         }
 
-        debug!("uuid_parsed was {:?}", uuid_parsed);
+        record_attendance(
+            &mut *pool.acquire().await?,
+            user_uuid,
+            Utc::now(),
+            checkin_code.location,
+            checkin_code.event,
+            device_token_uuid,
+        )
+        .await
+    }
+
+    // Attaches a pending card to a user who is already enrolled
+    #[graphql(guard(or(
+        CapabilityGuard(capability = "TokenCapability::Collector"),
+        CapabilityGuard(capability = "TokenCapability::Viewer")
+    )))]
+    async fn claim_pending_identifier(
+        &self,
+        ctx: &Context<'_>,
+        pending_identifier_id: i32,
+        user_uuid: String,
+    ) -> Result<User> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let pending_identifier =
+            fetch_unclaimed_pending_identifier(pool, pending_identifier_id).await?;
+
+        let mut user = match sqlx::query_as!(
+            User,
+            "SELECT * FROM users WHERE uuid=$1",
+            Uuid::parse_str(&user_uuid)?
+        )
+        .fetch_optional(&**pool)
+        .await?
+        {
+            Some(user) if user.archive_time.is_none() => user,
+            Some(_) => {
+                return Err(async_graphql::Error::new(
+                    "Archived users can't be given new cards",
+                ))
+            }
+            None => return Err(async_graphql::Error::new("User to claim for not found!")),
+        };
 
-        let mut attendance = Attendance {
-            id: -1,
-            user_uuid: uuid_parsed,
-            in_time: Utc::now(),
-            out_time: None,
+        let mut alt_id_fields: HashMap<String, String> = match &user.alt_id_fields {
+            Some(alt_id_fields) => serde_json::from_value(alt_id_fields.clone())?,
+            None => HashMap::new(),
         };
+        alt_id_fields.insert(
+            pending_identifier.alt_id_field.clone(),
+            pending_identifier.alt_id_value.clone(),
+        );
+        user.alt_id_fields = Some(serde_json::to_value(
+            normalize_alt_id_fields(pool, alt_id_fields, Some(user.uuid)).await?,
+        )?);
+
+        let mut tx = pool.begin().await?;
         let record = sqlx::query!(
-            "INSERT INTO attendance (user_uuid, in_time) VALUES ($1, $2) RETURNING id",
-            attendance.user_uuid,
-            attendance.in_time,
+            "UPDATE users SET alt_id_fields=$1 WHERE uuid=$2 RETURNING update_time, version",
+            user.alt_id_fields,
+            user.uuid
         )
-        .fetch_one(&**pool)
+        .fetch_one(&mut tx)
         .await?;
+        user.update_time = record.update_time;
+        user.version = record.version;
+        enroll_pending_identifier(&mut tx, &pending_identifier, user.uuid).await?;
+        tx.commit().await?;
 
-        attendance.id = record.id;
-
-        Ok(attendance)
+        Ok(user)
     }
 
-    // Only administrators
+    // Enrolls a brand new user with the pending card as their first alt ID
     #[graphql(guard(or(
-        CapabilityGuard(capability = "TokenCapability::Administrator"),
-        FirstRunGuard()
+        CapabilityGuard(capability = "TokenCapability::Collector"),
+        CapabilityGuard(capability = "TokenCapability::Viewer")
     )))]
-    async fn generate_token(
+    async fn create_user_from_pending_identifier(
+        &self,
+        ctx: &Context<'_>,
+        pending_identifier_id: i32,
+        full_name: String,
+        #[graphql(validator(Email))] email: String,
+        #[graphql(validator(PhoneNumber))] phone_number: Option<String>,
+    ) -> Result<User> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let pending_identifier =
+            fetch_unclaimed_pending_identifier(pool, pending_identifier_id).await?;
+
+        check_email_available(pool, &email, None).await?;
+        let phone_number = match phone_number {
+            Some(phone_number) => Some(normalize_phone_number(&phone_number)?),
+            None => None,
+        };
+
+        let mut alt_id_fields = HashMap::new();
+        alt_id_fields.insert(
+            pending_identifier.alt_id_field.clone(),
+            pending_identifier.alt_id_value.clone(),
+        );
+
+        let mut new_user = User {
+            full_name,
+            email,
+            phone_number,
+            uuid: Uuid::nil(),
+            create_time: Utc::now(),
+            update_time: None,
+            alt_id_fields: Some(serde_json::to_value(
+                normalize_alt_id_fields(pool, alt_id_fields, None).await?,
+            )?),
+            groups: Vec::new(),
+            archive_time: None,
+            archive_reason: None,
+            version: 0,
+        };
+
+        let mut tx = pool.begin().await?;
+        new_user.uuid = sqlx::query!(
+            "INSERT INTO users (full_name, email, phone_number, create_time, alt_id_fields) VALUES ($1, $2, $3, $4, $5) RETURNING uuid",
+            new_user.full_name, new_user.email, new_user.phone_number, new_user.create_time, new_user.alt_id_fields)
+            .fetch_one(&mut tx)
+            .await?
+            .uuid;
+        enroll_pending_identifier(&mut tx, &pending_identifier, new_user.uuid).await?;
+        tx.commit().await?;
+
+        Ok(new_user)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn create_alt_id_type(
         &self,
         ctx: &Context<'_>,
+        name: String,
         description: String,
-        capability: TokenCapability,
-        initial_valid_time: Option<DateTime<Utc>>,
-        expiration_time: DateTime<Utc>,
-    ) -> Result<String> {
-        // Generate a JWT
+        format: Option<AltIdFormat>,
+        pattern: Option<String>,
+        case_normalization: Option<AltIdCase>,
+        is_unique: Option<bool>,
+    ) -> Result<AltIdType> {
         let pool = ctx.data::<Arc<PgPool>>()?;
-        let mut token_struct = Token {
+
+        if let Some(pattern) = &pattern {
+            anchored_regex(pattern)?;
+        }
+
+        let alt_id_type = AltIdType {
+            name,
             description,
-            capability,
-            initial_valid_time,
-            expiration_time,
-            uuid: Uuid::nil(),
+            format: format.unwrap_or(AltIdFormat::Any),
+            pattern,
+            case_normalization: case_normalization.unwrap_or(AltIdCase::Preserve),
+            is_unique: is_unique.unwrap_or(false),
             create_time: Utc::now(),
         };
 
-        token_struct.uuid = sqlx::query!(
-            "INSERT INTO tokens (description, expiration_time, create_time, capability) VALUES ($1, $2, $3, $4) RETURNING uuid",
-            token_struct.description, token_struct.expiration_time, token_struct.create_time, token_struct.capability as TokenCapability
-        ).fetch_one(&**pool).await?.uuid;
+        sqlx::query!(
+            "INSERT INTO alt_id_types (name, description, format, pattern, case_normalization, is_unique, create_time) VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            alt_id_type.name,
+            alt_id_type.description,
+            alt_id_type.format as AltIdFormat,
+            alt_id_type.pattern,
+            alt_id_type.case_normalization as AltIdCase,
+            alt_id_type.is_unique,
+            alt_id_type.create_time
+        )
+        .execute(&**pool)
+        .await?;
 
-        let claims = JWTClaims {
-            uuid: token_struct.uuid.to_string(),
-            cap: token_struct.capability,
-            exp: token_struct.expiration_time.timestamp(),
-            nbf: token_struct.initial_valid_time.map(|item| item.timestamp()),
+        Ok(alt_id_type)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn update_alt_id_type(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        description: Option<String>,
+        format: Option<AltIdFormat>,
+        // Send null to remove the pattern
+        pattern: MaybeUndefined<String>,
+        case_normalization: Option<AltIdCase>,
+        is_unique: Option<bool>,
+    ) -> Result<AltIdType> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let mut tx = pool.begin().await?;
+
+        let mut alt_id_type = match sqlx::query_as!(
+            AltIdType,
+            r#"SELECT name, description, format as "format: AltIdFormat", pattern,
+            case_normalization as "case_normalization: AltIdCase", is_unique, create_time
+            FROM alt_id_types WHERE name=$1 FOR UPDATE"#,
+            name
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        {
+            Some(alt_id_type) => alt_id_type,
+            None => {
+                return Err(async_graphql::Error::new(format!(
+                    "'{}' is not a registered alt ID field type",
+                    name
+                )))
+            }
+        };
+        let old_case_normalization = alt_id_type.case_normalization;
+
+        if let Some(description) = description {
+            alt_id_type.description = description;
+        }
+        if let Some(format) = format {
+            alt_id_type.format = format;
+        }
+        match pattern {
+            MaybeUndefined::Value(pattern) => {
+                anchored_regex(&pattern)?;
+                alt_id_type.pattern = Some(pattern);
+            }
+            MaybeUndefined::Null => alt_id_type.pattern = None,
+            MaybeUndefined::Undefined => {}
+        }
+        if let Some(case_normalization) = case_normalization {
+            alt_id_type.case_normalization = case_normalization;
+        }
+        if let Some(is_unique) = is_unique {
+            alt_id_type.is_unique = is_unique;
+        }
+
+        // Stored values get the new case too, or cards that were enrolled before the change
+        // would stop matching what kiosks send
+        if alt_id_type.case_normalization != old_case_normalization {
+            let stored = sqlx::query!(
+                r#"SELECT uuid, alt_id_fields->>$1 as "value!" FROM users WHERE alt_id_fields ? $1 FOR UPDATE"#,
+                alt_id_type.name
+            )
+            .fetch_all(&mut tx)
+            .await?;
+            // Checked before anything is written, or the unique index would get there first
+            let mut values = HashSet::new();
+            let mut changed = Vec::new();
+            for user in stored {
+                let value = alt_id_type.normalize_case(&user.value);
+                if alt_id_type.is_unique && !values.insert(value.clone()) {
+                    return Err(async_graphql::Error::new(format!(
+                        "Changing the case would give more than one user the value {} for alt ID field '{}'",
+                        value, alt_id_type.name
+                    )));
+                }
+                if value != user.value {
+                    changed.push((user.uuid, value));
+                }
+            }
+            for (user_uuid, value) in changed {
+                sqlx::query!(
+                    "UPDATE users SET alt_id_fields = jsonb_set(alt_id_fields, ARRAY[$1], to_jsonb($2::text)) WHERE uuid=$3",
+                    alt_id_type.name,
+                    value,
+                    user_uuid
+                )
+                .execute(&mut tx)
+                .await?;
+            }
+        }
+
+        // Otherwise the unique index can't be made
+        if alt_id_type.is_unique {
+            if let Some(duplicate) = sqlx::query!(
+                r#"SELECT alt_id_fields->>$1 as "value!" FROM users WHERE alt_id_fields ? $1
+                GROUP BY 1 HAVING count(*) > 1 LIMIT 1"#,
+                alt_id_type.name
+            )
+            .fetch_optional(&mut tx)
+            .await?
+            {
+                return Err(async_graphql::Error::new(format!(
+                    "More than one user has the value {} for alt ID field '{}'",
+                    duplicate.value, alt_id_type.name
+                )));
+            }
+        }
+
+        sqlx::query!(
+            "UPDATE alt_id_types SET (description, format, pattern, case_normalization, is_unique) = ($1, $2, $3, $4, $5) WHERE name=$6",
+            alt_id_type.description,
+            alt_id_type.format as AltIdFormat,
+            alt_id_type.pattern,
+            alt_id_type.case_normalization as AltIdCase,
+            alt_id_type.is_unique,
+            alt_id_type.name
+        )
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(alt_id_type)
+    }
+
+    // Existing values stay on users, they just can't be written or looked up anymore
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn delete_alt_id_type(&self, ctx: &Context<'_>, name: String) -> Result<bool> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        Ok(sqlx::query!("DELETE FROM alt_id_types WHERE name=$1", name)
+            .execute(&**pool)
+            .await?
+            .rows_affected()
+            > 0)
+    }
+
+    // Moves everything from `remove` onto `keep`, then archives (or deletes) `remove`
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn merge_users(
+        &self,
+        ctx: &Context<'_>,
+        keep: String,
+        remove: String,
+        delete_duplicate: Option<bool>,
+    ) -> Result<MergeResult> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let keep = Uuid::parse_str(&keep)?;
+        let remove = Uuid::parse_str(&remove)?;
+        let delete_duplicate = delete_duplicate.unwrap_or(false);
+
+        if keep == remove {
+            return Err(async_graphql::Error::new(
+                "Can't merge a user into themselves",
+            ));
+        }
+
+        let mut tx = pool.begin().await?;
+
+        let mut user =
+            match sqlx::query_as!(User, "SELECT * FROM users WHERE uuid=$1 FOR UPDATE", keep)
+                .fetch_optional(&mut tx)
+                .await?
+            {
+                Some(user) => user,
+                None => return Err(async_graphql::Error::new("User to keep not found!")),
+            };
+        let duplicate =
+            match sqlx::query_as!(User, "SELECT * FROM users WHERE uuid=$1 FOR UPDATE", remove)
+                .fetch_optional(&mut tx)
+                .await?
+            {
+                Some(duplicate) => duplicate,
+                None => return Err(async_graphql::Error::new("User to remove not found!")),
+            };
+
+        let mut alt_id_fields: HashMap<String, String> = match &user.alt_id_fields {
+            Some(alt_id_fields) => serde_json::from_value(alt_id_fields.clone())?,
+            None => HashMap::new(),
+        };
+        let duplicate_alt_id_fields: HashMap<String, String> = match &duplicate.alt_id_fields {
+            Some(alt_id_fields) => serde_json::from_value(alt_id_fields.clone())?,
+            None => HashMap::new(),
+        };
+        let mut conflicts = Vec::new();
+        for (field, value) in duplicate_alt_id_fields {
+            match alt_id_fields.get(&field) {
+                Some(kept_value) if *kept_value != value => conflicts.push(AltIdConflict {
+                    field,
+                    kept_value: kept_value.clone(),
+                    discarded_value: value,
+                }),
+                Some(_) => {}
+                None => {
+                    alt_id_fields.insert(field, value);
+                }
+            }
+        }
+        conflicts.sort_by(|a, b| a.field.cmp(&b.field));
+
+        user.alt_id_fields = if alt_id_fields.is_empty() {
+            None
+        } else {
+            Some(serde_json::to_value(alt_id_fields)?)
         };
+        for group in duplicate.groups {
+            if !user.groups.contains(&group) {
+                user.groups.push(group);
+            }
+        }
+        if user.phone_number.is_none() {
+            user.phone_number = duplicate.phone_number;
+        }
+
+        // The duplicate gives up its alt IDs first, otherwise unique ones would clash
+        sqlx::query!("UPDATE users SET alt_id_fields=NULL WHERE uuid=$1", remove)
+            .execute(&mut tx)
+            .await?;
+        let record = sqlx::query!(
+            "UPDATE users SET (phone_number, alt_id_fields, groups) = ($1, $2, $3) WHERE uuid=$4 RETURNING update_time, version",
+            user.phone_number,
+            user.alt_id_fields,
+            &user.groups,
+            user.uuid
+        )
+        .fetch_one(&mut tx)
+        .await?;
+        user.update_time = record.update_time;
+        user.version = record.version;
+
+        let attendance_moved = sqlx::query!(
+            "UPDATE attendance SET user_uuid=$1 WHERE user_uuid=$2",
+            keep,
+            remove
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected() as i64;
+        sqlx::query!(
+            "UPDATE pending_identifiers SET claimed_user_uuid=$1 WHERE claimed_user_uuid=$2",
+            keep,
+            remove
+        )
+        .execute(&mut tx)
+        .await?;
+        sqlx::query!(
+            "UPDATE excuses SET user_uuid=$1 WHERE user_uuid=$2",
+            keep,
+            remove
+        )
+        .execute(&mut tx)
+        .await?;
+
+        if delete_duplicate {
+            sqlx::query!("DELETE FROM users WHERE uuid=$1", remove)
+                .execute(&mut tx)
+                .await?;
+        } else {
+            sqlx::query!(
+                "UPDATE users SET (archive_time, archive_reason) = ($1, $2) WHERE uuid=$3",
+                Utc::now(),
+                format!("Merged into {}", keep.to_hyphenated()),
+                remove
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+
+        record_audit(
+            &mut tx,
+            device_token_uuid(ctx)?,
+            "merge_users",
+            Some(keep),
+            serde_json::json!({
+                "removedUserUuid": remove.to_hyphenated().to_string(),
+                "duplicateDeleted": delete_duplicate,
+                "attendanceMoved": attendance_moved,
+                "conflictingFields": conflicts.iter().map(|conflict| &conflict.field).collect::<Vec<_>>(),
+            }),
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(MergeResult {
+            user,
+            attendance_moved,
+            conflicts,
+        })
+    }
+
+    // For deletion requests. Anonymizing keeps attendance counts for statistics, deleting
+    // removes everything.
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn erase_user(&self, ctx: &Context<'_>, uuid: String, mode: EraseMode) -> Result<bool> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let uuid = Uuid::parse_str(&uuid)?;
+
+        let mut tx = pool.begin().await?;
+
+        let user = match sqlx::query!(
+            "SELECT u.email, p.guardian_emails AS \"guardian_emails?\"
+            FROM users u LEFT JOIN notification_preferences p ON p.user_uuid = u.uuid
+            WHERE u.uuid=$1 FOR UPDATE OF u",
+            uuid
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        {
+            Some(user) => user,
+            None => return Err(async_graphql::Error::new("User to erase not found!")),
+        };
+
+        // Webhooks would only send everything we're about to remove to somebody else
+        sqlx::query!("SELECT set_config('attendance_rs.suppress_webhooks', 'on', true)")
+            .fetch_one(&mut tx)
+            .await?;
+
+        // Card numbers are personal data too
+        let pending_identifiers_removed = sqlx::query!(
+            "DELETE FROM pending_identifiers WHERE claimed_user_uuid=$1",
+            uuid
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+
+        // Login accounts, and the tokens they (or the user) were given, which are named after
+        // the account's e-mail
+        sqlx::query!(
+            "UPDATE tokens SET (description, revoke_time) = ('Erased user', COALESCE(revoke_time, now()))
+            WHERE user_uuid=$1 OR account_uuid IN (SELECT uuid FROM accounts WHERE user_uuid=$1)",
+            uuid
+        )
+        .execute(&mut tx)
+        .await?;
+        let account_emails: Vec<String> = sqlx::query!(
+            "DELETE FROM accounts WHERE user_uuid=$1 RETURNING lower(email) AS \"email!\"",
+            uuid
+        )
+        .fetch_all(&mut tx)
+        .await?
+        .into_iter()
+        .map(|account| account.email)
+        .collect();
+        let mut emails = account_emails;
+        emails.push(user.email.to_lowercase());
+        sqlx::query!("DELETE FROM login_failures WHERE email = ANY($1)", &emails)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query!(
+            "UPDATE audit_log SET details = details - 'email' WHERE subject_user_uuid=$1",
+            uuid
+        )
+        .execute(&mut tx)
+        .await?;
+
+        // E-mail about them or to them, and to their guardians. Rows from before notifications
+        // knew who they were about only have the address to go on.
+        let mut recipients: Vec<String> = user
+            .guardian_emails
+            .unwrap_or_default()
+            .iter()
+            .map(|email| email.to_lowercase())
+            .collect();
+        recipients.push(user.email.to_lowercase());
+        let notifications_removed = sqlx::query!(
+            "DELETE FROM notifications WHERE user_uuid=$1 OR (user_uuid IS NULL AND recipient = ANY($2))",
+            uuid,
+            &recipients
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+        sqlx::query!(
+            "DELETE FROM notification_opt_outs WHERE email=lower($1)",
+            user.email
+        )
+        .execute(&mut tx)
+        .await?;
+        sqlx::query!(
+            "DELETE FROM notification_preferences WHERE user_uuid=$1",
+            uuid
+        )
+        .execute(&mut tx)
+        .await?;
+        notifications::scrub_weekly_summaries(&mut tx, &user.email).await?;
+
+        let webhook_deliveries_removed = sqlx::query!(
+            "DELETE FROM webhook_deliveries
+            WHERE payload->'data'->'user'->>'uuid' = $1::text
+            OR payload->'data'->>'uuid' = $1::text
+            OR payload->'data'->'attendance'->>'user_uuid' = $1::text",
+            uuid.to_hyphenated().to_string()
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+
+        let details = match mode {
+            EraseMode::Delete => {
+                let attendance_removed =
+                    sqlx::query!("DELETE FROM attendance WHERE user_uuid=$1", uuid)
+                        .execute(&mut tx)
+                        .await?
+                        .rows_affected();
+                // Excuses, badges and roll call entries go with the user
+                sqlx::query!("DELETE FROM users WHERE uuid=$1", uuid)
+                    .execute(&mut tx)
+                    .await?;
+
+                serde_json::json!({
+                    "mode": "delete",
+                    "attendanceRemoved": attendance_removed,
+                    "pendingIdentifiersRemoved": pending_identifiers_removed,
+                    "notificationsRemoved": notifications_removed,
+                    "webhookDeliveriesRemoved": webhook_deliveries_removed,
+                })
+            }
+            EraseMode::Anonymize => {
+                // E-mail can't be null and has to stay unique, so it gets a placeholder
+                sqlx::query!(
+                    "UPDATE users SET (full_name, email, phone_number, alt_id_fields, archive_time, archive_reason) = ($1, $2, NULL, NULL, COALESCE(archive_time, $3), $4) WHERE uuid=$5",
+                    "Erased user",
+                    format!("erased-{}@invalid", uuid.to_hyphenated()),
+                    Utc::now(),
+                    "Personal data erased",
+                    uuid
+                )
+                .execute(&mut tx)
+                .await?;
+                // The dates and decisions still count towards attendance, the reasons don't
+                sqlx::query!(
+                    "UPDATE excuses SET (reason, review_note) = ('', NULL) WHERE user_uuid=$1",
+                    uuid
+                )
+                .execute(&mut tx)
+                .await?;
+
+                serde_json::json!({
+                    "mode": "anonymize",
+                    "pendingIdentifiersRemoved": pending_identifiers_removed,
+                    "notificationsRemoved": notifications_removed,
+                    "webhookDeliveriesRemoved": webhook_deliveries_removed,
+                })
+            }
+        };
+
+        record_audit(
+            &mut tx,
+            device_token_uuid(ctx)?,
+            "erase_user",
+            Some(uuid),
+            details,
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    // Deliveries are signed with the secret. See webhooks::sign for how receivers can check them.
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn create_webhook(
+        &self,
+        ctx: &Context<'_>,
+        url: String,
+        event_types: Vec<WebhookEventType>,
+        secret: String,
+        description: Option<String>,
+    ) -> Result<Webhook> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        check_webhook_url(&url)?;
+        check_webhook_secret(&secret)?;
+
+        Ok(sqlx::query_as!(
+            Webhook,
+            "INSERT INTO webhooks (url, description, event_types, secret) VALUES ($1, $2, $3, $4) RETURNING *",
+            url,
+            description.unwrap_or_default(),
+            &webhook_event_type_names(&event_types),
+            secret
+        )
+        .fetch_one(&**pool)
+        .await?)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn update_webhook(
+        &self,
+        ctx: &Context<'_>,
+        id: i32,
+        url: Option<String>,
+        event_types: Option<Vec<WebhookEventType>>,
+        secret: Option<String>,
+        description: Option<String>,
+        active: Option<bool>,
+    ) -> Result<Webhook> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        let mut webhook = match sqlx::query_as!(Webhook, "SELECT * FROM webhooks WHERE id=$1", id)
+            .fetch_optional(&**pool)
+            .await?
+        {
+            Some(webhook) => webhook,
+            None => return Err(async_graphql::Error::new("Webhook not found!")),
+        };
+
+        if let Some(url) = url {
+            check_webhook_url(&url)?;
+            webhook.url = url;
+        }
+        if let Some(event_types) = event_types {
+            webhook.event_types = webhook_event_type_names(&event_types);
+        }
+        if let Some(secret) = secret {
+            check_webhook_secret(&secret)?;
+            webhook.secret = secret;
+        }
+        if let Some(description) = description {
+            webhook.description = description;
+        }
+        if let Some(active) = active {
+            webhook.active = active;
+        }
+
+        sqlx::query!(
+            "UPDATE webhooks SET (url, event_types, secret, description, active) = ($1, $2, $3, $4, $5) WHERE id=$6",
+            webhook.url,
+            &webhook.event_types,
+            webhook.secret,
+            webhook.description,
+            webhook.active,
+            id
+        )
+        .execute(&**pool)
+        .await?;
+
+        Ok(webhook)
+    }
+
+    // Also throws away its delivery log
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn delete_webhook(&self, ctx: &Context<'_>, id: i32) -> Result<bool> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        Ok(sqlx::query!("DELETE FROM webhooks WHERE id=$1", id)
+            .execute(&**pool)
+            .await?
+            .rows_affected()
+            > 0)
+    }
+
+    // Queues a "ping" event for one webhook, to check the receiver is set up right
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn ping_webhook(&self, ctx: &Context<'_>, id: i32) -> Result<WebhookDelivery> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        match sqlx::query_as!(
+            WebhookDelivery,
+            r#"INSERT INTO webhook_deliveries (webhook_id, event_type, payload)
+            SELECT id, 'ping', jsonb_build_object('event', 'ping', 'time', now(), 'data', '{}'::jsonb)
+            FROM webhooks WHERE id=$1
+            RETURNING id, webhook_id, event_type, payload, status as "status: WebhookDeliveryStatus",
+            attempts, next_attempt_time, last_attempt_time, last_response_status, last_error,
+            delivered_time, create_time"#,
+            id
+        )
+        .fetch_optional(&**pool)
+        .await?
+        {
+            Some(delivery) => Ok(delivery),
+            None => Err(async_graphql::Error::new("Webhook not found!")),
+        }
+    }
+
+    // Sends a delivery again right away, with a fresh set of retries. Handy once a receiver
+    // that was down is fixed.
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn retry_webhook_delivery(&self, ctx: &Context<'_>, id: i64) -> Result<WebhookDelivery> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        match sqlx::query_as!(
+            WebhookDelivery,
+            r#"UPDATE webhook_deliveries SET (status, attempts, next_attempt_time) = ('pending', 0, now())
+            WHERE id=$1
+            RETURNING id, webhook_id, event_type, payload, status as "status: WebhookDeliveryStatus",
+            attempts, next_attempt_time, last_attempt_time, last_response_status, last_error,
+            delivered_time, create_time"#,
+            id
+        )
+        .fetch_optional(&**pool)
+        .await?
+        {
+            Some(delivery) => Ok(delivery),
+            None => Err(async_graphql::Error::new("Webhook delivery not found!")),
+        }
+    }
+
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn update_notification_template(
+        &self,
+        ctx: &Context<'_>,
+        kind: NotificationKind,
+        subject: String,
+        body: String,
+    ) -> Result<NotificationTemplate> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        notifications::check_template(kind, &subject).map_err(async_graphql::Error::new)?;
+        notifications::check_template(kind, &body).map_err(async_graphql::Error::new)?;
+
+        Ok(sqlx::query_as!(
+            NotificationTemplate,
+            r#"UPDATE notification_templates SET (subject, body, update_time) = ($1, $2, now()) WHERE kind=$3
+            RETURNING kind as "kind: NotificationKind", subject, body, update_time"#,
+            subject,
+            body,
+            kind as NotificationKind
+        )
+        .fetch_one(&**pool)
+        .await?)
+    }
+
+    // Anything left out stays how it was
+    #[allow(clippy::too_many_arguments)]
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn set_notification_preferences(
+        &self,
+        ctx: &Context<'_>,
+        user_uuid: String,
+        sign_out_notices: Option<bool>,
+        guardian_emails: Option<Vec<String>>,
+        weekly_summary: Option<bool>,
+        summary_groups: Option<Vec<String>>,
+    ) -> Result<NotificationPreferences> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let user_uuid = Uuid::parse_str(&user_uuid)?;
+        for email in guardian_emails.iter().flatten() {
+            if (Email {}).is_valid(&Value::String(email.clone())).is_err() {
+                return Err(async_graphql::Error::new(format!(
+                    "{} is not a valid e-mail address",
+                    email
+                )));
+            }
+        }
+
+        if sqlx::query!("SELECT uuid FROM users WHERE uuid=$1", user_uuid)
+            .fetch_optional(&**pool)
+            .await?
+            .is_none()
+        {
+            return Err(async_graphql::Error::new("User not found!"));
+        }
+
+        Ok(sqlx::query_as!(
+            NotificationPreferences,
+            "INSERT INTO notification_preferences (user_uuid, sign_out_notices, guardian_emails, weekly_summary, summary_groups)
+            VALUES ($1, coalesce($2, FALSE), coalesce($3::text[], '{}'), coalesce($4, FALSE), coalesce($5::text[], '{}'))
+            ON CONFLICT (user_uuid) DO UPDATE SET
+                sign_out_notices = coalesce($2, notification_preferences.sign_out_notices),
+                guardian_emails = coalesce($3, notification_preferences.guardian_emails),
+                weekly_summary = coalesce($4, notification_preferences.weekly_summary),
+                summary_groups = coalesce($5, notification_preferences.summary_groups)
+            RETURNING sign_out_notices, guardian_emails, weekly_summary, summary_groups",
+            user_uuid,
+            sign_out_notices,
+            guardian_emails.as_deref(),
+            weekly_summary,
+            summary_groups.as_deref()
+        )
+        .fetch_one(&**pool)
+        .await?)
+    }
+
+    // Same as following the unsubscribe link in an e-mail, or undoing that
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn set_email_opt_out(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(validator(Email))] email: String,
+        opted_out: bool,
+    ) -> Result<bool> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        if opted_out {
+            sqlx::query!(
+                "INSERT INTO notification_opt_outs (email) VALUES (lower($1)) ON CONFLICT DO NOTHING",
+                email
+            )
+            .execute(&**pool)
+            .await?;
+        } else {
+            sqlx::query!(
+                "DELETE FROM notification_opt_outs WHERE email = lower($1)",
+                email
+            )
+            .execute(&**pool)
+            .await?;
+        }
+        Ok(opted_out)
+    }
+
+    // For lost badges. The old badge stops working and /badges gives out the new one.
+    // Returns the new badge's serial number.
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn reissue_badge(&self, ctx: &Context<'_>, user_uuid: String) -> Result<i32> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let user_uuid = Uuid::parse_str(&user_uuid)?;
+
+        let mut tx = pool.begin().await?;
+        match sqlx::query!("SELECT archive_time FROM users WHERE uuid=$1", user_uuid)
+            .fetch_optional(&mut tx)
+            .await?
+        {
+            Some(user) if user.archive_time.is_none() => {}
+            Some(_) => {
+                return Err(async_graphql::Error::new(
+                    "Archived users can't be given new badges",
+                ))
+            }
+            None => return Err(async_graphql::Error::new("User not found!")),
+        }
+        let serial = sqlx::query!(
+            "INSERT INTO badges (user_uuid, serial) VALUES ($1, 1)
+            ON CONFLICT (user_uuid) DO UPDATE SET (serial, reissue_time) = (badges.serial + 1, now())
+            RETURNING serial",
+            user_uuid
+        )
+        .fetch_one(&mut tx)
+        .await?
+        .serial;
+
+        record_audit(
+            &mut tx,
+            device_token_uuid(ctx)?,
+            "reissue_badge",
+            Some(user_uuid),
+            serde_json::json!({ "serial": serial }),
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(serial)
+    }
+
+    // Sends the weekly summary for the last 7 days now, on top of the usual Sunday one.
+    // Returns how many e-mails were queued.
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn send_weekly_summary(
+        &self,
+        ctx: &Context<'_>,
+        threshold_hours: Option<f64>,
+    ) -> Result<i32> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let threshold_hours =
+            threshold_hours.unwrap_or(*WEEKLY_SUMMARY_THRESHOLD_HOURS.read().unwrap());
+
+        let mut conn = pool.acquire().await?;
+        Ok(
+            notifications::queue_weekly_summary(&mut conn, Utc::now(), threshold_hours).await?
+                as i32,
+        )
+    }
+
+    // Starts an evacuation roll call with everyone who is signed in right now (the same people
+    // presentNow returns), optionally only at one location
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn start_roll_call(
+        &self,
+        ctx: &Context<'_>,
+        location: Option<String>,
+    ) -> Result<RollCall> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let mut tx = pool.begin().await?;
+
+        let roll_call = sqlx::query_as!(
+            RollCall,
+            "INSERT INTO roll_calls (location, started_by_token_uuid, start_time) VALUES ($1, $2, $3) RETURNING *",
+            location,
+            device_token_uuid(ctx)?,
+            Utc::now()
+        )
+        .fetch_one(&mut tx)
+        .await?;
+
+        sqlx::query!(
+            "INSERT INTO roll_call_entries (roll_call_id, user_uuid, in_time, location)
+            SELECT $1, user_uuid, in_time, location FROM attendance
+            WHERE out_time IS NULL AND in_time > now() - make_interval(hours => $2)
+            AND ($3::text IS NULL OR location = $3)
+            ON CONFLICT DO NOTHING",
+            roll_call.id,
+            SIGN_OUT_WINDOW_HOURS,
+            roll_call.location
+        )
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(roll_call)
+    }
+
+    // Checks someone off at the assembly point. Pass accounted: false to undo a mistake.
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Viewer")))]
+    async fn mark_accounted_for(
+        &self,
+        ctx: &Context<'_>,
+        roll_call_id: i32,
+        user_uuid: String,
+        #[graphql(default = true)] accounted: bool,
+    ) -> Result<RollCallEntry> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        match sqlx::query!("SELECT end_time FROM roll_calls WHERE id=$1", roll_call_id)
+            .fetch_optional(&**pool)
+            .await?
+        {
+            Some(roll_call) if roll_call.end_time.is_some() => {
+                return Err(async_graphql::Error::new(
+                    "That roll call has already ended",
+                ))
+            }
+            Some(_) => {}
+            None => return Err(async_graphql::Error::new("Roll call not found!")),
+        }
+
+        let (accounted_time, accounted_by_token_uuid) = if accounted {
+            (Some(Utc::now()), device_token_uuid(ctx)?)
+        } else {
+            (None, None)
+        };
+
+        match sqlx::query_as!(
+            RollCallEntry,
+            "UPDATE roll_call_entries SET (accounted_time, accounted_by_token_uuid) = ($1, $2)
+            WHERE roll_call_id=$3 AND user_uuid=$4 RETURNING *",
+            accounted_time,
+            accounted_by_token_uuid,
+            roll_call_id,
+            Uuid::parse_str(&user_uuid)?
+        )
+        .fetch_optional(&**pool)
+        .await?
+        {
+            Some(entry) => Ok(entry),
+            None => Err(async_graphql::Error::new(
+                "That user wasn't signed in when the roll call started",
+            )),
+        }
+    }
+
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn end_roll_call(&self, ctx: &Context<'_>, id: i32) -> Result<RollCall> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        match sqlx::query_as!(
+            RollCall,
+            "UPDATE roll_calls SET end_time = COALESCE(end_time, $1) WHERE id=$2 RETURNING *",
+            Utc::now(),
+            id
+        )
+        .fetch_optional(&**pool)
+        .await?
+        {
+            Some(roll_call) => Ok(roll_call),
+            None => Err(async_graphql::Error::new("Roll call not found!")),
+        }
+    }
+
+    // The schedule is a cron expression in UTC, like "0 18 * * 0" for Sundays at 6 PM
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn update_job(
+        &self,
+        ctx: &Context<'_>,
+        kind: JobKind,
+        schedule: Option<String>,
+        enabled: Option<bool>,
+    ) -> Result<Job> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        if let Some(schedule) = &schedule {
+            let parsed = jobs::Schedule::parse(schedule).map_err(async_graphql::Error::new)?;
+            if parsed.next_after(Utc::now()).is_none() {
+                return Err(async_graphql::Error::new(format!(
+                    "{} never happens",
+                    schedule
+                )));
+            }
+        }
+
+        // Clearing next_run_time has the scheduler work it out again from the new schedule
+        Ok(sqlx::query_as!(
+            Job,
+            r#"UPDATE jobs SET (schedule, enabled, next_run_time) = (coalesce($1, schedule), coalesce($2, enabled), NULL)
+            WHERE kind=$3
+            RETURNING kind as "kind: JobKind", schedule, enabled, next_run_time"#,
+            schedule,
+            enabled,
+            kind as JobKind
+        )
+        .fetch_one(&**pool)
+        .await?)
+    }
+
+    // Runs a job now, whether or not it's enabled, and waits for it to finish. Its schedule
+    // carries on as usual.
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn run_job(&self, ctx: &Context<'_>, kind: JobKind) -> Result<JobRun> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        Ok(jobs::run_job(pool, kind, device_token_uuid(ctx)?).await?)
+    }
+
+    // Creates and updates events from an iCalendar (.ics) file, matched by UID. Events the file
+    // has problems with are skipped and listed in the report.
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn import_calendar(
+        &self,
+        ctx: &Context<'_>,
+        data: String,
+        #[graphql(default = true)] dry_run: bool,
+    ) -> Result<CalendarImportReport> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        calendar::import_calendar(pool, &data, dry_run, device_token_uuid(ctx)?).await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn create_event(
+        &self,
+        ctx: &Context<'_>,
+        summary: String,
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+        description: Option<String>,
+        location: Option<String>,
+        #[graphql(default = false)] all_day: bool,
+    ) -> Result<Event> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        check_event_times(start_time, end_time)?;
+
+        Ok(sqlx::query_as!(
+            Event,
+            "INSERT INTO events (uid, summary, description, location, start_time, end_time, all_day)
+            VALUES (gen_random_uuid()::text || '@attendance-rs', $1, $2, $3, $4, $5, $6) RETURNING *",
+            summary,
+            description.unwrap_or_default(),
+            location,
+            start_time,
+            end_time,
+            all_day
+        )
+        .fetch_one(&**pool)
+        .await?)
+    }
+
+    // Anything left out stays how it was. Cancelling is better than deleting for events people
+    // might have already seen, since calendar apps show them as cancelled.
+    #[allow(clippy::too_many_arguments)]
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn update_event(
+        &self,
+        ctx: &Context<'_>,
+        id: i32,
+        summary: Option<String>,
+        description: Option<String>,
+        location: Option<String>,
+        start_time: Option<DateTime<Utc>>,
+        end_time: Option<DateTime<Utc>>,
+        all_day: Option<bool>,
+        cancelled: Option<bool>,
+    ) -> Result<Event> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        let mut event = match sqlx::query_as!(Event, "SELECT * FROM events WHERE id=$1", id)
+            .fetch_optional(&**pool)
+            .await?
+        {
+            Some(event) => event,
+            None => return Err(async_graphql::Error::new("Event not found!")),
+        };
+
+        if let Some(summary) = summary {
+            event.summary = summary;
+        }
+        if let Some(description) = description {
+            event.description = description;
+        }
+        if location.is_some() {
+            event.location = location;
+        }
+        event.start_time = start_time.unwrap_or(event.start_time);
+        event.end_time = end_time.unwrap_or(event.end_time);
+        event.all_day = all_day.unwrap_or(event.all_day);
+        event.cancelled = cancelled.unwrap_or(event.cancelled);
+        check_event_times(event.start_time, event.end_time)?;
+
+        Ok(sqlx::query_as!(
+            Event,
+            "UPDATE events SET (summary, description, location, start_time, end_time, all_day, cancelled, sequence, update_time)
+            = ($1, $2, $3, $4, $5, $6, $7, sequence + 1, now()) WHERE id=$8 RETURNING *",
+            event.summary,
+            event.description,
+            event.location,
+            event.start_time,
+            event.end_time,
+            event.all_day,
+            event.cancelled,
+            id
+        )
+        .fetch_one(&**pool)
+        .await?)
+    }
+
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn delete_event(&self, ctx: &Context<'_>, id: i32) -> Result<bool> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        Ok(sqlx::query!("DELETE FROM events WHERE id=$1", id)
+            .execute(&**pool)
+            .await?
+            .rows_affected()
+            > 0)
+    }
+
+    // Makes a link to the calendar feed. Give each person or app their own, so one can be
+    // revoked without breaking everyone else's.
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn create_calendar_feed_key(
+        &self,
+        ctx: &Context<'_>,
+        description: String,
+    ) -> Result<CalendarFeedKey> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        Ok(sqlx::query_as!(
+            CalendarFeedKey,
+            "INSERT INTO calendar_feed_keys (description) VALUES ($1) RETURNING *",
+            description
+        )
+        .fetch_one(&**pool)
+        .await?)
+    }
+
+    // For the day a member can't make it. Mentors see it in `excuses` and approve it or not.
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Member")))]
+    async fn submit_excuse(
+        &self,
+        ctx: &Context<'_>,
+        date: NaiveDate,
+        reason: String,
+    ) -> Result<Excuse> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let user_uuid = member_user_uuid(ctx)?;
+        if reason.trim().is_empty() {
+            return Err(async_graphql::Error::new("Please give a reason"));
+        }
+
+        Ok(sqlx::query_as!(
+            Excuse,
+            r#"INSERT INTO excuses (user_uuid, date, reason) VALUES ($1, $2, $3)
+            RETURNING id, user_uuid, date, reason, status as "status: ExcuseStatus", create_time,
+            review_time, reviewed_by_token_uuid, review_note"#,
+            user_uuid,
+            date,
+            reason.trim()
+        )
+        .fetch_one(&**pool)
+        .await?)
+    }
+
+    // Takes back an excuse that hasn't been looked at yet
+    #[graphql(guard(and(
+        CapabilityGuard(capability = "TokenCapability::Member"),
+        ExcuseOwnerGuard(id = "@id")
+    )))]
+    async fn withdraw_excuse(&self, ctx: &Context<'_>, id: i32) -> Result<bool> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        if sqlx::query!("DELETE FROM excuses WHERE id=$1 AND status='pending'", id)
+            .execute(&**pool)
+            .await?
+            .rows_affected()
+            == 0
+        {
+            return Err(async_graphql::Error::new(
+                "This excuse has already been reviewed",
+            ));
+        }
+        Ok(true)
+    }
+
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn review_excuse(
+        &self,
+        ctx: &Context<'_>,
+        id: i32,
+        approved: bool,
+        note: Option<String>,
+    ) -> Result<Excuse> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let status = if approved {
+            ExcuseStatus::Approved
+        } else {
+            ExcuseStatus::Rejected
+        };
+
+        match sqlx::query_as!(
+            Excuse,
+            r#"UPDATE excuses SET (status, review_time, reviewed_by_token_uuid, review_note) = ($1, now(), $2, $3)
+            WHERE id=$4
+            RETURNING id, user_uuid, date, reason, status as "status: ExcuseStatus", create_time,
+            review_time, reviewed_by_token_uuid, review_note"#,
+            status as ExcuseStatus,
+            device_token_uuid(ctx)?,
+            note,
+            id
+        )
+        .fetch_optional(&**pool)
+        .await?
+        {
+            Some(excuse) => Ok(excuse),
+            None => Err(async_graphql::Error::new("Excuse not found!")),
+        }
+    }
+
+    // A new code for a screen at a location or event. Put its displayPath up on the screen.
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn create_checkin_code(
+        &self,
+        ctx: &Context<'_>,
+        description: String,
+        location: Option<String>,
+        event: Option<String>,
+        #[graphql(default = 30)] period_seconds: i32,
+        #[graphql(default = 6)] digits: i32,
+    ) -> Result<CheckinCode> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        if location.is_none() && event.is_none() {
+            return Err(async_graphql::Error::new(
+                "A check-in code needs a location or an event",
+            ));
+        }
+        if !(10..=600).contains(&period_seconds) {
+            return Err(async_graphql::Error::new(
+                "periodSeconds must be between 10 and 600",
+            ));
+        }
+        if !(4..=8).contains(&digits) {
+            return Err(async_graphql::Error::new("digits must be between 4 and 8"));
+        }
+
+        Ok(sqlx::query_as!(
+            CheckinCode,
+            "INSERT INTO checkin_codes (description, location, event, period_seconds, digits)
+            VALUES ($1, $2, $3, $4, $5) RETURNING *",
+            description,
+            location,
+            event,
+            period_seconds,
+            digits
+        )
+        .fetch_one(&**pool)
+        .await?)
+    }
+
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn delete_checkin_code(&self, ctx: &Context<'_>, id: i32) -> Result<bool> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        Ok(sqlx::query!("DELETE FROM checkin_codes WHERE id=$1", id)
+            .execute(&**pool)
+            .await?
+            .rows_affected()
+            > 0)
+    }
+
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn delete_calendar_feed_key(&self, ctx: &Context<'_>, key: String) -> Result<bool> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let key = Uuid::parse_str(&key)?;
+
+        Ok(
+            sqlx::query!("DELETE FROM calendar_feed_keys WHERE key=$1", key)
+                .execute(&**pool)
+                .await?
+                .rows_affected()
+                > 0,
+        )
+    }
+
+    // Only administrators
+    #[graphql(guard(or(
+        CapabilityGuard(capability = "TokenCapability::Administrator"),
+        FirstRunGuard()
+    )))]
+    async fn generate_token(
+        &self,
+        ctx: &Context<'_>,
+        description: String,
+        capability: TokenCapability,
+        initial_valid_time: Option<DateTime<Utc>>,
+        expiration_time: DateTime<Utc>,
+        // Which member a MEMBER token belongs to. Only for MEMBER tokens.
+        user_uuid: Option<String>,
+    ) -> Result<String> {
+        // Generate a JWT
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        let user_uuid = check_token_user(pool, capability, user_uuid).await?;
+
+        let mut token_struct = Token {
+            description,
+            capability,
+            initial_valid_time,
+            expiration_time,
+            uuid: Uuid::nil(),
+            create_time: Utc::now(),
+            user_uuid,
+            account_uuid: None,
+            refresh_token_hash: None,
+            revoke_time: None,
+        };
+
+        token_struct.uuid = sqlx::query!(
+            "INSERT INTO tokens (description, expiration_time, create_time, capability, user_uuid) VALUES ($1, $2, $3, $4, $5) RETURNING uuid",
+            token_struct.description, token_struct.expiration_time, token_struct.create_time, token_struct.capability as TokenCapability, token_struct.user_uuid
+        ).fetch_one(&**pool).await?.uuid;
+
+        let claims = JWTClaims {
+            uuid: token_struct.uuid.to_string(),
+            cap: token_struct.capability,
+            exp: token_struct.expiration_time.timestamp(),
+            nbf: token_struct.initial_valid_time.map(|item| item.timestamp()),
+            user: token_struct
+                .user_uuid
+                .map(|user_uuid| user_uuid.to_hyphenated().to_string()),
+        };
+
+        encode_token(&claims)
+    }
+
+    // Like generateToken, but instead of one JWT that works until expirationTime, it gives out
+    // a refresh token for the device to keep and an access token that only lasts a few minutes.
+    // The device trades the refresh token for new ones with refreshToken, and revokeToken cuts
+    // it off.
+    #[graphql(guard(or(
+        CapabilityGuard(capability = "TokenCapability::Administrator"),
+        FirstRunGuard()
+    )))]
+    async fn generate_refresh_token(
+        &self,
+        ctx: &Context<'_>,
+        description: String,
+        capability: TokenCapability,
+        initial_valid_time: Option<DateTime<Utc>>,
+        expiration_time: DateTime<Utc>,
+        // Which member a MEMBER token belongs to. Only for MEMBER tokens.
+        user_uuid: Option<String>,
+    ) -> Result<TokenPair> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let user_uuid = check_token_user(pool, capability, user_uuid).await?;
+        if expiration_time <= Utc::now() {
+            return Err(async_graphql::Error::new(
+                "expirationTime has to be in the future",
+            ));
+        }
+
+        let (refresh_token, refresh_token_hash) = accounts::new_refresh_token();
+        let token_uuid = sqlx::query!(
+            "INSERT INTO tokens (description, initial_valid_time, expiration_time, create_time, capability, user_uuid, refresh_token_hash)
+            VALUES ($1, $2, $3, now(), $4, $5, $6) RETURNING uuid",
+            description,
+            initial_valid_time,
+            expiration_time,
+            capability as TokenCapability,
+            user_uuid,
+            refresh_token_hash
+        )
+        .fetch_one(&**pool)
+        .await?
+        .uuid;
+
+        token_pair(
+            token_uuid,
+            capability,
+            user_uuid,
+            initial_valid_time,
+            refresh_token,
+            expiration_time,
+        )
+    }
+
+    // Stops a refresh token from getting any more access tokens. The ones it already got keep
+    // working for the few minutes they have left.
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn revoke_token(&self, ctx: &Context<'_>, uuid: String) -> Result<bool> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        let uuid = Uuid::parse_str(&uuid)?;
+        match sqlx::query!("SELECT refresh_token_hash FROM tokens WHERE uuid=$1", uuid)
+            .fetch_optional(&**pool)
+            .await?
+        {
+            Some(token) if token.refresh_token_hash.is_some() => {
+                sqlx::query!(
+                    "UPDATE tokens SET revoke_time = coalesce(revoke_time, now()) WHERE uuid=$1",
+                    uuid
+                )
+                .execute(&**pool)
+                .await?;
+                Ok(true)
+            }
+            // Nothing checks the database for these, so marking them revoked would only be a lie
+            Some(_) => Err(async_graphql::Error::new(
+                "Only refresh tokens can be revoked. Other tokens work until they expire.",
+            )),
+            None => Err(async_graphql::Error::new("Token not found!")),
+        }
+    }
+
+    // People with an account log in here instead of being given a token. Send the access token
+    // in the Token header like any other, and use refreshToken to get a new one every few minutes.
+    async fn login(&self, ctx: &Context<'_>, email: String, password: String) -> Result<TokenPair> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        accounts::check_login_attempts(pool, &email).await?;
+
+        let account = sqlx::query_as!(
+            Account,
+            r#"SELECT uuid, email, full_name, capability as "capability: TokenCapability", user_uuid,
+            password_hash, disabled, create_time, last_login_time FROM accounts WHERE email = lower($1)"#,
+            email.trim()
+        )
+        .fetch_optional(&**pool)
+        .await?;
+
+        let password_hash = account
+            .as_ref()
+            .and_then(|account| account.password_hash.clone());
+        let password_matches = accounts::verify_password(password, password_hash).await;
+        accounts::record_login_attempt(pool, &email, password_matches).await?;
+        let account = match account {
+            Some(account) if password_matches => account,
+            // Same answer for both, so nobody can find out who has an account
+            _ => return Err(async_graphql::Error::new("Wrong e-mail or password")),
+        };
+        if account.disabled {
+            return Err(async_graphql::Error::new("This account is disabled"));
+        }
+
+        let (refresh_token, refresh_token_hash) = accounts::new_refresh_token();
+        let mut tx = pool.begin().await?;
+        let token = sqlx::query!(
+            "INSERT INTO tokens (description, expiration_time, create_time, capability, user_uuid, account_uuid, refresh_token_hash)
+            VALUES ($1, now() + make_interval(days => $2), now(), $3, $4, $5, $6) RETURNING uuid, expiration_time",
+            format!("Login by {}", account.email),
+            accounts::REFRESH_TOKEN_DAYS as i32,
+            account.capability as TokenCapability,
+            account.user_uuid,
+            account.uuid,
+            refresh_token_hash
+        )
+        .fetch_one(&mut tx)
+        .await?;
+        sqlx::query!(
+            "UPDATE accounts SET last_login_time = now() WHERE uuid=$1",
+            account.uuid
+        )
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+
+        token_pair(
+            token.uuid,
+            account.capability,
+            account.user_uuid,
+            None,
+            refresh_token,
+            token.expiration_time,
+        )
+    }
+
+    // Trades a refresh token for a new access token, and a new refresh token to use next time.
+    // Each refresh token only works once.
+    async fn refresh_token(&self, ctx: &Context<'_>, refresh_token: String) -> Result<TokenPair> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let (new_refresh_token, new_refresh_token_hash) = accounts::new_refresh_token();
+
+        // Logins last as long as they're used, but device tokens expire when they were set to.
+        // Disabling an account revokes its tokens, but check anyway.
+        match sqlx::query!(
+            r#"UPDATE tokens SET (refresh_token_hash, expiration_time) = ($1, CASE WHEN account_uuid IS NULL
+                THEN expiration_time ELSE now() + make_interval(days => $2) END)
+            WHERE refresh_token_hash = $3 AND revoke_time IS NULL AND expiration_time > now()
+            AND (initial_valid_time IS NULL OR initial_valid_time <= now())
+            AND NOT EXISTS (SELECT 1 FROM accounts WHERE uuid = tokens.account_uuid AND disabled)
+            RETURNING uuid, capability as "capability: TokenCapability", user_uuid, expiration_time"#,
+            new_refresh_token_hash,
+            accounts::REFRESH_TOKEN_DAYS as i32,
+            accounts::hash_refresh_token(&refresh_token)
+        )
+        .fetch_optional(&**pool)
+        .await?
+        {
+            Some(token) => token_pair(
+                token.uuid,
+                token.capability,
+                token.user_uuid,
+                None,
+                new_refresh_token,
+                token.expiration_time,
+            ),
+            None => Err(async_graphql::Error::new(
+                "This refresh token isn't valid. It may have expired or been revoked.",
+            )),
+        }
+    }
+
+    // Revokes the login a refresh token is for. Access tokens already handed out for it keep
+    // working until they expire, which doesn't take long.
+    async fn logout(&self, ctx: &Context<'_>, refresh_token: String) -> Result<bool> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        Ok(sqlx::query!(
+            "UPDATE tokens SET revoke_time = now() WHERE refresh_token_hash = $1 AND revoke_time IS NULL",
+            accounts::hash_refresh_token(&refresh_token)
+        )
+        .execute(&**pool)
+        .await?
+        .rows_affected()
+            > 0)
+    }
+
+    // Changes the password of the account that's logged in. Its other logins are logged out.
+    #[graphql(guard(or(
+        CapabilityGuard(capability = "TokenCapability::Viewer"),
+        or(
+            CapabilityGuard(capability = "TokenCapability::Collector"),
+            CapabilityGuard(capability = "TokenCapability::Member")
+        )
+    )))]
+    async fn change_password(
+        &self,
+        ctx: &Context<'_>,
+        current_password: String,
+        new_password: String,
+    ) -> Result<bool> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let token_uuid = device_token_uuid(ctx)?;
+
+        let account = match sqlx::query!(
+            "SELECT accounts.uuid, email, password_hash FROM accounts
+            JOIN tokens ON tokens.account_uuid = accounts.uuid WHERE tokens.uuid = $1",
+            token_uuid
+        )
+        .fetch_optional(&**pool)
+        .await?
+        {
+            Some(account) => account,
+            None => {
+                return Err(async_graphql::Error::new(
+                    "Only logins from an account have a password",
+                ))
+            }
+        };
+        // A stolen access token shouldn't be a way around the login limit
+        accounts::check_login_attempts(pool, &account.email).await?;
+        let password_matches =
+            accounts::verify_password(current_password, account.password_hash.clone()).await;
+        accounts::record_login_attempt(pool, &account.email, password_matches).await?;
+        if !password_matches {
+            return Err(async_graphql::Error::new("Wrong password"));
+        }
+        let password_hash = accounts::hash_password(new_password).await?;
+
+        let mut tx = pool.begin().await?;
+        sqlx::query!(
+            "UPDATE accounts SET password_hash = $1 WHERE uuid=$2",
+            password_hash,
+            account.uuid
+        )
+        .execute(&mut tx)
+        .await?;
+        sqlx::query!(
+            "UPDATE tokens SET revoke_time = now() WHERE account_uuid = $1 AND uuid <> $2 AND revoke_time IS NULL",
+            account.uuid,
+            token_uuid
+        )
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    // An account for someone to log in with. Without a password, they can't log in until
+    // setAccountPassword gives them one.
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn create_account(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(validator(Email))] email: String,
+        full_name: String,
+        capability: TokenCapability,
+        // Only for MEMBER accounts, which are for one member
+        user_uuid: Option<String>,
+        password: Option<String>,
+    ) -> Result<Account> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        let user_uuid = match (capability, user_uuid) {
+            (TokenCapability::Member, Some(user_uuid)) => Some(Uuid::parse_str(&user_uuid)?),
+            (TokenCapability::Member, None) => {
+                return Err(async_graphql::Error::new(
+                    "Member accounts need the userUuid of the member they're for",
+                ))
+            }
+            (_, Some(_)) => {
+                return Err(async_graphql::Error::new(
+                    "Only member accounts belong to a user",
+                ))
+            }
+            (_, None) => None,
+        };
+        let password_hash = match password {
+            Some(password) => Some(accounts::hash_password(password).await?),
+            None => None,
+        };
+        if sqlx::query!("SELECT uuid FROM accounts WHERE email = lower($1)", email)
+            .fetch_optional(&**pool)
+            .await?
+            .is_some()
+        {
+            return Err(async_graphql::Error::new(format!(
+                "There is already an account for {}",
+                email
+            )));
+        }
+
+        let mut tx = pool.begin().await?;
+        let account = sqlx::query_as!(
+            Account,
+            r#"INSERT INTO accounts (email, full_name, capability, user_uuid, password_hash)
+            VALUES (lower($1), $2, $3, $4, $5)
+            RETURNING uuid, email, full_name, capability as "capability: TokenCapability", user_uuid,
+            password_hash, disabled, create_time, last_login_time"#,
+            email,
+            full_name,
+            capability as TokenCapability,
+            user_uuid,
+            password_hash
+        )
+        .fetch_one(&mut tx)
+        .await?;
+        record_audit(
+            &mut tx,
+            device_token_uuid(ctx)?,
+            "create_account",
+            account.user_uuid,
+            serde_json::json!({
+                "accountUuid": account.uuid.to_hyphenated().to_string(),
+                "email": account.email,
+                "capability": account.capability,
+            }),
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(account)
+    }
+
+    // Disabling an account logs it out everywhere. Member accounts stay member accounts (make a
+    // new one instead), but any other account can be moved between capabilities.
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn update_account(
+        &self,
+        ctx: &Context<'_>,
+        uuid: String,
+        full_name: Option<String>,
+        capability: Option<TokenCapability>,
+        disabled: Option<bool>,
+    ) -> Result<Account> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let mut account = fetch_account(pool, &uuid).await?;
+
+        if let Some(capability) = capability {
+            if (capability == TokenCapability::Member)
+                != (account.capability == TokenCapability::Member)
+            {
+                return Err(async_graphql::Error::new(
+                    "Accounts can't be changed to or from MEMBER. Make a new account instead.",
+                ));
+            }
+            account.capability = capability;
+        }
+        account.full_name = full_name.unwrap_or(account.full_name);
+        account.disabled = disabled.unwrap_or(account.disabled);
+
+        let mut tx = pool.begin().await?;
+        sqlx::query!(
+            "UPDATE accounts SET (full_name, capability, disabled) = ($1, $2, $3) WHERE uuid=$4",
+            account.full_name,
+            account.capability as TokenCapability,
+            account.disabled,
+            account.uuid
+        )
+        .execute(&mut tx)
+        .await?;
+        // Logins carry the capability they were made with, so those have to go too
+        if account.disabled || capability.is_some() {
+            sqlx::query!(
+                "UPDATE tokens SET revoke_time = now() WHERE account_uuid = $1 AND revoke_time IS NULL",
+                account.uuid
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+        record_audit(
+            &mut tx,
+            device_token_uuid(ctx)?,
+            "update_account",
+            account.user_uuid,
+            serde_json::json!({
+                "accountUuid": account.uuid.to_hyphenated().to_string(),
+                "capability": account.capability,
+                "disabled": account.disabled,
+            }),
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(account)
+    }
+
+    // For new accounts and forgotten passwords. Logs the account out everywhere.
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Administrator")))]
+    async fn set_account_password(
+        &self,
+        ctx: &Context<'_>,
+        uuid: String,
+        password: String,
+    ) -> Result<bool> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let account = fetch_account(pool, &uuid).await?;
+        let password_hash = accounts::hash_password(password).await?;
+
+        let mut tx = pool.begin().await?;
+        sqlx::query!(
+            "UPDATE accounts SET password_hash = $1 WHERE uuid=$2",
+            password_hash,
+            account.uuid
+        )
+        .execute(&mut tx)
+        .await?;
+        sqlx::query!(
+            "UPDATE tokens SET revoke_time = now() WHERE account_uuid = $1 AND revoke_time IS NULL",
+            account.uuid
+        )
+        .execute(&mut tx)
+        .await?;
+        record_audit(
+            &mut tx,
+            device_token_uuid(ctx)?,
+            "set_account_password",
+            account.user_uuid,
+            serde_json::json!({ "accountUuid": account.uuid.to_hyphenated().to_string() }),
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(true)
+    }
+}
+
+#[Subscription]
+impl Subscription {
+    // Sign-ins and sign-outs as they happen, for live displays. Leave out location or group
+    // to hear about everything.
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Viewer")))]
+    async fn attendance_events(
+        &self,
+        location: Option<String>,
+        group: Option<String>,
+    ) -> impl Stream<Item = AttendanceEvent> {
+        crate::events::subscribe_to_attendance().filter(move |event| {
+            let matches = location
+                .as_ref()
+                .is_none_or(|location| event.attendance.location.as_ref() == Some(location))
+                && group
+                    .as_ref()
+                    .is_none_or(|group| event.groups.contains(group));
+            futures::future::ready(matches)
+        })
+    }
+
+    // Entries of a roll call as they get checked off, so everyone at the assembly point sees
+    // the same list
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Viewer")))]
+    async fn roll_call_updates(&self, roll_call_id: i32) -> impl Stream<Item = RollCallEntry> {
+        crate::events::subscribe_to_roll_calls()
+            .filter(move |entry| futures::future::ready(entry.roll_call_id == roll_call_id))
+    }
+}
+
+// What update_user returns when the user changed since the caller last looked
+fn version_conflict(current_version: i32) -> async_graphql::Error {
+    async_graphql::Error::new("This user was changed by someone else. Reload it and try again.")
+        .extend_with(|_, e| {
+            e.set("code", "CONFLICT");
+            e.set("currentVersion", current_version);
+        })
+}
+
+// E-mails are unique (ignoring case) among active users.
+// `user_uuid` is the user being edited, so their own e-mail doesn't count.
+async fn check_email_available(pool: &PgPool, email: &str, user_uuid: Option<Uuid>) -> Result<()> {
+    if sqlx::query!(
+        "SELECT uuid FROM users WHERE lower(email)=lower($1) AND archive_time IS NULL AND uuid IS DISTINCT FROM $2",
+        email,
+        user_uuid
+    )
+    .fetch_optional(pool)
+    .await?
+    .is_some()
+    {
+        return Err(async_graphql::Error::new(format!(
+            "Another user already has the e-mail {}",
+            email
+        )));
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn fetch_alt_id_type(pool: &PgPool, name: &str) -> Result<AltIdType> {
+    match sqlx::query_as!(
+        AltIdType,
+        r#"SELECT name, description, format as "format: AltIdFormat", pattern,
+        case_normalization as "case_normalization: AltIdCase", is_unique, create_time
+        FROM alt_id_types WHERE name=$1"#,
+        name
+    )
+    .fetch_optional(pool)
+    .await?
+    {
+        Some(alt_id_type) => Ok(alt_id_type),
+        None => Err(async_graphql::Error::new(format!(
+            "'{}' is not a registered alt ID field type",
+            name
+        ))),
+    }
+}
+
+// Checks every field against the alt ID type registry and returns the normalized values.
+// `user_uuid` is the user being edited, so their own values don't count as duplicates. This
+// is only for a nicer error, the unique indexes on users are what actually stop duplicates.
+async fn normalize_alt_id_fields(
+    pool: &PgPool,
+    alt_id_fields: HashMap<String, String>,
+    user_uuid: Option<Uuid>,
+) -> Result<HashMap<String, String>> {
+    let mut normalized_fields = HashMap::new();
+
+    for (field, value) in alt_id_fields {
+        let alt_id_type = fetch_alt_id_type(pool, &field).await?;
+        let value = alt_id_type.normalize(&value)?;
+
+        if alt_id_type.is_unique
+            && sqlx::query!(
+                "SELECT uuid FROM users WHERE alt_id_fields->>$1 = $2 AND uuid IS DISTINCT FROM $3",
+                field,
+                value,
+                user_uuid
+            )
+            .fetch_optional(pool)
+            .await?
+            .is_some()
+        {
+            return Err(async_graphql::Error::new(format!(
+                "Another user already has the value {} for alt ID field '{}'",
+                value, field
+            )));
+        }
+
+        normalized_fields.insert(field, value);
+    }
+
+    Ok(normalized_fields)
+}
+
+// For user input that goes into a LIKE pattern
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+fn check_event_times(start_time: DateTime<Utc>, end_time: DateTime<Utc>) -> Result<()> {
+    if end_time < start_time {
+        return Err(async_graphql::Error::new(
+            "Events can't end before they start",
+        ));
+    }
+    Ok(())
+}
+
+fn check_webhook_url(url: &str) -> Result<()> {
+    match url.parse::<::http::Uri>() {
+        Ok(uri)
+            if (uri.scheme_str() == Some("https") || uri.scheme_str() == Some("http"))
+                && uri.host().is_some() =>
+        {
+            Ok(())
+        }
+        _ => Err(async_graphql::Error::new(format!(
+            "{} is not an http:// or https:// URL",
+            url
+        ))),
+    }
+}
+
+// Short secrets are easy to guess, which would let anyone sign fake deliveries
+fn check_webhook_secret(secret: &str) -> Result<()> {
+    if secret.len() < 16 {
+        return Err(async_graphql::Error::new(
+            "Webhook secrets need to be at least 16 characters long",
+        ));
+    }
+    Ok(())
+}
+
+fn webhook_event_type_names(event_types: &[WebhookEventType]) -> Vec<String> {
+    let mut names: Vec<String> = event_types
+        .iter()
+        .map(|event_type| event_type.name().to_string())
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+// The token that made this request, which is how we know which kiosk a scan came from
+fn device_token_uuid(ctx: &Context<'_>) -> Result<Option<Uuid>> {
+    match ctx.data_opt::<JWTClaims>() {
+        Some(claims) => Ok(Some(Uuid::parse_str(&claims.uuid)?)),
+        None => Ok(None),
+    }
+}
+
+fn encode_token(claims: &JWTClaims) -> Result<String> {
+    let private_key_read = PRIVATE_KEY.read().unwrap();
+    let private_key_as_bytes = private_key_read.as_ref();
+    match jsonwebtoken::encode(
+        &Header::new(Algorithm::ES256),
+        claims,
+        &EncodingKey::from_ec_pem(private_key_as_bytes).expect("Expected a valid private key"),
+    ) {
+        Ok(key) => Ok(key),
+        Err(error) => Err(async_graphql::Error::new(format!("{}", error))),
+    }
+}
+
+// A short-lived access token, handed out with its refresh token. It never outlasts the refresh
+// token.
+fn token_pair(
+    token_uuid: Uuid,
+    capability: TokenCapability,
+    user_uuid: Option<Uuid>,
+    initial_valid_time: Option<DateTime<Utc>>,
+    refresh_token: String,
+    refresh_token_expiration_time: DateTime<Utc>,
+) -> Result<TokenPair> {
+    let access_token_expiration_time = refresh_token_expiration_time
+        .min(Utc::now() + Duration::minutes(accounts::ACCESS_TOKEN_MINUTES));
+    let access_token = encode_token(&JWTClaims {
+        uuid: token_uuid.to_string(),
+        cap: capability,
+        exp: access_token_expiration_time.timestamp(),
+        nbf: initial_valid_time.map(|time| time.timestamp()),
+        user: user_uuid.map(|user_uuid| user_uuid.to_hyphenated().to_string()),
+    })?;
+
+    Ok(TokenPair {
+        access_token,
+        access_token_expiration_time,
+        refresh_token,
+        refresh_token_expiration_time,
+    })
+}
+
+// Member tokens need the user they belong to, and no other kind of token has one
+async fn check_token_user(
+    pool: &PgPool,
+    capability: TokenCapability,
+    user_uuid: Option<String>,
+) -> Result<Option<Uuid>> {
+    Ok(match (capability, user_uuid) {
+        (TokenCapability::Member, Some(user_uuid)) => {
+            let user_uuid = Uuid::parse_str(&user_uuid)?;
+            match sqlx::query!("SELECT archive_time FROM users WHERE uuid=$1", user_uuid)
+                .fetch_optional(pool)
+                .await?
+            {
+                Some(user) if user.archive_time.is_none() => Some(user_uuid),
+                Some(_) => {
+                    return Err(async_graphql::Error::new(
+                        "Archived users can't be given tokens",
+                    ))
+                }
+                None => return Err(async_graphql::Error::new("User not found!")),
+            }
+        }
+        (TokenCapability::Member, None) => {
+            return Err(async_graphql::Error::new(
+                "Member tokens need the userUuid of the member they belong to",
+            ))
+        }
+        (_, Some(_)) => {
+            return Err(async_graphql::Error::new(
+                "Only member tokens belong to a user",
+            ))
+        }
+        (_, None) => None,
+    })
+}
+
+async fn fetch_account(pool: &PgPool, uuid: &str) -> Result<Account> {
+    match sqlx::query_as!(
+        Account,
+        r#"SELECT uuid, email, full_name, capability as "capability: TokenCapability", user_uuid,
+        password_hash, disabled, create_time, last_login_time FROM accounts WHERE uuid=$1"#,
+        Uuid::parse_str(uuid)?
+    )
+    .fetch_optional(pool)
+    .await?
+    {
+        Some(account) => Ok(account),
+        None => Err(async_graphql::Error::new("Account not found!")),
+    }
+}
+
+// The user a Member token belongs to. Other tokens (even administrators') don't have one.
+fn member_user_uuid(ctx: &Context<'_>) -> Result<Uuid> {
+    match ctx
+        .data_opt::<JWTClaims>()
+        .and_then(|claims| claims.user.as_ref())
+    {
+        Some(user) => Ok(Uuid::parse_str(user)?),
+        None => Err(async_graphql::Error::new(
+            "This token doesn't belong to a member",
+        )),
+    }
+}
+
+pub(crate) async fn record_audit(
+    conn: &mut PgConnection,
+    actor_token_uuid: Option<Uuid>,
+    action: &str,
+    subject_user_uuid: Option<Uuid>,
+    details: serde_json::Value,
+) -> Result<()> {
+    sqlx::query!(
+        "INSERT INTO audit_log (action, actor_token_uuid, subject_user_uuid, details, create_time) VALUES ($1, $2, $3, $4, $5)",
+        action,
+        actor_token_uuid,
+        subject_user_uuid,
+        details,
+        Utc::now()
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+// Signs the user in at `time`, or signs them out if they have a recent open entry.
+// Sign-outs keep the location and event they signed in with.
+async fn record_attendance(
+    conn: &mut PgConnection,
+    user_uuid: Uuid,
+    time: DateTime<Utc>,
+    location: Option<String>,
+    event: Option<String>,
+    device_token_uuid: Option<Uuid>,
+) -> Result<Attendance> {
+    // Check if the user has an entry without an out time
+
+    if let Some(mut attendance) = sqlx::query_as!(
+        Attendance,
+        "SELECT * FROM attendance WHERE user_uuid=$1 ORDER BY in_time DESC LIMIT 1",
+        user_uuid
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    {
+        if attendance.out_time.is_none() {
+            // We only let people sign out less than four hours after sign in (otherwise we sign in instead of sign out)
+            // TODO make this amount configurable
+            if time - attendance.in_time < Duration::hours(SIGN_OUT_WINDOW_HOURS.into()) {
+                // Run an update query, as the user checked in, but not out
+
+                attendance.out_time = sqlx::query!(
+                    "UPDATE attendance SET out_time=$1 WHERE id=$2 RETURNING out_time",
+                    time,
+                    attendance.id
+                )
+                .fetch_one(&mut *conn)
+                .await?
+                .out_time;
+
+                return Ok(attendance);
+            }
+        }
+    }
+
+    let mut attendance = Attendance {
+        id: -1,
+        user_uuid,
+        in_time: time,
+        out_time: None,
+        location,
+        event,
+        device_token_uuid,
+        auto_closed: false,
+        backfilled: false,
+    };
+    let record = sqlx::query!(
+        "INSERT INTO attendance (user_uuid, in_time, location, event, device_token_uuid) VALUES ($1, $2, $3, $4, $5) RETURNING id",
+        attendance.user_uuid,
+        attendance.in_time,
+        attendance.location,
+        attendance.event,
+        attendance.device_token_uuid
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    attendance.id = record.id;
+
+    Ok(attendance)
+}
+
+async fn fetch_unclaimed_pending_identifier(pool: &PgPool, id: i32) -> Result<PendingIdentifier> {
+    match sqlx::query_as!(
+        PendingIdentifier,
+        "SELECT * FROM pending_identifiers WHERE id=$1",
+        id
+    )
+    .fetch_optional(pool)
+    .await?
+    {
+        Some(pending_identifier) if pending_identifier.claimed_user_uuid.is_none() => {
+            Ok(pending_identifier)
+        }
+        Some(_) => Err(async_graphql::Error::new(
+            "This pending identifier was already claimed",
+        )),
+        None => Err(async_graphql::Error::new("Pending identifier not found!")),
+    }
+}
+
+// Marks every unclaimed scan of the card as claimed and back-fills them as attendance
+async fn enroll_pending_identifier(
+    conn: &mut PgConnection,
+    pending_identifier: &PendingIdentifier,
+    user_uuid: Uuid,
+) -> Result<()> {
+    let mut scans = sqlx::query!(
+        "UPDATE pending_identifiers SET (claimed_user_uuid, claim_time) = ($1, $2) WHERE alt_id_field=$3 AND alt_id_value=$4 AND claimed_user_uuid IS NULL RETURNING scan_time, location, event, device_token_uuid",
+        user_uuid,
+        Utc::now(),
+        pending_identifier.alt_id_field,
+        pending_identifier.alt_id_value
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    // Oldest first, so each scan pairs up with the one before it
+    scans.sort_by_key(|scan| scan.scan_time);
+    for scan in scans {
+        backfill_attendance(
+            &mut *conn,
+            user_uuid,
+            scan.scan_time,
+            scan.location,
+            scan.event,
+            scan.device_token_uuid,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+// Like record_attendance, but for a scan from the past. Only sessions that started before the
+// scan count, so an old scan never signs out a newer session.
+async fn backfill_attendance(
+    conn: &mut PgConnection,
+    user_uuid: Uuid,
+    time: DateTime<Utc>,
+    location: Option<String>,
+    event: Option<String>,
+    device_token_uuid: Option<Uuid>,
+) -> Result<()> {
+    if let Some(attendance) = sqlx::query!(
+        "SELECT id, in_time, out_time FROM attendance WHERE user_uuid=$1 AND in_time <= $2 ORDER BY in_time DESC LIMIT 1",
+        user_uuid,
+        time
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    {
+        match attendance.out_time {
+            None if time - attendance.in_time < Duration::hours(SIGN_OUT_WINDOW_HOURS.into()) => {
+                sqlx::query!(
+                    "UPDATE attendance SET (out_time, backfilled) = ($1, TRUE) WHERE id=$2",
+                    time,
+                    attendance.id
+                )
+                .execute(&mut *conn)
+                .await?;
+                return Ok(());
+            }
+            // They were already signed in some other way when they scanned
+            Some(out_time) if out_time >= time => return Ok(()),
+            _ => {}
+        }
+    }
+
+    sqlx::query!(
+        "INSERT INTO attendance (user_uuid, in_time, location, event, device_token_uuid, backfilled) VALUES ($1, $2, $3, $4, $5, TRUE)",
+        user_uuid,
+        time,
+        location,
+        event,
+        device_token_uuid
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sets up a user with a bit of everything, erases them, and looks through every table for
+    // anything that's left. Needs DATABASE_URL pointing at a migrated database, so run it with
+    // `cargo test -- --ignored`.
+    #[actix_rt::test]
+    #[ignore]
+    async fn erase_user_leaves_no_personal_data() {
+        let pool = PgPool::connect(&dotenv::var("DATABASE_URL").unwrap())
+            .await
+            .unwrap();
+        let schema = Schema::build(Query, Mutation, Subscription)
+            .data(Arc::new(pool.clone()))
+            .finish();
+
+        for mode in &["ANONYMIZE", "DELETE"] {
+            let marker: String = sqlx::query_scalar("SELECT md5(random()::text)")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+            let full_name = format!("Erase Me {}", marker);
+            let email = format!("{}@example.com", marker);
+            let guardian_email = format!("guardian-{}@example.com", marker);
+            let account_email = format!("login-{}@example.com", marker);
+            let reason = format!("Dentist appointment {}", marker);
+            let card = format!("card-{}", marker);
+            let phone_number = "+15555550123";
+
+            let webhook_id: i32 = sqlx::query_scalar(
+                "INSERT INTO webhooks (url, event_types, secret) VALUES ('http://127.0.0.1:9/',
+                ARRAY['attendance.created', 'attendance.closed', 'user.created', 'user.updated', 'user.archived'],
+                '0123456789abcdef') RETURNING id",
+            )
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+            let uuid: Uuid = sqlx::query_scalar(
+                "INSERT INTO users (full_name, email, phone_number, alt_id_fields, create_time, update_time)
+                VALUES ($1, $2, $3, jsonb_build_object('erase_test_card', $4::text), now(), now()) RETURNING uuid",
+            )
+            .bind(&full_name)
+            .bind(&email)
+            .bind(phone_number)
+            .bind(&card)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+            sqlx::query(
+                "INSERT INTO notification_preferences (user_uuid, sign_out_notices, guardian_emails)
+                VALUES ($1, TRUE, ARRAY[$2])",
+            )
+            .bind(uuid)
+            .bind(&guardian_email)
+            .execute(&pool)
+            .await
+            .unwrap();
+            // Signing in and out queues webhooks and e-mail to the guardian
+            let attendance_id: i32 = sqlx::query_scalar(
+                "INSERT INTO attendance (user_uuid, in_time) VALUES ($1, now() - interval '1 hour') RETURNING id",
+            )
+            .bind(uuid)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+            sqlx::query("UPDATE attendance SET out_time = now() WHERE id=$1")
+                .bind(attendance_id)
+                .execute(&pool)
+                .await
+                .unwrap();
+            sqlx::query(
+                "INSERT INTO excuses (user_uuid, date, reason) VALUES ($1, CURRENT_DATE, $2)",
+            )
+            .bind(uuid)
+            .bind(&reason)
+            .execute(&pool)
+            .await
+            .unwrap();
+            let account_uuid: Uuid = sqlx::query_scalar(
+                "INSERT INTO accounts (email, full_name, capability, user_uuid) VALUES ($1, $2, 'member', $3) RETURNING uuid",
+            )
+            .bind(&account_email)
+            .bind(&full_name)
+            .bind(uuid)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+            sqlx::query(
+                "INSERT INTO tokens (description, expiration_time, create_time, capability, user_uuid, account_uuid)
+                VALUES ($1, now() + interval '1 day', now(), 'member', $2, $3)",
+            )
+            .bind(format!("Login by {}", account_email))
+            .bind(uuid)
+            .bind(account_uuid)
+            .execute(&pool)
+            .await
+            .unwrap();
+            sqlx::query("INSERT INTO login_failures (email) VALUES ($1)")
+                .bind(&account_email)
+                .execute(&pool)
+                .await
+                .unwrap();
+            sqlx::query(
+                "INSERT INTO audit_log (action, subject_user_uuid, details, create_time)
+                VALUES ('create_account', $1, jsonb_build_object('email', $2::text), now())",
+            )
+            .bind(uuid)
+            .bind(&account_email)
+            .execute(&pool)
+            .await
+            .unwrap();
+            // Another mentor's summary that lists them
+            let other_line = "- Someone Else <someone.else@example.com>: 2.0 hours";
+            let summary_id: i64 = sqlx::query_scalar(
+                "INSERT INTO notifications (kind, recipient, data) VALUES ('weekly_summary', 'mentor@example.com',
+                jsonb_build_object('members', $1::text)) RETURNING id",
+            )
+            .bind(format!("- {} <{}>: 1.0 hours\n{}", full_name, email, other_line))
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+
+            let deliveries_before: i64 =
+                sqlx::query_scalar("SELECT COUNT(*) FROM webhook_deliveries WHERE webhook_id=$1")
+                    .bind(webhook_id)
+                    .fetch_one(&pool)
+                    .await
+                    .unwrap();
+            assert!(deliveries_before > 0);
+
+            let response = schema
+                .execute(
+                    async_graphql::Request::new(format!(
+                        "mutation {{ eraseUser(uuid: \"{}\", mode: {}) }}",
+                        uuid, mode
+                    ))
+                    .data(TokenCapability::Administrator),
+                )
+                .await;
+            assert!(response.errors.is_empty(), "{:?}", response.errors);
+
+            let tables: Vec<String> = sqlx::query_scalar(
+                "SELECT table_name::text FROM information_schema.tables
+                WHERE table_schema = 'public' AND table_type = 'BASE TABLE' AND table_name <> '_sqlx_migrations'",
+            )
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+            for table in &tables {
+                for personal in &[&marker[..], phone_number, &uuid.to_string()] {
+                    // The UUID itself is fine to keep for statistics and the audit log
+                    if *personal == uuid.to_string()
+                        && (table == "users"
+                            || table == "attendance"
+                            || table == "audit_log"
+                            || table == "excuses"
+                            || table == "badges"
+                            || table == "tokens")
+                    {
+                        continue;
+                    }
+                    let left: i64 = sqlx::query_scalar(&format!(
+                        "SELECT COUNT(*) FROM {} t WHERE t::text LIKE '%' || $1 || '%'",
+                        table
+                    ))
+                    .bind(personal)
+                    .fetch_one(&pool)
+                    .await
+                    .unwrap();
+                    assert_eq!(left, 0, "{} still has {} after {}", table, personal, mode);
+                }
+            }
+
+            let summary: String =
+                sqlx::query_scalar("SELECT data->>'members' FROM notifications WHERE id=$1")
+                    .bind(summary_id)
+                    .fetch_one(&pool)
+                    .await
+                    .unwrap();
+            assert_eq!(summary, format!("- Erased user\n{}", other_line));
 
-        let private_key_read = PRIVATE_KEY.read().unwrap();
-        let private_key_as_bytes = private_key_read.as_ref();
-        match jsonwebtoken::encode(
-            &Header::new(Algorithm::ES256),
-            &claims,
-            &EncodingKey::from_ec_pem(private_key_as_bytes).expect("Expected a valid private key"),
-        ) {
-            Ok(key) => Ok(key),
-            Err(error) => Err(async_graphql::Error::new(format!("{}", error))),
+            sqlx::query("DELETE FROM webhooks WHERE id=$1")
+                .bind(webhook_id)
+                .execute(&pool)
+                .await
+                .unwrap();
+            sqlx::query("DELETE FROM notifications WHERE id=$1")
+                .bind(summary_id)
+                .execute(&pool)
+                .await
+                .unwrap();
         }
     }
 }
@@ -0,0 +1,480 @@
+// Bulk user import, shared by the importUsers mutation and the `import-users` command
+
+use crate::graphql_schema::{fetch_alt_id_type, record_audit};
+use crate::tables::*;
+use async_graphql::validators::{Email, InputValueValidator};
+use async_graphql::*;
+use serde::Deserialize;
+use sqlx::{
+    postgres::PgPool,
+    types::{chrono::Utc, Uuid},
+};
+use std::collections::HashMap;
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ImportFormat {
+    // A header row, then one user per line. Alt IDs go in columns named like alt_id.rfid,
+    // and groups are separated with semicolons.
+    Csv,
+    // An array of objects with the same fields as createUser
+    Json,
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ImportAction {
+    Create,
+    Update,
+    // The user already exists and the row wouldn't change anything
+    Unchanged,
+    // Clashes with another user or another row in the file
+    Conflict,
+    Invalid,
+}
+
+#[derive(SimpleObject)]
+#[graphql(complex)]
+pub struct ImportRowResult {
+    // Counts from 1 and doesn't include the CSV header
+    pub row: i32,
+    pub action: ImportAction,
+    pub full_name: String,
+    pub email: String,
+    // What's wrong with the row, or what an update changes
+    pub messages: Vec<String>,
+    #[graphql(skip)]
+    pub user_uuid: Option<Uuid>,
+    // The user as it will be written, for creates and updates
+    #[graphql(skip)]
+    pub user: Option<User>,
+}
+
+#[ComplexObject]
+impl ImportRowResult {
+    async fn user_uuid(&self) -> Option<String> {
+        self.user_uuid
+            .map(|user_uuid| user_uuid.to_hyphenated().to_string())
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct ImportReport {
+    pub rows: Vec<ImportRowResult>,
+    pub creates: i32,
+    pub updates: i32,
+    pub unchanged: i32,
+    pub conflicts: i32,
+    pub invalid: i32,
+    // False for dry runs, and when conflicts or invalid rows stopped the import
+    pub applied: bool,
+}
+
+impl ImportReport {
+    fn new(rows: Vec<ImportRowResult>, applied: bool) -> ImportReport {
+        let count = |action| rows.iter().filter(|row| row.action == action).count() as i32;
+        ImportReport {
+            creates: count(ImportAction::Create),
+            updates: count(ImportAction::Update),
+            unchanged: count(ImportAction::Unchanged),
+            conflicts: count(ImportAction::Conflict),
+            invalid: count(ImportAction::Invalid),
+            rows,
+            applied,
+        }
+    }
+}
+
+// Everything is optional here so a bad row shows up in the report instead of failing the whole file
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+struct ImportRow {
+    #[serde(alias = "full_name", alias = "name")]
+    full_name: String,
+    email: String,
+    #[serde(alias = "phone_number", alias = "phone")]
+    phone_number: Option<String>,
+    #[serde(alias = "alt_id_fields")]
+    alt_id_fields: HashMap<String, String>,
+    groups: Vec<String>,
+}
+
+fn parse_rows(format: ImportFormat, data: &str) -> Result<Vec<ImportRow>> {
+    match format {
+        ImportFormat::Json => serde_json::from_str(data)
+            .map_err(|e| Error::new(format!("Could not read the JSON import: {}", e))),
+        ImportFormat::Csv => {
+            let mut reader = csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .from_reader(data.as_bytes());
+            let headers = reader.headers()?.clone();
+            let mut rows = Vec::new();
+
+            for record in reader.records() {
+                let record = record
+                    .map_err(|e| Error::new(format!("Could not read the CSV import: {}", e)))?;
+                let mut row = ImportRow::default();
+
+                for (header, value) in headers.iter().zip(record.iter()) {
+                    if value.is_empty() {
+                        continue;
+                    }
+                    match header {
+                        "full_name" | "name" => row.full_name = value.to_string(),
+                        "email" => row.email = value.to_string(),
+                        "phone_number" | "phone" => row.phone_number = Some(value.to_string()),
+                        "groups" => row.groups = value.split(';').map(str::to_string).collect(),
+                        // The users export writes these, but they aren't something an import sets
+                        "uuid" | "create_time" | "update_time" | "archive_time"
+                        | "archive_reason" => {}
+                        _ => match header.strip_prefix("alt_id.") {
+                            Some(field) => {
+                                row.alt_id_fields
+                                    .insert(field.to_string(), value.to_string());
+                            }
+                            None => {
+                                return Err(Error::new(format!(
+                                    "Unknown column '{}'. Alt ID columns look like alt_id.FIELD",
+                                    header
+                                )))
+                            }
+                        },
+                    }
+                }
+
+                rows.push(row);
+            }
+
+            Ok(rows)
+        }
+    }
+}
+
+// Works out what each row would do without writing anything. Rows are matched to existing
+// users by e-mail. Updates overwrite the name and phone number, merge in the alt IDs and add
+// the groups, so a file only has to list what it knows about.
+async fn plan_import(pool: &PgPool, rows: Vec<ImportRow>) -> Result<Vec<ImportRowResult>> {
+    let mut results = Vec::with_capacity(rows.len());
+    // Two rows in the same file can't have the same e-mail or unique alt ID either
+    let mut seen_emails: HashMap<String, i32> = HashMap::new();
+    let mut seen_alt_ids: HashMap<(String, String), i32> = HashMap::new();
+
+    for (index, row) in rows.into_iter().enumerate() {
+        let row_number = index as i32 + 1;
+        let mut invalid = Vec::new();
+        let mut conflicts = Vec::new();
+
+        let full_name = row.full_name.trim().to_string();
+        let email = row.email.trim().to_string();
+        if full_name.is_empty() {
+            invalid.push("Missing full name".to_string());
+        }
+        if let Err(e) = (Email {}).is_valid(&Value::String(email.clone())) {
+            invalid.push(format!("{} is an {}", email, e));
+        }
+        let phone_number = match row.phone_number.as_deref().map(str::trim) {
+            Some(phone_number) if !phone_number.is_empty() => {
+                match normalize_phone_number(phone_number) {
+                    Ok(phone_number) => Some(phone_number),
+                    Err(e) => {
+                        invalid.push(e);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        let existing = sqlx::query_as!(
+            User,
+            "SELECT * FROM users WHERE lower(email)=lower($1) AND archive_time IS NULL",
+            email
+        )
+        .fetch_optional(pool)
+        .await?;
+        if let Some(other_row) = seen_emails.insert(email.to_lowercase(), row_number) {
+            conflicts.push(format!("Row {} has the same e-mail", other_row));
+        }
+
+        let mut alt_id_fields = HashMap::new();
+        for (field, value) in row.alt_id_fields {
+            let alt_id_type = match fetch_alt_id_type(pool, &field).await {
+                Ok(alt_id_type) => alt_id_type,
+                Err(e) => {
+                    invalid.push(e.message);
+                    continue;
+                }
+            };
+            let value = match alt_id_type.normalize(&value) {
+                Ok(value) => value,
+                Err(e) => {
+                    invalid.push(e);
+                    continue;
+                }
+            };
+
+            if alt_id_type.is_unique {
+                if let Some(other_row) =
+                    seen_alt_ids.insert((field.clone(), value.clone()), row_number)
+                {
+                    conflicts.push(format!(
+                        "Row {} has the same value for alt ID field '{}'",
+                        other_row, field
+                    ));
+                }
+                if let Some(owner) = sqlx::query!(
+                    "SELECT full_name FROM users WHERE alt_id_fields->>$1 = $2 AND uuid IS DISTINCT FROM $3",
+                    field,
+                    value,
+                    existing.as_ref().map(|user| user.uuid)
+                )
+                .fetch_optional(pool)
+                .await?
+                {
+                    conflicts.push(format!(
+                        "{} already has the value {} for alt ID field '{}'",
+                        owner.full_name, value, field
+                    ));
+                }
+            }
+
+            alt_id_fields.insert(field, value);
+        }
+
+        let mut groups: Vec<String> = Vec::new();
+        for group in row.groups {
+            let group = group.trim();
+            if !group.is_empty() && !groups.iter().any(|existing| existing == group) {
+                groups.push(group.to_string());
+            }
+        }
+
+        let mut result = ImportRowResult {
+            row: row_number,
+            action: ImportAction::Create,
+            full_name,
+            email,
+            messages: Vec::new(),
+            user_uuid: existing.as_ref().map(|user| user.uuid),
+            user: None,
+        };
+
+        if !invalid.is_empty() {
+            result.action = ImportAction::Invalid;
+            result.messages = invalid;
+        } else if !conflicts.is_empty() {
+            result.action = ImportAction::Conflict;
+            result.messages = conflicts;
+        } else if let Some(mut user) = existing {
+            let mut changes = Vec::new();
+
+            if user.full_name != result.full_name {
+                changes.push(format!("full name from {}", user.full_name));
+                user.full_name = result.full_name.clone();
+            }
+            if phone_number.is_some() && user.phone_number != phone_number {
+                changes.push("phone number".to_string());
+                user.phone_number = phone_number;
+            }
+            let mut merged_alt_id_fields = match user.alt_id_fields.take() {
+                Some(serde_json::Value::Object(fields)) => fields,
+                _ => serde_json::Map::new(),
+            };
+            for (field, value) in alt_id_fields {
+                let value = serde_json::Value::String(value);
+                if merged_alt_id_fields.get(&field) != Some(&value) {
+                    changes.push(format!("alt ID field '{}'", field));
+                    merged_alt_id_fields.insert(field, value);
+                }
+            }
+            if !merged_alt_id_fields.is_empty() {
+                user.alt_id_fields = Some(serde_json::Value::Object(merged_alt_id_fields));
+            }
+            for group in groups {
+                if !user.groups.contains(&group) {
+                    changes.push(format!("group {}", group));
+                    user.groups.push(group);
+                }
+            }
+
+            if changes.is_empty() {
+                result.action = ImportAction::Unchanged;
+            } else {
+                result.action = ImportAction::Update;
+                result.messages = vec![format!("Changes {}", changes.join(", "))];
+                result.user = Some(user);
+            }
+        } else {
+            result.user = Some(User {
+                uuid: Uuid::nil(),
+                full_name: result.full_name.clone(),
+                email: result.email.clone(),
+                phone_number,
+                create_time: Utc::now(),
+                update_time: None,
+                alt_id_fields: if alt_id_fields.is_empty() {
+                    None
+                } else {
+                    Some(serde_json::to_value(alt_id_fields)?)
+                },
+                groups,
+                archive_time: None,
+                archive_reason: None,
+                version: 0,
+            });
+        }
+
+        results.push(result);
+    }
+
+    Ok(results)
+}
+
+// Writes every planned create and update in one transaction, so a failure part way through
+// leaves the database as it was
+async fn apply_import(
+    pool: &PgPool,
+    results: &mut [ImportRowResult],
+    actor_token_uuid: Option<Uuid>,
+) -> Result<()> {
+    let mut tx = pool.begin().await?;
+
+    for result in results.iter_mut() {
+        let user = match &result.user {
+            Some(user) => user,
+            None => continue,
+        };
+
+        match result.action {
+            ImportAction::Create => {
+                result.user_uuid = Some(sqlx::query!(
+                    "INSERT INTO users (full_name, email, phone_number, create_time, alt_id_fields, groups) VALUES ($1, $2, $3, $4, $5, $6) RETURNING uuid",
+                    user.full_name,
+                    user.email,
+                    user.phone_number,
+                    user.create_time,
+                    user.alt_id_fields,
+                    &user.groups
+                )
+                .fetch_one(&mut tx)
+                .await?
+                .uuid);
+            }
+            ImportAction::Update => {
+                // Same check as update_user, in case someone edited the user after the plan was made
+                let updated = sqlx::query!(
+                    "UPDATE users SET (full_name, phone_number, alt_id_fields, groups) = ($1, $2, $3, $4) WHERE uuid=$5 AND version=$6",
+                    user.full_name,
+                    user.phone_number,
+                    user.alt_id_fields,
+                    &user.groups,
+                    user.uuid,
+                    user.version
+                )
+                .execute(&mut tx)
+                .await?
+                .rows_affected();
+                if updated == 0 {
+                    return Err(Error::new(format!(
+                        "Row {}: {} was changed during the import. Nothing was imported, please try again.",
+                        result.row, result.email
+                    )));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let report_counts = |action| results.iter().filter(|row| row.action == action).count();
+    record_audit(
+        &mut tx,
+        actor_token_uuid,
+        "import_users",
+        None,
+        serde_json::json!({
+            "created": report_counts(ImportAction::Create),
+            "updated": report_counts(ImportAction::Update),
+        }),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+// Plans the import and, unless this is a dry run, applies it. The import is only applied
+// if every row is valid and conflict-free.
+pub async fn import_users(
+    pool: &PgPool,
+    format: ImportFormat,
+    data: &str,
+    dry_run: bool,
+    actor_token_uuid: Option<Uuid>,
+) -> Result<ImportReport> {
+    let mut results = plan_import(pool, parse_rows(format, data)?).await?;
+
+    let applied = !dry_run
+        && results.iter().all(|result| {
+            result.action != ImportAction::Conflict && result.action != ImportAction::Invalid
+        });
+    if applied {
+        apply_import(pool, &mut results, actor_token_uuid).await?;
+    }
+
+    Ok(ImportReport::new(results, applied))
+}
+
+// `attendance-rs import-users FILE [--apply]`. Files ending in .json are read as JSON,
+// anything else as CSV.
+pub async fn run_cli(pool: &PgPool, args: &[String]) -> Result<(), String> {
+    let mut path = None;
+    let mut apply = false;
+    for arg in args {
+        match arg.as_str() {
+            "--apply" => apply = true,
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(format!("Unexpected argument {}", arg)),
+        }
+    }
+    let path = path.ok_or("Usage: attendance-rs import-users FILE [--apply]")?;
+
+    let data =
+        std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+    let format = if path.to_lowercase().ends_with(".json") {
+        ImportFormat::Json
+    } else {
+        ImportFormat::Csv
+    };
+
+    let report = import_users(pool, format, &data, !apply, None)
+        .await
+        .map_err(|e| e.message)?;
+
+    for row in &report.rows {
+        println!(
+            "{:>4}  {:<9}  {} <{}>",
+            row.row,
+            format!("{:?}", row.action).to_uppercase(),
+            row.full_name,
+            row.email
+        );
+        for message in &row.messages {
+            println!("                 {}", message);
+        }
+    }
+    println!(
+        "\n{} to create, {} to update, {} unchanged, {} conflicts, {} invalid",
+        report.creates, report.updates, report.unchanged, report.conflicts, report.invalid
+    );
+
+    if report.applied {
+        println!("Import applied.");
+        Ok(())
+    } else if report.conflicts > 0 || report.invalid > 0 {
+        Err(
+            "Fix the conflicts and invalid rows above before importing. Nothing was written."
+                .to_string(),
+        )
+    } else {
+        println!("Dry run, nothing was written. Run again with --apply to import.");
+        Ok(())
+    }
+}
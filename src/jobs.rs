@@ -0,0 +1,466 @@
+// Runs the jobs in the jobs table on their cron schedules. Every run is recorded in job_runs,
+// which is what the admin `jobs` query shows.
+
+use crate::accounts;
+use crate::graphql_schema::SIGN_OUT_WINDOW_HOURS;
+use crate::notifications;
+use crate::tables::{JobKind, JobRun, JobRunStatus};
+use crate::{EMAIL_ENABLED, WEEKLY_SUMMARY_THRESHOLD_HOURS};
+use chrono::{Datelike, Duration, TimeZone, Timelike};
+use log::{error, info};
+use sqlx::{
+    postgres::PgPool,
+    types::{
+        chrono::{DateTime, Utc},
+        Uuid,
+    },
+};
+use std::sync::Arc;
+
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+// How long prune_logs keeps webhook deliveries, notifications and job runs. The audit log is
+// left alone, since that's a record rather than a log.
+const LOG_RETENTION_DAYS: i32 = 90;
+// Expired tokens are kept this long, so admins can still see what an old one was
+const TOKEN_RETENTION_DAYS: i32 = 30;
+
+// A cron schedule: minute, hour, day of month, month and day of week, each a bit set
+#[derive(Debug, PartialEq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    // Like cron, a day matches if either day field does, unless one of them is *
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+impl Schedule {
+    // Takes the usual five fields, where each is *, a number, a range like 1-5, a list like
+    // 1,3,5, or any of those followed by a step like */15. Sunday is 0 or 7. @hourly, @daily,
+    // @weekly and @monthly work too.
+    pub fn parse(schedule: &str) -> Result<Schedule, String> {
+        let expanded = match schedule.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            schedule => schedule,
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!(
+                "{} should have 5 fields (minute hour day-of-month month day-of-week)",
+                schedule
+            ));
+        }
+
+        let mut days_of_week = parse_field(fields[4], 0, 7, "day of week")?;
+        // 7 is also Sunday
+        if days_of_week & (1 << 7) != 0 {
+            days_of_week |= 1;
+        }
+
+        Ok(Schedule {
+            minutes: parse_field(fields[0], 0, 59, "minute")?,
+            hours: parse_field(fields[1], 0, 23, "hour")?,
+            days_of_month: parse_field(fields[2], 1, 31, "day of month")?,
+            months: parse_field(fields[3], 1, 12, "month")?,
+            days_of_week,
+            any_day_of_month: fields[2] == "*",
+            any_day_of_week: fields[4] == "*",
+        })
+    }
+
+    fn matches_day(&self, time: DateTime<Utc>) -> bool {
+        let day_of_month = self.days_of_month & (1 << time.day()) != 0;
+        let day_of_week = self.days_of_week & (1 << time.weekday().num_days_from_sunday()) != 0;
+        match (self.any_day_of_month, self.any_day_of_week) {
+            (true, true) => true,
+            (true, false) => day_of_week,
+            (false, true) => day_of_month,
+            (false, false) => day_of_month || day_of_week,
+        }
+    }
+
+    // The first minute after `after` that the schedule matches. None for schedules that can
+    // never happen, like February 30th.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut time = Utc.ymd(after.year(), after.month(), after.day()).and_hms(
+            after.hour(),
+            after.minute(),
+            0,
+        ) + Duration::minutes(1);
+        // Skipping whole months, days and hours at a time, this covers every combination well
+        // within a few thousand steps
+        for _ in 0..10000 {
+            if self.months & (1 << time.month()) == 0 {
+                time = if time.month() == 12 {
+                    Utc.ymd(time.year() + 1, 1, 1)
+                } else {
+                    Utc.ymd(time.year(), time.month() + 1, 1)
+                }
+                .and_hms(0, 0, 0);
+            } else if !self.matches_day(time) {
+                time = (time.date() + Duration::days(1)).and_hms(0, 0, 0);
+            } else if self.hours & (1 << time.hour()) == 0 {
+                time = time.date().and_hms(time.hour(), 0, 0) + Duration::hours(1);
+            } else if self.minutes & (1 << time.minute()) == 0 {
+                time = time + Duration::minutes(1);
+            } else {
+                return Some(time);
+            }
+        }
+        None
+    }
+}
+
+fn parse_field(field: &str, min: u32, max: u32, name: &str) -> Result<u64, String> {
+    let invalid = || {
+        format!(
+            "{} is not a valid {} (it can be {}-{})",
+            field, name, min, max
+        )
+    };
+    let number = |value: &str| -> Result<u32, String> {
+        match value.parse::<u32>() {
+            Ok(value) if (min..=max).contains(&value) => Ok(value),
+            _ => Err(invalid()),
+        }
+    };
+
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(invalid()),
+            },
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (number(start)?, number(end)?),
+                // 5/10 means from 5 to the end, every 10
+                None if step > 1 => (number(range)?, max),
+                None => (number(range)?, number(range)?),
+            },
+        };
+        if start > end {
+            return Err(invalid());
+        }
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+// Runs for as long as the server does
+pub async fn run_jobs(pool: Arc<PgPool>) {
+    loop {
+        if let Err(e) = run_due_jobs(&pool).await {
+            error!("Could not run scheduled jobs: {}", e);
+        }
+        actix_web::rt::time::delay_for(POLL_INTERVAL).await;
+    }
+}
+
+async fn run_due_jobs(pool: &PgPool) -> Result<(), sqlx::Error> {
+    let jobs = sqlx::query!(
+        r#"SELECT kind as "kind: JobKind", schedule, next_run_time FROM jobs WHERE enabled"#
+    )
+    .fetch_all(pool)
+    .await?;
+    let now = Utc::now();
+
+    for job in jobs {
+        let next_run_time = match Schedule::parse(&job.schedule) {
+            Ok(schedule) => schedule.next_after(now),
+            Err(e) => {
+                error!("The {:?} job has a bad schedule: {}", job.kind, e);
+                continue;
+            }
+        };
+
+        match job.next_run_time {
+            // Not due yet
+            Some(due) if due > now => continue,
+            // Only one server gets to move next_run_time along, and that's the one that runs it
+            Some(due) => {
+                if sqlx::query!(
+                    "UPDATE jobs SET next_run_time=$1 WHERE kind=$2 AND next_run_time=$3",
+                    next_run_time,
+                    job.kind as JobKind,
+                    due
+                )
+                .execute(pool)
+                .await?
+                .rows_affected()
+                    == 1
+                {
+                    // The rest of the jobs still get their turn
+                    if let Err(e) = run_job(pool, job.kind, None).await {
+                        error!("Could not record a run of the {:?} job: {}", job.kind, e);
+                        continue;
+                    }
+                }
+            }
+            // New, or the schedule just changed
+            None => {
+                sqlx::query!(
+                    "UPDATE jobs SET next_run_time=$1 WHERE kind=$2 AND next_run_time IS NULL",
+                    next_run_time,
+                    job.kind as JobKind
+                )
+                .execute(pool)
+                .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Runs a job right away and records how it went. A job failing is recorded rather than
+// returned as an error, which is only for not being able to record anything at all.
+pub async fn run_job(
+    pool: &PgPool,
+    kind: JobKind,
+    triggered_by_token_uuid: Option<Uuid>,
+) -> Result<JobRun, sqlx::Error> {
+    let id = sqlx::query!(
+        "INSERT INTO job_runs (kind, triggered_by_token_uuid) VALUES ($1, $2) RETURNING id",
+        kind as JobKind,
+        triggered_by_token_uuid
+    )
+    .fetch_one(pool)
+    .await?
+    .id;
+
+    let (status, result) = match execute(pool, kind).await {
+        Ok(result) => {
+            info!("The {:?} job finished: {}", kind, result);
+            (JobRunStatus::Succeeded, result)
+        }
+        Err(e) => {
+            error!("The {:?} job failed: {}", kind, e);
+            (JobRunStatus::Failed, e.to_string())
+        }
+    };
+
+    sqlx::query_as!(
+        JobRun,
+        r#"UPDATE job_runs SET (status, result, end_time) = ($1, $2, now()) WHERE id=$3
+        RETURNING id, kind as "kind: JobKind", triggered_by_token_uuid, status as "status: JobRunStatus",
+        result, start_time, end_time"#,
+        status as JobRunStatus,
+        result,
+        id
+    )
+    .fetch_one(pool)
+    .await
+}
+
+async fn execute(pool: &PgPool, kind: JobKind) -> Result<String, sqlx::Error> {
+    match kind {
+        JobKind::WeeklySummary => {
+            if !*EMAIL_ENABLED.read().unwrap() {
+                return Ok("E-mail is off, so there was nothing to do".to_string());
+            }
+            let threshold_hours = *WEEKLY_SUMMARY_THRESHOLD_HOURS.read().unwrap();
+            let mut conn = pool.acquire().await?;
+            let queued =
+                notifications::queue_weekly_summary(&mut conn, Utc::now(), threshold_hours).await?;
+            Ok(format!("Queued {} weekly summaries", queued))
+        }
+        // These can't be signed out of any more (a scan would sign in again instead), so
+        // they'd stay open forever
+        JobKind::CloseOpenSessions => {
+            let closed = sqlx::query!(
+                "UPDATE attendance SET (out_time, auto_closed) = (in_time, TRUE)
+                WHERE out_time IS NULL AND in_time <= now() - make_interval(hours => $1)",
+                SIGN_OUT_WINDOW_HOURS
+            )
+            .execute(pool)
+            .await?
+            .rows_affected();
+            Ok(format!("Closed {} sessions", closed))
+        }
+        // Anything a token did points back at it, so only tokens that never did anything can
+        // go. There has to be a valid token left too, or the next start would be a first run
+        // and hand out an administrator token to whoever asks.
+        JobKind::ExpireTokens => {
+            let removed = sqlx::query!(
+                "DELETE FROM tokens t
+                WHERE t.expiration_time < now() - make_interval(days => $1)
+                AND EXISTS (SELECT 1 FROM tokens WHERE expiration_time > now())
+                AND NOT EXISTS (SELECT 1 FROM attendance WHERE device_token_uuid = t.uuid)
+                AND NOT EXISTS (SELECT 1 FROM pending_identifiers WHERE device_token_uuid = t.uuid)
+                AND NOT EXISTS (SELECT 1 FROM audit_log WHERE actor_token_uuid = t.uuid)
+                AND NOT EXISTS (SELECT 1 FROM roll_calls WHERE started_by_token_uuid = t.uuid)
+                AND NOT EXISTS (SELECT 1 FROM roll_call_entries WHERE accounted_by_token_uuid = t.uuid)
+                AND NOT EXISTS (SELECT 1 FROM job_runs WHERE triggered_by_token_uuid = t.uuid)
+                AND NOT EXISTS (SELECT 1 FROM excuses WHERE reviewed_by_token_uuid = t.uuid)",
+                TOKEN_RETENTION_DAYS
+            )
+            .execute(pool)
+            .await?
+            .rows_affected();
+            Ok(format!("Removed {} expired tokens", removed))
+        }
+        JobKind::PruneLogs => {
+            let mut tx = pool.begin().await?;
+            let deliveries = sqlx::query!(
+                "DELETE FROM webhook_deliveries
+                WHERE status <> 'pending' AND create_time < now() - make_interval(days => $1)",
+                LOG_RETENTION_DAYS
+            )
+            .execute(&mut tx)
+            .await?
+            .rows_affected();
+            let notifications = sqlx::query!(
+                "DELETE FROM notifications
+                WHERE status <> 'pending' AND create_time < now() - make_interval(days => $1)",
+                LOG_RETENTION_DAYS
+            )
+            .execute(&mut tx)
+            .await?
+            .rows_affected();
+            let runs = sqlx::query!(
+                "DELETE FROM job_runs
+                WHERE status <> 'running' AND start_time < now() - make_interval(days => $1)",
+                LOG_RETENTION_DAYS
+            )
+            .execute(&mut tx)
+            .await?
+            .rows_affected();
+            // Only the last few minutes of these matter
+            sqlx::query!(
+                "DELETE FROM login_failures WHERE attempt_time < now() - make_interval(mins => $1)",
+                accounts::LOGIN_LOCKOUT_MINUTES
+            )
+            .execute(&mut tx)
+            .await?;
+            tx.commit().await?;
+            Ok(format!(
+                "Removed {} webhook deliveries, {} notifications and {} job runs",
+                deliveries, notifications, runs
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> DateTime<Utc> {
+        Utc.datetime_from_str(time, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn bits(values: &[u32]) -> u64 {
+        values.iter().fold(0, |bits, value| bits | 1 << value)
+    }
+
+    #[test]
+    fn parse_field_cases() {
+        let cases: &[(&str, u32, u32, &[u32])] = &[
+            ("*/15", 0, 59, &[0, 15, 30, 45]),
+            ("1-5", 0, 7, &[1, 2, 3, 4, 5]),
+            ("5/10", 0, 59, &[5, 15, 25, 35, 45, 55]),
+            ("10-20/5", 0, 59, &[10, 15, 20]),
+            ("1,3,5", 0, 7, &[1, 3, 5]),
+            ("0-2,22-23", 0, 23, &[0, 1, 2, 22, 23]),
+            ("7", 0, 7, &[7]),
+            ("*", 1, 12, &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]),
+        ];
+        for (field, min, max, values) in cases {
+            assert_eq!(
+                parse_field(field, *min, *max, "test"),
+                Ok(bits(values)),
+                "{}",
+                field
+            );
+        }
+
+        for field in &["60", "5-1", "*/0", "*/x", "a", "", "1-", "0"] {
+            assert!(parse_field(field, 1, 59, "test").is_err(), "{}", field);
+        }
+    }
+
+    #[test]
+    fn parse_schedules() {
+        // Sunday is 0 or 7
+        assert_eq!(Schedule::parse("0 0 * * 7").unwrap().days_of_week & 1, 1);
+        assert_eq!(Schedule::parse("@weekly"), Schedule::parse("0 0 * * 0"));
+        assert_eq!(Schedule::parse("@daily"), Schedule::parse("0 0 * * *"));
+        assert_eq!(Schedule::parse(" @hourly "), Schedule::parse("0 * * * *"));
+
+        for schedule in &[
+            "",
+            "* * * *",
+            "* * * * * *",
+            "@yearly",
+            "0 24 * * *",
+            "0 0 0 * *",
+        ] {
+            assert!(Schedule::parse(schedule).is_err(), "{}", schedule);
+        }
+    }
+
+    #[test]
+    fn next_after_cases() {
+        // 2026-10-19 is a Monday
+        let cases = [
+            ("*/15 * * * *", "2026-10-19 10:07", "2026-10-19 10:15"),
+            ("*/15 * * * *", "2026-10-19 10:15", "2026-10-19 10:30"),
+            ("*/15 * * * *", "2026-10-19 23:50", "2026-10-20 00:00"),
+            ("5/10 * * * *", "2026-10-19 10:56", "2026-10-19 11:05"),
+            ("0 9 * * 1-5", "2026-10-23 09:00", "2026-10-26 09:00"),
+            ("0 9 * * 1-5", "2026-10-24 08:00", "2026-10-26 09:00"),
+            ("0 9 * * 7", "2026-10-19 10:00", "2026-10-25 09:00"),
+            ("@weekly", "2026-10-19 10:00", "2026-10-25 00:00"),
+            ("@monthly", "2026-12-15 10:00", "2027-01-01 00:00"),
+            // With both day fields set, either one matching is enough: the 1st or a Friday
+            ("0 0 1 * 5", "2026-10-24 00:00", "2026-10-30 00:00"),
+            ("0 0 1 * 5", "2026-10-30 00:00", "2026-11-01 00:00"),
+            // With one of them *, only the other counts
+            ("0 0 1 * *", "2026-10-24 00:00", "2026-11-01 00:00"),
+            ("0 0 * * 5", "2026-10-30 00:00", "2026-11-06 00:00"),
+            ("0 0 29 2 *", "2026-03-01 00:00", "2028-02-29 00:00"),
+            ("30 3 31 * *", "2026-11-01 00:00", "2026-12-31 03:30"),
+        ];
+        for (schedule, after, next) in cases.iter() {
+            assert_eq!(
+                Schedule::parse(schedule).unwrap().next_after(at(after)),
+                Some(at(next)),
+                "{} after {}",
+                schedule,
+                after
+            );
+        }
+
+        // Seconds are dropped, and the result is always after `after`
+        assert_eq!(
+            Schedule::parse("* * * * *")
+                .unwrap()
+                .next_after(at("2026-10-19 10:07") + Duration::seconds(59)),
+            Some(at("2026-10-19 10:08"))
+        );
+
+        for schedule in &["0 0 30 2 *", "0 0 31 4 *"] {
+            assert_eq!(
+                Schedule::parse(schedule)
+                    .unwrap()
+                    .next_after(at("2026-10-19 10:00")),
+                None,
+                "{}",
+                schedule
+            );
+        }
+    }
+}
@@ -2,11 +2,9 @@ use actix_cors::Cors;
 use actix_web::{guard, middleware::Logger, web, App, HttpRequest, HttpResponse, HttpServer};
 use async_graphql::{
     http::{playground_source, GraphQLPlaygroundConfig},
-    EmptySubscription, Schema, ServerError,
+    Schema, ServerError,
 };
-use async_graphql_actix_web::{Request, Response};
-use dotenv;
-use http;
+use async_graphql_actix_web::{Request, Response, WSSubscription};
 use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
 use log::{debug, error, info, warn};
 use sqlx::postgres::{PgPool, PgPoolOptions};
@@ -14,14 +12,30 @@ use std::sync::{Arc, RwLock};
 
 use lazy_static::lazy_static;
 
+mod accounts;
+mod badges;
+mod calendar;
+mod checkin;
+mod events;
+mod export;
 mod graphql_schema;
+mod import;
+mod jobs;
+mod notifications;
 mod tables;
+mod webhooks;
 
 lazy_static! {
     // Unfortunately, there is no better solution than this (other than option, which will just be a pain later)
     static ref PRIVATE_KEY: RwLock<String> = RwLock::new("".to_string());
     static ref PUBLIC_KEY: RwLock<String> = RwLock::new("".to_string());
     static ref FIRST_RUN: RwLock<bool> = RwLock::new(false);
+    // Phone numbers typed without a country code are assumed to be from here
+    static ref DEFAULT_PHONE_REGION: RwLock<phonenumber::country::Id> = RwLock::new(phonenumber::country::Id::US);
+    // Members who attended less than this many hours in a week show up in the weekly summary
+    static ref WEEKLY_SUMMARY_THRESHOLD_HOURS: RwLock<f64> = RwLock::new(4.0);
+    // Whether AR_PG_SMTP_URL is set
+    static ref EMAIL_ENABLED: RwLock<bool> = RwLock::new(false);
 }
 
 fn read_keyfile_from_envvar(var: &str) -> String {
@@ -42,12 +56,16 @@ fn read_keyfile_from_envvar(var: &str) -> String {
 async fn graphql_playground() -> HttpResponse {
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
-        .body(playground_source(GraphQLPlaygroundConfig::new("/graphql")))
+        .body(playground_source(
+            GraphQLPlaygroundConfig::new("/graphql").subscription_endpoint("/graphql"),
+        ))
 }
 
 async fn graphql_request(
     pool: web::Data<Arc<PgPool>>,
-    schema: web::Data<Schema<graphql_schema::Query, graphql_schema::Mutation, EmptySubscription>>,
+    schema: web::Data<
+        Schema<graphql_schema::Query, graphql_schema::Mutation, graphql_schema::Subscription>,
+    >,
     request: HttpRequest,
     graphql_request: Request,
 ) -> Response {
@@ -65,7 +83,7 @@ async fn graphql_request(
         let graphql_response = schema.execute(graphql_request).await.into();
 
         // Check if we should continue first-run mode
-        match check_first_run(&*pool).await {
+        match check_first_run(&pool).await {
             Ok(continue_first_run) if !continue_first_run => {
                 info!("NOTICE: Disabling first-run mode. A token has been generated.");
                 *FIRST_RUN.write().unwrap() = false;
@@ -79,42 +97,96 @@ async fn graphql_request(
         return graphql_response;
     }
 
-    if let Some(token) = request.headers().get("Token") {
-        if let Ok(token_str) = token.to_str() {
-            // Fetch token from SQL and check if it's valid
-            // TODO potential inefficiency here since it has to do this every time
-            let public_key_read = PUBLIC_KEY.read().unwrap();
-            let public_key_as_bytes = public_key_read.as_ref();
-            let decoding_key = match DecodingKey::from_ec_pem(public_key_as_bytes) {
-                Ok(decoding_key) => decoding_key,
-                Err(e) => return err_msg_response(&format!("Expected a valid public key. Please check your server configuration. Error: {}", e)),
-            };
-
-            match decode::<tables::JWTClaims>(
-                token_str,
-                &decoding_key,
-                &Validation::new(Algorithm::ES256),
-            ) {
-                Ok(claim_data) => {
-                    debug!("{:#?} details", claim_data);
-
-                    // TODO x-verify the claim data with the database.
-                    // If the data doesn't match with the data in the db,
-                    // **notify everything and everyone immediately, since someone stole the signing key**
-                    // (on second thought, you're not supposed to do this since this defeats the point of a JWT)
-
-                    // Pass the capability as data that we can use in the guard
-                    let graphql_request = graphql_request.into_inner().data(claim_data.claims.cap);
-                    return schema.execute(graphql_request).await.into();
-                }
-                Err(e) => {
-                    return err_msg_response(&format!("There was an error with your token: {}", e))
-                }
-            }
+    match decode_token(&request) {
+        Ok(claims) => {
+            debug!("{:#?} details", claims);
+
+            // TODO x-verify the claim data with the database.
+            // If the data doesn't match with the data in the db,
+            // **notify everything and everyone immediately, since someone stole the signing key**
+            // (on second thought, you're not supposed to do this since this defeats the point of a JWT)
+
+            // Pass the capability as data that we can use in the guard, and the rest of the
+            // claims so resolvers know which token (and therefore which device) is calling
+            let graphql_request = graphql_request.into_inner().data(claims.cap).data(claims);
+            schema.execute(graphql_request).await.into()
+        }
+        // Without a token, the guards keep everything out of reach except logging in
+        Err(_) if request.headers().get("Token").is_none() => {
+            schema.execute(graphql_request.into_inner()).await.into()
         }
+        Err(e) => err_msg_response(&e),
+    }
+}
+
+// Reads and checks the JWT in the Token header. Shared by /graphql and the export endpoints.
+fn decode_token(request: &HttpRequest) -> Result<tables::JWTClaims, String> {
+    match request.headers().get("Token").map(|token| token.to_str()) {
+        Some(Ok(token_str)) => verify_token(token_str),
+        _ => Err("A valid token is missing. Please provide one in the HTTP header.".to_string()),
+    }
+}
+
+fn verify_token(token_str: &str) -> Result<tables::JWTClaims, String> {
+    // Fetch token from SQL and check if it's valid
+    // TODO potential inefficiency here since it has to do this every time
+    let public_key_read = PUBLIC_KEY.read().unwrap();
+    let public_key_as_bytes = public_key_read.as_ref();
+    let decoding_key = DecodingKey::from_ec_pem(public_key_as_bytes).map_err(|e| {
+        format!(
+            "Expected a valid public key. Please check your server configuration. Error: {}",
+            e
+        )
+    })?;
+
+    match decode::<tables::JWTClaims>(token_str, &decoding_key, &Validation::new(Algorithm::ES256))
+    {
+        Ok(claim_data) => Ok(claim_data.claims),
+        Err(e) => Err(format!("There was an error with your token: {}", e)),
     }
+}
+
+// Subscriptions over graphql-ws. Browsers can't set headers on a WebSocket, so the token can
+// also be sent in the connection_init payload as {"Token": "..."}.
+async fn graphql_subscription(
+    schema: web::Data<
+        Schema<graphql_schema::Query, graphql_schema::Mutation, graphql_schema::Subscription>,
+    >,
+    request: HttpRequest,
+    payload: web::Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    let header_token = request
+        .headers()
+        .get("Token")
+        .and_then(|token| token.to_str().ok())
+        .map(String::from);
+
+    WSSubscription::start_with_initializer(
+        Schema::clone(&*schema),
+        &request,
+        payload,
+        |connection_params| async move {
+            let token_str = header_token
+                .or_else(|| {
+                    connection_params
+                        .get("Token")
+                        .and_then(|token| token.as_str())
+                        .map(String::from)
+                })
+                .ok_or_else(|| {
+                    async_graphql::Error::new(
+                        "A valid token is missing. Please provide one in the connection_init payload.",
+                    )
+                })?;
+            let claims = verify_token(&token_str).map_err(async_graphql::Error::new)?;
 
-    err_msg_response("A valid token is missing. Please provide one in the HTTP header.")
+            // Same data graphql_request passes, so the guards work the same way
+            let mut data = async_graphql::Data::default();
+            data.insert(claims.cap);
+            data.insert(claims);
+            Ok(data)
+        },
+    )
 }
 
 async fn check_first_run(pool: &PgPool) -> Result<bool, String> {
@@ -136,7 +208,7 @@ async fn check_first_run(pool: &PgPool) -> Result<bool, String> {
     };
     debug!("Checking number of tokens: {}", number_of_tokens);
 
-    Ok(if number_of_tokens == 0 { true } else { false })
+    Ok(number_of_tokens == 0)
 }
 
 fn error_exit(error_message: &str) -> ! {
@@ -155,6 +227,30 @@ async fn main() -> Result<(), std::io::Error> {
 
     let http_host_str = dotenv::var("AR_PG_HTTP_HOST_STR").unwrap_or("127.0.0.1:8080".to_string());
 
+    if let Ok(phone_region) = dotenv::var("AR_PG_DEFAULT_PHONE_REGION") {
+        *DEFAULT_PHONE_REGION.write().unwrap() = match phone_region.to_uppercase().parse() {
+            Ok(phone_region) => phone_region,
+            Err(_) => error_exit(&format!(
+                "AR_PG_DEFAULT_PHONE_REGION must be a two-letter country code like US or CA, not {}",
+                phone_region
+            )),
+        };
+    }
+    info!(
+        "Default phone region is {:?}",
+        DEFAULT_PHONE_REGION.read().unwrap()
+    );
+
+    if let Ok(threshold_hours) = dotenv::var("AR_PG_WEEKLY_SUMMARY_THRESHOLD_HOURS") {
+        *WEEKLY_SUMMARY_THRESHOLD_HOURS.write().unwrap() = match threshold_hours.parse() {
+            Ok(threshold_hours) if threshold_hours > 0.0 => threshold_hours,
+            _ => error_exit(&format!(
+                "AR_PG_WEEKLY_SUMMARY_THRESHOLD_HOURS must be a positive number of hours, not {}",
+                threshold_hours
+            )),
+        };
+    }
+
     let pool = Arc::new(match PgPoolOptions::new()
         .max_connections(5)
         .connect(&pg_connection_str)
@@ -169,8 +265,45 @@ Please check your connection string and try again.\nError details (check the SQL
         }
     });
 
+    // `attendance-rs import-users FILE [--apply]` imports users without starting the server
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("import-users") {
+        if let Err(e) = import::run_cli(&pool, &args[2..]).await {
+            error_exit(&e);
+        }
+        return Ok(());
+    }
+    // `attendance-rs normalize-phone-numbers [--apply]` converts numbers saved by older versions
+    if args.get(1).map(String::as_str) == Some("normalize-phone-numbers") {
+        if let Err(e) = tables::normalize_stored_phone_numbers(&pool, &args[2..]).await {
+            error_exit(&e);
+        }
+        return Ok(());
+    }
+
+    actix_web::rt::spawn(events::listen_for_events(
+        pg_connection_str.clone(),
+        Arc::clone(&pool),
+    ));
+
+    actix_web::rt::spawn(webhooks::deliver_webhooks(Arc::clone(&pool)));
+
+    match notifications::Mailer::from_env() {
+        Ok(Some(mailer)) => {
+            *EMAIL_ENABLED.write().unwrap() = true;
+            actix_web::rt::spawn(notifications::send_notifications(
+                Arc::clone(&pool),
+                Arc::new(mailer),
+            ));
+        }
+        Ok(None) => info!("AR_PG_SMTP_URL is not set, so no e-mail will be sent"),
+        Err(e) => error_exit(&e),
+    }
+
+    actix_web::rt::spawn(jobs::run_jobs(Arc::clone(&pool)));
+
     // If there are zero tokens in the database, we will remove authentication so someone can create a token (and then immediately turn off "first run" mode)
-    *FIRST_RUN.write().unwrap() = match check_first_run(&*pool).await {
+    *FIRST_RUN.write().unwrap() = match check_first_run(&pool).await {
         Ok(should_first_run) => should_first_run,
         Err(e) => error_exit(&e),
     };
@@ -184,7 +317,7 @@ Please check your connection string and try again.\nError details (check the SQL
     let schema = Schema::build(
         graphql_schema::Query,
         graphql_schema::Mutation,
-        EmptySubscription,
+        graphql_schema::Subscription,
     )
     .data(Arc::clone(&pool))
     .finish();
@@ -209,11 +342,57 @@ Please check your connection string and try again.\nError details (check the SQL
             .data(schema.clone())
             .wrap(cors)
             .wrap(Logger::default())
+            .service(
+                web::resource("/graphql")
+                    .guard(guard::Get())
+                    .guard(guard::Header("upgrade", "websocket"))
+                    .to(graphql_subscription),
+            )
             .service(
                 web::resource("/graphql")
                     .guard(guard::Post())
                     .to(graphql_request),
             )
+            .service(
+                web::resource("/export/users")
+                    .guard(guard::Get())
+                    .to(export::export_users),
+            )
+            .service(
+                web::resource("/export/attendance")
+                    .guard(guard::Get())
+                    .to(export::export_attendance),
+            )
+            .service(
+                web::resource("/export/summary")
+                    .guard(guard::Get())
+                    .to(export::export_summary),
+            )
+            .service(
+                web::resource("/badges")
+                    .guard(guard::Get())
+                    .to(badges::badge_sheet),
+            )
+            .service(
+                web::resource("/badges/{uuid}")
+                    .guard(guard::Get())
+                    .to(badges::badge),
+            )
+            .service(
+                web::resource("/checkin")
+                    .guard(guard::Get())
+                    .to(checkin::checkin_display),
+            )
+            .service(
+                web::resource("/calendar.ics")
+                    .guard(guard::Get())
+                    .to(calendar::calendar_feed),
+            )
+            .service(
+                web::resource("/notifications/unsubscribe")
+                    .route(web::get().to(notifications::unsubscribe_page))
+                    .route(web::post().to(notifications::unsubscribe)),
+            )
             .service(
                 web::resource("/")
                     .guard(guard::Get())
@@ -1,17 +1,18 @@
 use async_graphql::{guard::Guard, validators::InputValueValidator, *};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use sqlx::{
     postgres::PgPool,
     types::{
-        chrono::{DateTime, Utc},
+        chrono::{DateTime, NaiveDate, Utc},
         Uuid,
     },
 };
 use std::{collections::HashMap, sync::Arc};
 
-use crate::FIRST_RUN;
+use crate::{DEFAULT_PHONE_REGION, FIRST_RUN};
 
-static ACCESS_DENIED_MESSAGE: &str = "You are not allowed to access this resource";
+pub(crate) static ACCESS_DENIED_MESSAGE: &str = "You are not allowed to access this resource";
 
 #[derive(Debug, SimpleObject)]
 #[graphql(complex)]
@@ -26,14 +27,32 @@ pub struct User {
     #[graphql(skip)]
     // This is *supposed* to be a serde_json::Value::Object
     pub alt_id_fields: Option<serde_json::Value>,
+    pub groups: Vec<String>,
+    // Archived users (like members who graduated) are hidden from lookups and can't sign
+    // in, but their attendance history stays around for reports
+    pub archive_time: Option<DateTime<Utc>>,
+    pub archive_reason: Option<String>,
+    // Goes up by one every time the user changes. Pass it back as expectedVersion to
+    // update_user so edits from two kiosks can't silently overwrite each other.
+    pub version: i32,
 }
 
 #[ComplexObject]
 impl User {
     async fn uuid(&self) -> String {
-        let hyphenated = self.uuid.to_hyphenated().clone();
+        let hyphenated = self.uuid.to_hyphenated();
         hyphenated.to_string()
     }
+    async fn active(&self) -> bool {
+        self.archive_time.is_none()
+    }
+    // phoneNumber is stored in E.164, these are the forms people actually read
+    async fn phone_number_national(&self) -> Option<String> {
+        format_phone_number(&self.phone_number, phonenumber::Mode::National)
+    }
+    async fn phone_number_international(&self) -> Option<String> {
+        format_phone_number(&self.phone_number, phonenumber::Mode::International)
+    }
     async fn alt_id_fields(&self) -> Result<Option<HashMap<String, String>>> {
         // Again, the ? operator doesn't work in closures, annoyingly.
         if let Some(unwrapped_alt_id_fields) = &self.alt_id_fields {
@@ -55,9 +74,212 @@ impl User {
         .fetch_all(&**pool)
         .await?)
     }
+    // Guardian emails are personal data, so kiosks and collectors don't get to see them
+    #[graphql(guard(CapabilityGuard(capability = "TokenCapability::Viewer")))]
+    async fn notification_preferences(&self, ctx: &Context<'_>) -> Result<NotificationPreferences> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        Ok(sqlx::query_as!(
+            NotificationPreferences,
+            "SELECT sign_out_notices, guardian_emails, weekly_summary, summary_groups FROM notification_preferences WHERE user_uuid=$1",
+            self.uuid
+        )
+        .fetch_optional(&**pool)
+        .await?
+        .unwrap_or_default())
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct UserSearchResult {
+    pub user: User,
+    // Between 0 and 1, where 1 means the search matched a whole word exactly
+    pub score: f32,
+}
+
+#[derive(InputObject)]
+pub struct AltIdFilter {
+    pub field: String,
+    pub value: String,
+}
+
+// Every filter that is set has to match
+#[derive(InputObject)]
+pub struct UserFilter {
+    pub uuid: Option<String>,
+    // Case-insensitive, matches any part of the full name
+    pub name: Option<String>,
+    // Case-insensitive, matches the whole e-mail
+    pub email: Option<String>,
+    pub phone_number: Option<String>,
+    pub alt_id: Option<AltIdFilter>,
+    pub group: Option<String>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    // Only active users unless asked otherwise. Set to null to get everyone.
+    #[graphql(default_with = "Some(true)")]
+    pub active: Option<bool>,
+}
+
+impl Default for UserFilter {
+    fn default() -> Self {
+        UserFilter {
+            uuid: None,
+            name: None,
+            email: None,
+            phone_number: None,
+            alt_id: None,
+            group: None,
+            created_after: None,
+            created_before: None,
+            active: Some(true),
+        }
+    }
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq)]
+pub enum UserOrderField {
+    FullName,
+    Email,
+    CreateTime,
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq)]
+pub enum OrderDirection {
+    Asc,
+    Desc,
 }
 
+#[derive(InputObject)]
+pub struct UserOrderBy {
+    pub field: UserOrderField,
+    #[graphql(default_with = "OrderDirection::Asc")]
+    pub direction: OrderDirection,
+}
+
+// Extra fields that go on every connection we return
 #[derive(SimpleObject)]
+pub struct ConnectionFields {
+    pub total_count: i64,
+}
+
+// Where a page of users left off: the value they're sorted by, and the UUID for ties. Unlike an
+// offset, it still points at the right place when users are added or removed in between.
+pub struct UserCursor {
+    pub key: String,
+    pub uuid: Uuid,
+}
+
+impl connection::CursorType for UserCursor {
+    type Error = String;
+
+    fn decode_cursor(s: &str) -> Result<Self, Self::Error> {
+        let invalid = || "Invalid cursor".to_string();
+        let decoded =
+            String::from_utf8(hex::decode(s).map_err(|_| invalid())?).map_err(|_| invalid())?;
+        let (uuid, key) = decoded.split_once(' ').ok_or_else(invalid)?;
+        Ok(UserCursor {
+            key: key.to_string(),
+            uuid: Uuid::parse_str(uuid).map_err(|_| invalid())?,
+        })
+    }
+
+    fn encode_cursor(&self) -> String {
+        hex::encode(format!("{} {}", self.uuid.to_hyphenated(), self.key))
+    }
+}
+
+// Where a page of possible duplicates left off, in the order they're ranked
+pub struct DuplicateCursor {
+    pub same_email: bool,
+    pub same_phone_number: bool,
+    pub name_similarity: f32,
+    pub first_uuid: Uuid,
+    pub second_uuid: Uuid,
+}
+
+impl DuplicateCursor {
+    pub fn new(candidate: &DuplicateCandidate) -> DuplicateCursor {
+        DuplicateCursor {
+            same_email: candidate.same_email,
+            same_phone_number: candidate.same_phone_number,
+            name_similarity: candidate.name_similarity,
+            first_uuid: candidate.first_uuid,
+            second_uuid: candidate.second_uuid,
+        }
+    }
+}
+
+impl connection::CursorType for DuplicateCursor {
+    type Error = String;
+
+    fn decode_cursor(s: &str) -> Result<Self, Self::Error> {
+        let invalid = || "Invalid cursor".to_string();
+        let decoded =
+            String::from_utf8(hex::decode(s).map_err(|_| invalid())?).map_err(|_| invalid())?;
+        let parts: Vec<&str> = decoded.split(' ').collect();
+        if parts.len() != 5 {
+            return Err(invalid());
+        }
+        Ok(DuplicateCursor {
+            same_email: parts[0].parse().map_err(|_| invalid())?,
+            same_phone_number: parts[1].parse().map_err(|_| invalid())?,
+            name_similarity: parts[2].parse().map_err(|_| invalid())?,
+            first_uuid: Uuid::parse_str(parts[3]).map_err(|_| invalid())?,
+            second_uuid: Uuid::parse_str(parts[4]).map_err(|_| invalid())?,
+        })
+    }
+
+    // f32's Display gives back exactly the same number when parsed, so ties still compare equal
+    fn encode_cursor(&self) -> String {
+        hex::encode(format!(
+            "{} {} {} {} {}",
+            self.same_email,
+            self.same_phone_number,
+            self.name_similarity,
+            self.first_uuid.to_hyphenated(),
+            self.second_uuid.to_hyphenated()
+        ))
+    }
+}
+
+// Where a page of attendance left off: the time it's sorted by (no out time for open
+// sessions) and the ID for ties
+pub struct AttendanceCursor {
+    pub time: Option<DateTime<Utc>>,
+    pub id: i32,
+}
+
+impl connection::CursorType for AttendanceCursor {
+    type Error = String;
+
+    fn decode_cursor(s: &str) -> Result<Self, Self::Error> {
+        let invalid = || "Invalid cursor".to_string();
+        let decoded =
+            String::from_utf8(hex::decode(s).map_err(|_| invalid())?).map_err(|_| invalid())?;
+        let (id, time) = decoded.split_once(' ').ok_or_else(invalid)?;
+        Ok(AttendanceCursor {
+            time: match time {
+                "" => None,
+                time => Some(
+                    DateTime::parse_from_rfc3339(time)
+                        .map_err(|_| invalid())?
+                        .with_timezone(&Utc),
+                ),
+            },
+            id: id.parse().map_err(|_| invalid())?,
+        })
+    }
+
+    fn encode_cursor(&self) -> String {
+        let time = self
+            .time
+            .map(|time| time.to_rfc3339_opts(chrono::SecondsFormat::Micros, true))
+            .unwrap_or_default();
+        hex::encode(format!("{} {}", self.id, time))
+    }
+}
+
+#[derive(SimpleObject, Clone)]
 #[graphql(complex)]
 pub struct Attendance {
     pub id: i32,
@@ -65,13 +287,853 @@ pub struct Attendance {
     pub user_uuid: Uuid,
     pub in_time: DateTime<Utc>,
     pub out_time: Option<DateTime<Utc>>,
+    pub location: Option<String>,
+    pub event: Option<String>,
+    #[graphql(skip)]
+    pub device_token_uuid: Option<Uuid>,
+    // Nobody signed out, so the close_open_sessions job closed it with no time counted
+    pub auto_closed: bool,
+    // Filled in from scans made before the card was enrolled
+    pub backfilled: bool,
 }
 #[ComplexObject]
 impl Attendance {
     async fn user_uuid(&self) -> String {
-        let hyphenated = self.user_uuid.to_hyphenated().clone();
+        let hyphenated = self.user_uuid.to_hyphenated();
         hyphenated.to_string()
     }
+    async fn device_token_uuid(&self) -> Option<String> {
+        self.device_token_uuid
+            .map(|uuid| uuid.to_hyphenated().to_string())
+    }
+    async fn device_description(&self, ctx: &Context<'_>) -> Result<Option<String>> {
+        device_description(ctx, self.device_token_uuid).await
+    }
+    async fn user(&self, ctx: &Context<'_>) -> Result<Option<User>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        Ok(
+            sqlx::query_as!(User, "SELECT * FROM users WHERE uuid=$1", self.user_uuid)
+                .fetch_optional(&**pool)
+                .await?,
+        )
+    }
+}
+
+// Every filter that is set has to match. Times match against in_time.
+#[derive(InputObject, Default)]
+pub struct AttendanceFilter {
+    pub user_uuids: Option<Vec<String>>,
+    pub start_time: Option<DateTime<Utc>>,
+    pub end_time: Option<DateTime<Utc>>,
+    pub location: Option<String>,
+    pub event: Option<String>,
+    pub device_token_uuid: Option<String>,
+    // true for people who haven't signed out yet, false for finished entries
+    pub open: Option<bool>,
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq)]
+pub enum AttendanceOrderField {
+    InTime,
+    OutTime,
+}
+
+#[derive(InputObject)]
+pub struct AttendanceOrderBy {
+    pub field: AttendanceOrderField,
+    #[graphql(default_with = "OrderDirection::Asc")]
+    pub direction: OrderDirection,
+}
+
+// One slice of the occupancy time series
+#[derive(SimpleObject)]
+pub struct OccupancyBucket {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    // How many people were in when the bucket started
+    pub at_start: i64,
+    // The most people in at once during the bucket
+    pub peak: i64,
+    pub arrivals: i64,
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum AttendanceEventKind {
+    SignIn,
+    SignOut,
+}
+
+// Sent to attendanceEvents subscribers when someone signs in or out
+#[derive(SimpleObject, Clone)]
+#[graphql(complex)]
+pub struct AttendanceEvent {
+    pub kind: AttendanceEventKind,
+    pub attendance: Attendance,
+    // The user's groups when it happened, so subscriptions can filter on them
+    #[graphql(skip)]
+    pub groups: Vec<String>,
+}
+#[ComplexObject]
+impl AttendanceEvent {
+    async fn user(&self, ctx: &Context<'_>) -> Result<Option<User>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        Ok(sqlx::query_as!(
+            User,
+            "SELECT * FROM users WHERE uuid=$1",
+            self.attendance.user_uuid
+        )
+        .fetch_optional(&**pool)
+        .await?)
+    }
+}
+
+// Everyone who was signed in when an evacuation started, to be checked off at the assembly point
+#[derive(SimpleObject)]
+#[graphql(complex)]
+pub struct RollCall {
+    pub id: i32,
+    // Only people signed in here were included. None means everywhere.
+    pub location: Option<String>,
+    #[graphql(skip)]
+    pub started_by_token_uuid: Option<Uuid>,
+    pub start_time: DateTime<Utc>,
+    pub end_time: Option<DateTime<Utc>>,
+}
+#[ComplexObject]
+impl RollCall {
+    async fn started_by(&self, ctx: &Context<'_>) -> Result<Option<String>> {
+        device_description(ctx, self.started_by_token_uuid).await
+    }
+    async fn entries(&self, ctx: &Context<'_>) -> Result<Vec<RollCallEntry>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        Ok(sqlx::query_as!(
+            RollCallEntry,
+            "SELECT * FROM roll_call_entries WHERE roll_call_id=$1 ORDER BY in_time",
+            self.id
+        )
+        .fetch_all(&**pool)
+        .await?)
+    }
+    // Who still hasn't been checked off
+    async fn missing(&self, ctx: &Context<'_>) -> Result<Vec<RollCallEntry>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        Ok(sqlx::query_as!(
+            RollCallEntry,
+            "SELECT * FROM roll_call_entries WHERE roll_call_id=$1 AND accounted_time IS NULL ORDER BY in_time",
+            self.id
+        )
+        .fetch_all(&**pool)
+        .await?)
+    }
+    async fn missing_count(&self, ctx: &Context<'_>) -> Result<i64> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        Ok(sqlx::query!(
+            r#"SELECT COUNT(*) AS "count!" FROM roll_call_entries WHERE roll_call_id=$1 AND accounted_time IS NULL"#,
+            self.id
+        )
+        .fetch_one(&**pool)
+        .await?
+        .count)
+    }
+    async fn total_count(&self, ctx: &Context<'_>) -> Result<i64> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        Ok(sqlx::query!(
+            r#"SELECT COUNT(*) AS "count!" FROM roll_call_entries WHERE roll_call_id=$1"#,
+            self.id
+        )
+        .fetch_one(&**pool)
+        .await?
+        .count)
+    }
+}
+
+#[derive(SimpleObject, Clone)]
+#[graphql(complex)]
+pub struct RollCallEntry {
+    pub roll_call_id: i32,
+    #[graphql(skip)]
+    pub user_uuid: Uuid,
+    // Where and when they signed in, as of the start of the roll call
+    pub in_time: DateTime<Utc>,
+    pub location: Option<String>,
+    pub accounted_time: Option<DateTime<Utc>>,
+    #[graphql(skip)]
+    pub accounted_by_token_uuid: Option<Uuid>,
+}
+#[ComplexObject]
+impl RollCallEntry {
+    async fn user_uuid(&self) -> String {
+        self.user_uuid.to_hyphenated().to_string()
+    }
+    async fn user(&self, ctx: &Context<'_>) -> Result<Option<User>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        Ok(
+            sqlx::query_as!(User, "SELECT * FROM users WHERE uuid=$1", self.user_uuid)
+                .fetch_optional(&**pool)
+                .await?,
+        )
+    }
+    async fn accounted(&self) -> bool {
+        self.accounted_time.is_some()
+    }
+    async fn accounted_by(&self, ctx: &Context<'_>) -> Result<Option<String>> {
+        device_description(ctx, self.accounted_by_token_uuid).await
+    }
+}
+
+// A card scan from a kiosk that didn't match any user, waiting to be enrolled
+#[derive(SimpleObject)]
+#[graphql(complex)]
+pub struct PendingIdentifier {
+    pub id: i32,
+    pub alt_id_field: String,
+    pub alt_id_value: String,
+    #[graphql(skip)]
+    pub device_token_uuid: Option<Uuid>,
+    pub scan_time: DateTime<Utc>,
+    #[graphql(skip)]
+    pub claimed_user_uuid: Option<Uuid>,
+    pub claim_time: Option<DateTime<Utc>>,
+    pub location: Option<String>,
+    pub event: Option<String>,
+}
+#[ComplexObject]
+impl PendingIdentifier {
+    async fn device_token_uuid(&self) -> Option<String> {
+        self.device_token_uuid
+            .map(|uuid| uuid.to_hyphenated().to_string())
+    }
+    async fn device_description(&self, ctx: &Context<'_>) -> Result<Option<String>> {
+        device_description(ctx, self.device_token_uuid).await
+    }
+    async fn claimed_user_uuid(&self) -> Option<String> {
+        self.claimed_user_uuid
+            .map(|uuid| uuid.to_hyphenated().to_string())
+    }
+}
+
+// The token description is how we tell kiosks apart
+async fn device_description(
+    ctx: &Context<'_>,
+    device_token_uuid: Option<Uuid>,
+) -> Result<Option<String>> {
+    let pool = ctx.data::<Arc<PgPool>>()?;
+    Ok(sqlx::query!(
+        "SELECT description FROM tokens WHERE uuid=$1",
+        device_token_uuid
+    )
+    .fetch_optional(&**pool)
+    .await?
+    .map(|record| record.description))
+}
+
+// Who did what to which user. Details should never hold personal data, since entries
+// stick around after a user is erased.
+#[derive(SimpleObject)]
+#[graphql(complex)]
+pub struct AuditEntry {
+    pub id: i32,
+    pub action: String,
+    #[graphql(skip)]
+    pub actor_token_uuid: Option<Uuid>,
+    #[graphql(skip)]
+    pub subject_user_uuid: Option<Uuid>,
+    pub details: Option<serde_json::Value>,
+    pub create_time: DateTime<Utc>,
+}
+#[ComplexObject]
+impl AuditEntry {
+    async fn actor_token_uuid(&self) -> Option<String> {
+        self.actor_token_uuid
+            .map(|uuid| uuid.to_hyphenated().to_string())
+    }
+    async fn actor_description(&self, ctx: &Context<'_>) -> Result<Option<String>> {
+        device_description(ctx, self.actor_token_uuid).await
+    }
+    async fn subject_user_uuid(&self) -> Option<String> {
+        self.subject_user_uuid
+            .map(|uuid| uuid.to_hyphenated().to_string())
+    }
+}
+
+// An alt ID field both users had with different values. The kept user's value wins.
+#[derive(SimpleObject)]
+pub struct AltIdConflict {
+    pub field: String,
+    pub kept_value: String,
+    pub discarded_value: String,
+}
+
+#[derive(SimpleObject)]
+pub struct MergeResult {
+    pub user: User,
+    pub attendance_moved: i64,
+    pub conflicts: Vec<AltIdConflict>,
+}
+
+// Two users that might be the same person, and why we think so
+#[derive(SimpleObject)]
+#[graphql(complex)]
+pub struct DuplicateCandidate {
+    #[graphql(skip)]
+    pub first_uuid: Uuid,
+    #[graphql(skip)]
+    pub second_uuid: Uuid,
+    pub same_email: bool,
+    pub same_phone_number: bool,
+    pub name_similarity: f32,
+}
+#[ComplexObject]
+impl DuplicateCandidate {
+    async fn first(&self, ctx: &Context<'_>) -> Result<User> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        Ok(
+            sqlx::query_as!(User, "SELECT * FROM users WHERE uuid=$1", self.first_uuid)
+                .fetch_one(&**pool)
+                .await?,
+        )
+    }
+    async fn second(&self, ctx: &Context<'_>) -> Result<User> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        Ok(
+            sqlx::query_as!(User, "SELECT * FROM users WHERE uuid=$1", self.second_uuid)
+                .fetch_one(&**pool)
+                .await?,
+        )
+    }
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq, Debug)]
+pub enum EraseMode {
+    // Removes the user and every attendance entry they have
+    Delete,
+    // Strips personal fields but keeps the user (archived) and their attendance for statistics
+    Anonymize,
+}
+
+#[derive(sqlx::Type, Enum, Copy, Clone, Eq, PartialEq, Debug)]
+#[sqlx(type_name = "alt_id_format", rename_all = "lowercase")]
+pub enum AltIdFormat {
+    Any,
+    Numeric,
+    Hexadecimal,
+    Alphanumeric,
+}
+
+#[derive(sqlx::Type, Enum, Copy, Clone, Eq, PartialEq, Debug)]
+#[sqlx(type_name = "alt_id_case", rename_all = "lowercase")]
+pub enum AltIdCase {
+    Preserve,
+    Lower,
+    Upper,
+}
+
+// An entry in the registry of alt ID field names that kiosks are allowed to use
+#[derive(Debug, SimpleObject)]
+pub struct AltIdType {
+    pub name: String,
+    pub description: String,
+    pub format: AltIdFormat,
+    pub pattern: Option<String>,
+    pub case_normalization: AltIdCase,
+    pub is_unique: bool,
+    pub create_time: DateTime<Utc>,
+}
+
+impl AltIdType {
+    // Trims and case-normalizes the value, then checks it against the format and pattern
+    pub fn normalize(&self, value: &str) -> Result<String, String> {
+        let value = self.normalize_case(value);
+
+        if value.is_empty() {
+            return Err(format!(
+                "A value for alt ID field '{}' cannot be empty",
+                self.name
+            ));
+        }
+
+        let format_matches = match self.format {
+            AltIdFormat::Any => true,
+            AltIdFormat::Numeric => value.chars().all(|c| c.is_ascii_digit()),
+            AltIdFormat::Hexadecimal => value.chars().all(|c| c.is_ascii_hexdigit()),
+            AltIdFormat::Alphanumeric => value.chars().all(|c| c.is_ascii_alphanumeric()),
+        };
+        if !format_matches {
+            return Err(format!(
+                "The value for alt ID field '{}' is not in the {:?} format",
+                self.name, self.format
+            ));
+        }
+
+        if let Some(pattern) = &self.pattern {
+            let regex = anchored_regex(pattern)?;
+            if !regex.is_match(&value) {
+                return Err(format!(
+                    "The value for alt ID field '{}' does not match the pattern {}",
+                    self.name, pattern
+                ));
+            }
+        }
+
+        Ok(value)
+    }
+
+    // Just the trimming and case, for values that are already stored
+    pub fn normalize_case(&self, value: &str) -> String {
+        match self.case_normalization {
+            AltIdCase::Preserve => value.trim().to_string(),
+            AltIdCase::Lower => value.trim().to_lowercase(),
+            AltIdCase::Upper => value.trim().to_uppercase(),
+        }
+    }
+}
+
+// Patterns have to match the whole value, not just part of it
+pub fn anchored_regex(pattern: &str) -> Result<Regex, String> {
+    Regex::new(&format!("^(?:{})$", pattern))
+        .map_err(|e| format!("Invalid alt ID pattern {}: {}", pattern, e))
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq, Debug)]
+pub enum WebhookEventType {
+    AttendanceCreated,
+    // Someone signed out
+    AttendanceClosed,
+    UserCreated,
+    UserUpdated,
+    UserArchived,
+}
+
+impl WebhookEventType {
+    pub const ALL: [WebhookEventType; 5] = [
+        WebhookEventType::AttendanceCreated,
+        WebhookEventType::AttendanceClosed,
+        WebhookEventType::UserCreated,
+        WebhookEventType::UserUpdated,
+        WebhookEventType::UserArchived,
+    ];
+
+    // What's stored in the database and sent as `event` in the payload
+    pub fn name(self) -> &'static str {
+        match self {
+            WebhookEventType::AttendanceCreated => "attendance.created",
+            WebhookEventType::AttendanceClosed => "attendance.closed",
+            WebhookEventType::UserCreated => "user.created",
+            WebhookEventType::UserUpdated => "user.updated",
+            WebhookEventType::UserArchived => "user.archived",
+        }
+    }
+}
+
+// Somewhere we POST events to. The secret is write-only.
+#[derive(SimpleObject)]
+#[graphql(complex)]
+pub struct Webhook {
+    pub id: i32,
+    pub url: String,
+    pub description: String,
+    #[graphql(skip)]
+    pub event_types: Vec<String>,
+    #[graphql(skip)]
+    pub secret: String,
+    pub active: bool,
+    pub create_time: DateTime<Utc>,
+}
+#[ComplexObject]
+impl Webhook {
+    async fn event_types(&self) -> Vec<WebhookEventType> {
+        WebhookEventType::ALL
+            .iter()
+            .copied()
+            .filter(|event_type| {
+                self.event_types
+                    .iter()
+                    .any(|name| name == event_type.name())
+            })
+            .collect()
+    }
+}
+
+#[derive(sqlx::Type, Enum, Copy, Clone, Eq, PartialEq, Debug)]
+#[sqlx(type_name = "webhook_delivery_status", rename_all = "lowercase")]
+pub enum WebhookDeliveryStatus {
+    Pending,
+    Delivered,
+    // Ran out of retries
+    Failed,
+}
+
+#[derive(SimpleObject)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub webhook_id: i32,
+    pub event_type: String,
+    pub payload: serde_json::Value,
+    pub status: WebhookDeliveryStatus,
+    pub attempts: i32,
+    pub next_attempt_time: DateTime<Utc>,
+    pub last_attempt_time: Option<DateTime<Utc>>,
+    pub last_response_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub delivered_time: Option<DateTime<Utc>>,
+    pub create_time: DateTime<Utc>,
+}
+
+#[derive(sqlx::Type, Enum, Copy, Clone, Eq, PartialEq, Debug)]
+#[sqlx(type_name = "notification_kind", rename_all = "snake_case")]
+pub enum NotificationKind {
+    // Goes to guardians (and the member, if they want) when a member signs out
+    SignOut,
+    // Goes to mentors on Sundays
+    WeeklySummary,
+}
+
+impl NotificationKind {
+    // What a template for this kind can use, as {{name}}
+    pub fn placeholders(self) -> &'static [&'static str] {
+        match self {
+            NotificationKind::SignOut => &[
+                "full_name",
+                "location",
+                "in_time",
+                "out_time",
+                "hours",
+                "unsubscribe_url",
+            ],
+            NotificationKind::WeeklySummary => &[
+                "recipient_name",
+                "threshold_hours",
+                "period_start",
+                "period_end",
+                "members",
+                "member_count",
+                "unsubscribe_url",
+            ],
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+#[graphql(complex)]
+pub struct NotificationTemplate {
+    pub kind: NotificationKind,
+    pub subject: String,
+    pub body: String,
+    pub update_time: Option<DateTime<Utc>>,
+}
+#[ComplexObject]
+impl NotificationTemplate {
+    async fn placeholders(&self) -> Vec<&'static str> {
+        self.kind.placeholders().to_vec()
+    }
+}
+
+// Users without a notification_preferences row get the default, which is nothing
+#[derive(SimpleObject, Default)]
+pub struct NotificationPreferences {
+    pub sign_out_notices: bool,
+    pub guardian_emails: Vec<String>,
+    pub weekly_summary: bool,
+    pub summary_groups: Vec<String>,
+}
+
+#[derive(sqlx::Type, Enum, Copy, Clone, Eq, PartialEq, Debug)]
+#[sqlx(type_name = "notification_status", rename_all = "lowercase")]
+pub enum NotificationStatus {
+    Pending,
+    Sent,
+    // Ran out of retries, or sat in the queue too long to still be worth sending
+    Failed,
+}
+
+#[derive(SimpleObject)]
+pub struct Notification {
+    pub id: i64,
+    pub kind: NotificationKind,
+    pub recipient: String,
+    pub data: serde_json::Value,
+    pub subject: Option<String>,
+    pub body: Option<String>,
+    pub status: NotificationStatus,
+    pub attempts: i32,
+    pub next_attempt_time: DateTime<Utc>,
+    pub last_error: Option<String>,
+    pub sent_time: Option<DateTime<Utc>>,
+    pub create_time: DateTime<Utc>,
+}
+
+#[derive(SimpleObject, Clone)]
+#[graphql(complex)]
+pub struct Event {
+    pub id: i32,
+    pub uid: String,
+    #[graphql(skip)]
+    pub recurrence_id: String,
+    pub summary: String,
+    pub description: String,
+    pub location: Option<String>,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    pub all_day: bool,
+    // The time zone the calendar this came from gave its times in
+    pub time_zone: Option<String>,
+    // RRULE, RDATE and EXDATE lines from the calendar this came from
+    pub recurrence: Vec<String>,
+    pub cancelled: bool,
+    pub sequence: i32,
+    pub create_time: DateTime<Utc>,
+    pub update_time: Option<DateTime<Utc>>,
+}
+#[ComplexObject]
+impl Event {
+    // True for events that happen more than once. Only the first time is in startTime/endTime.
+    async fn recurring(&self) -> bool {
+        !self.recurrence.is_empty()
+    }
+    // Set for a changed occurrence of a recurring event, which shares that event's uid
+    async fn recurrence_id(&self) -> Option<&str> {
+        if self.recurrence_id.is_empty() {
+            None
+        } else {
+            Some(&self.recurrence_id)
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+#[graphql(complex)]
+pub struct CalendarFeedKey {
+    #[graphql(skip)]
+    pub key: Uuid,
+    pub description: String,
+    pub create_time: DateTime<Utc>,
+    pub last_access_time: Option<DateTime<Utc>>,
+}
+#[ComplexObject]
+impl CalendarFeedKey {
+    async fn key(&self) -> String {
+        self.key.to_hyphenated().to_string()
+    }
+    // Relative to wherever the server is, like /graphql
+    async fn path(&self) -> String {
+        format!("/calendar.ics?key={}", self.key.to_hyphenated())
+    }
+}
+
+#[derive(sqlx::Type, Enum, Copy, Clone, Eq, PartialEq, Debug)]
+#[sqlx(type_name = "excuse_status", rename_all = "lowercase")]
+pub enum ExcuseStatus {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+#[derive(SimpleObject)]
+#[graphql(complex)]
+pub struct Excuse {
+    pub id: i32,
+    #[graphql(skip)]
+    pub user_uuid: Uuid,
+    // The day they'll miss (or missed)
+    pub date: NaiveDate,
+    pub reason: String,
+    pub status: ExcuseStatus,
+    pub create_time: DateTime<Utc>,
+    pub review_time: Option<DateTime<Utc>>,
+    #[graphql(skip)]
+    pub reviewed_by_token_uuid: Option<Uuid>,
+    // Left by whoever approved or rejected it, for the member to read
+    pub review_note: Option<String>,
+}
+#[ComplexObject]
+impl Excuse {
+    async fn user_uuid(&self) -> String {
+        self.user_uuid.to_hyphenated().to_string()
+    }
+    // The description of the token that approved or rejected it
+    async fn reviewed_by(&self, ctx: &Context<'_>) -> Result<Option<String>> {
+        device_description(ctx, self.reviewed_by_token_uuid).await
+    }
+}
+
+// What a member token sees, all of it about the member it belongs to
+#[derive(SimpleObject)]
+#[graphql(complex)]
+pub struct Me {
+    #[graphql(skip)]
+    pub uuid: Uuid,
+    pub full_name: String,
+}
+#[ComplexObject]
+impl Me {
+    async fn uuid(&self) -> String {
+        self.uuid.to_hyphenated().to_string()
+    }
+    // Sessions that started between from and to (leave either out for no limit), newest first
+    async fn attendance(
+        &self,
+        ctx: &Context<'_>,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<Vec<Attendance>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        Ok(sqlx::query_as!(
+            Attendance,
+            "SELECT * FROM attendance WHERE user_uuid=$1
+            AND ($2::timestamptz IS NULL OR in_time >= $2) AND ($3::timestamptz IS NULL OR in_time < $3)
+            ORDER BY in_time DESC",
+            self.uuid,
+            from,
+            to
+        )
+        .fetch_all(&**pool)
+        .await?)
+    }
+    // Hours from sessions that started between from and to and have been signed out of
+    async fn total_hours(
+        &self,
+        ctx: &Context<'_>,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<f64> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        Ok(sqlx::query!(
+            r#"SELECT round(coalesce(sum(extract(epoch FROM out_time - in_time)), 0)::numeric / 3600, 2)::float8 AS "hours!"
+            FROM attendance WHERE user_uuid=$1 AND out_time IS NOT NULL
+            AND ($2::timestamptz IS NULL OR in_time >= $2) AND ($3::timestamptz IS NULL OR in_time < $3)"#,
+            self.uuid,
+            from,
+            to
+        )
+        .fetch_one(&**pool)
+        .await?
+        .hours)
+    }
+    // Newest first
+    async fn excuses(&self, ctx: &Context<'_>) -> Result<Vec<Excuse>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        Ok(sqlx::query_as!(
+            Excuse,
+            r#"SELECT id, user_uuid, date, reason, status as "status: ExcuseStatus", create_time,
+            review_time, reviewed_by_token_uuid, review_note
+            FROM excuses WHERE user_uuid=$1 ORDER BY date DESC, id DESC"#,
+            self.uuid
+        )
+        .fetch_all(&**pool)
+        .await?)
+    }
+}
+
+#[derive(SimpleObject)]
+#[graphql(complex)]
+pub struct CheckinCode {
+    pub id: i32,
+    pub description: String,
+    pub location: Option<String>,
+    pub event: Option<String>,
+    #[graphql(skip)]
+    pub secret: Vec<u8>,
+    // How often the code changes
+    pub period_seconds: i32,
+    pub digits: i32,
+    #[graphql(skip)]
+    pub display_key: Uuid,
+    pub create_time: DateTime<Utc>,
+}
+#[ComplexObject]
+impl CheckinCode {
+    // The page to put on the screen, relative to wherever the server is
+    async fn display_path(&self) -> String {
+        format!("/checkin?key={}", self.display_key.to_hyphenated())
+    }
+}
+
+#[derive(sqlx::Type, Enum, Copy, Clone, Eq, PartialEq, Debug)]
+#[sqlx(type_name = "job_kind", rename_all = "snake_case")]
+pub enum JobKind {
+    // Queues the weekly summary e-mails
+    WeeklySummary,
+    // Closes sessions that are too old to sign out of
+    CloseOpenSessions,
+    // Removes tokens that expired long ago and were never used for anything
+    ExpireTokens,
+    // Removes old webhook deliveries, notifications and job runs
+    PruneLogs,
+}
+
+#[derive(SimpleObject)]
+#[graphql(complex)]
+pub struct Job {
+    pub kind: JobKind,
+    pub schedule: String,
+    pub enabled: bool,
+    pub next_run_time: Option<DateTime<Utc>>,
+}
+#[ComplexObject]
+impl Job {
+    async fn last_run(&self, ctx: &Context<'_>) -> Result<Option<JobRun>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        Ok(sqlx::query_as!(
+            JobRun,
+            r#"SELECT id, kind as "kind: JobKind", triggered_by_token_uuid, status as "status: JobRunStatus",
+            result, start_time, end_time
+            FROM job_runs WHERE kind=$1 ORDER BY id DESC LIMIT 1"#,
+            self.kind as JobKind
+        )
+        .fetch_optional(&**pool)
+        .await?)
+    }
+    // Newest first
+    async fn runs(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 20)] limit: i64,
+    ) -> Result<Vec<JobRun>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        Ok(sqlx::query_as!(
+            JobRun,
+            r#"SELECT id, kind as "kind: JobKind", triggered_by_token_uuid, status as "status: JobRunStatus",
+            result, start_time, end_time
+            FROM job_runs WHERE kind=$1 ORDER BY id DESC LIMIT $2"#,
+            self.kind as JobKind,
+            limit.clamp(0, 100)
+        )
+        .fetch_all(&**pool)
+        .await?)
+    }
+}
+
+#[derive(sqlx::Type, Enum, Copy, Clone, Eq, PartialEq, Debug)]
+#[sqlx(type_name = "job_run_status", rename_all = "lowercase")]
+pub enum JobRunStatus {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(SimpleObject)]
+#[graphql(complex)]
+pub struct JobRun {
+    pub id: i64,
+    pub kind: JobKind,
+    #[graphql(skip)]
+    pub triggered_by_token_uuid: Option<Uuid>,
+    pub status: JobRunStatus,
+    pub result: Option<String>,
+    pub start_time: DateTime<Utc>,
+    pub end_time: Option<DateTime<Utc>>,
+}
+#[ComplexObject]
+impl JobRun {
+    // False for runs the scheduler started
+    async fn manual(&self) -> bool {
+        self.triggered_by_token_uuid.is_some()
+    }
+    async fn triggered_by_token_uuid(&self) -> Option<String> {
+        self.triggered_by_token_uuid
+            .map(|uuid| uuid.to_hyphenated().to_string())
+    }
 }
 
 #[derive(sqlx::Type, Enum, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, Debug)]
@@ -80,6 +1142,8 @@ pub enum TokenCapability {
     Collector,
     Viewer,
     Administrator,
+    // Belongs to one user, and can only see that user's own attendance and excuses
+    Member,
 }
 
 #[derive(SimpleObject)]
@@ -92,6 +1156,57 @@ pub struct Token {
     pub expiration_time: DateTime<Utc>,
     pub create_time: DateTime<Utc>,
     pub capability: TokenCapability,
+    #[graphql(skip)]
+    pub user_uuid: Option<Uuid>,
+    #[graphql(skip)]
+    pub account_uuid: Option<Uuid>,
+    #[graphql(skip)]
+    pub refresh_token_hash: Option<Vec<u8>>,
+    pub revoke_time: Option<DateTime<Utc>>,
+}
+
+#[derive(SimpleObject)]
+#[graphql(complex)]
+pub struct Account {
+    #[graphql(skip)]
+    pub uuid: Uuid,
+    pub email: String,
+    pub full_name: String,
+    pub capability: TokenCapability,
+    #[graphql(skip)]
+    pub user_uuid: Option<Uuid>,
+    #[graphql(skip)]
+    pub password_hash: Option<String>,
+    pub disabled: bool,
+    pub create_time: DateTime<Utc>,
+    pub last_login_time: Option<DateTime<Utc>>,
+}
+#[ComplexObject]
+impl Account {
+    async fn uuid(&self) -> String {
+        self.uuid.to_hyphenated().to_string()
+    }
+    // The member a MEMBER account is
+    async fn user_uuid(&self) -> Option<String> {
+        self.user_uuid
+            .map(|user_uuid| user_uuid.to_hyphenated().to_string())
+    }
+    // They can't log in without one
+    async fn has_password(&self) -> bool {
+        self.password_hash.is_some()
+    }
+}
+
+// What logging in or generateRefreshToken gives out
+#[derive(SimpleObject)]
+pub struct TokenPair {
+    // Goes in the Token header, like any other token. Get a new one with refreshToken before
+    // it expires.
+    pub access_token: String,
+    pub access_token_expiration_time: DateTime<Utc>,
+    // Keep this somewhere safe. It's only given out once, and each one only works once.
+    pub refresh_token: String,
+    pub refresh_token_expiration_time: DateTime<Utc>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -103,6 +1218,9 @@ pub struct JWTClaims {
     pub exp: i64,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub nbf: Option<i64>,
+    // The user a Member token belongs to. Missing from every other kind of token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
     // We will want to validate these pieces of data in the JWT **and** in the database
 }
 
@@ -139,14 +1257,42 @@ impl Guard for CapabilityGuard {
     }
 }
 
+// For fields that act on one excuse. Members only get their own, and administrators get any.
+pub struct ExcuseOwnerGuard {
+    pub id: i32,
+}
+
+#[async_trait::async_trait]
+impl Guard for ExcuseOwnerGuard {
+    async fn check(&self, ctx: &Context<'_>) -> Result<()> {
+        if ctx.data_opt::<TokenCapability>() == Some(&TokenCapability::Administrator) {
+            return Ok(());
+        }
+        let user_uuid = match ctx
+            .data_opt::<JWTClaims>()
+            .and_then(|claims| claims.user.as_ref())
+        {
+            Some(user) => Uuid::parse_str(user)?,
+            None => return Err(ACCESS_DENIED_MESSAGE.into()),
+        };
+
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        match sqlx::query!("SELECT user_uuid FROM excuses WHERE id=$1", self.id)
+            .fetch_optional(&**pool)
+            .await?
+        {
+            Some(excuse) if excuse.user_uuid == user_uuid => Ok(()),
+            // Someone else's looks the same as one that doesn't exist
+            _ => Err(ACCESS_DENIED_MESSAGE.into()),
+        }
+    }
+}
+
 pub struct PhoneNumber;
 impl InputValueValidator for PhoneNumber {
     fn is_valid(&self, value: &Value) -> Result<(), String> {
         if let Value::String(value_unwrap) = value {
-            match phonenumber::parse(Some(phonenumber::country::Id::US), value_unwrap) {
-                Ok(_) => return Ok(()),
-                Err(e) => return Err(format!("Phone number validation failed: error {}", e)),
-            }
+            return normalize_phone_number(value_unwrap).map(|_| ());
         } else if let Value::Null = value {
             // That's okay since this field is nullable and
             // it'll just go in as null.
@@ -156,12 +1302,215 @@ impl InputValueValidator for PhoneNumber {
             return Ok(());
         }
 
-        Err(format!(
-            "Phone number validation failed. Invalid 'value' provided."
-        ))
+        Err("Phone number validation failed. Invalid 'value' provided.".to_string())
+    }
+}
+
+// Parses the number like the validator does and returns it in E.164 (+15555555555),
+// which is how we store phone numbers
+pub fn normalize_phone_number(phone_number: &str) -> Result<String, String> {
+    let default_region = *DEFAULT_PHONE_REGION.read().unwrap();
+    match phonenumber::parse(Some(default_region), phone_number) {
+        Ok(parsed) => Ok(parsed.format().mode(phonenumber::Mode::E164).to_string()),
+        Err(e) => Err(format!("Phone number validation failed: error {}", e)),
+    }
+}
+
+// `attendance-rs normalize-phone-numbers [--apply]` converts numbers stored before the server
+// normalized them. Anything the parser can't make sense of is left alone and reported, rather
+// than guessing at a country code. Webhooks are suppressed so the conversion doesn't announce
+// every member as updated.
+pub async fn normalize_stored_phone_numbers(pool: &PgPool, args: &[String]) -> Result<(), String> {
+    let mut apply = false;
+    for arg in args {
+        match arg.as_str() {
+            "--apply" => apply = true,
+            _ => {
+                return Err(format!(
+                "Unexpected argument {}\nUsage: attendance-rs normalize-phone-numbers [--apply]",
+                arg
+            ))
+            }
+        }
+    }
+
+    let db_error = |e: sqlx::Error| format!("Database error: {}", e);
+    let mut tx = pool.begin().await.map_err(db_error)?;
+    sqlx::query!("SELECT set_config('attendance_rs.suppress_webhooks', 'on', true)")
+        .fetch_one(&mut tx)
+        .await
+        .map_err(db_error)?;
+
+    let stored = sqlx::query!(
+        r#"SELECT uuid, full_name, phone_number AS "phone_number!" FROM users
+        WHERE phone_number IS NOT NULL AND phone_number !~ '^\+[0-9]{8,15}$'
+        ORDER BY full_name FOR UPDATE"#
+    )
+    .fetch_all(&mut tx)
+    .await
+    .map_err(db_error)?;
+
+    let mut converted = 0;
+    let mut unparseable = 0;
+    for user in stored {
+        match normalize_phone_number(&user.phone_number) {
+            Ok(phone_number) => {
+                println!(
+                    "{}  {}: {} -> {}",
+                    user.uuid, user.full_name, user.phone_number, phone_number
+                );
+                sqlx::query!(
+                    "UPDATE users SET phone_number=$1 WHERE uuid=$2",
+                    phone_number,
+                    user.uuid
+                )
+                .execute(&mut tx)
+                .await
+                .map_err(db_error)?;
+                converted += 1;
+            }
+            Err(_) => {
+                println!(
+                    "{}  {}: {} could not be converted",
+                    user.uuid, user.full_name, user.phone_number
+                );
+                unparseable += 1;
+            }
+        }
+    }
+    println!(
+        "\n{} to convert, {} left as they are",
+        converted, unparseable
+    );
+
+    if apply {
+        tx.commit().await.map_err(db_error)?;
+        println!("Phone numbers converted.");
+    } else {
+        tx.rollback().await.map_err(db_error)?;
+        println!("Dry run, nothing was written. Run again with --apply to convert.");
+    }
+    Ok(())
+}
+
+fn format_phone_number(phone_number: &Option<String>, mode: phonenumber::Mode) -> Option<String> {
+    let phone_number = phone_number.as_ref()?;
+    let default_region = *DEFAULT_PHONE_REGION.read().unwrap();
+    // Anything that doesn't parse (old data, mostly) is shown as-is
+    match phonenumber::parse(Some(default_region), phone_number) {
+        Ok(parsed) => Some(parsed.format().mode(mode).to_string()),
+        Err(_) => Some(phone_number.clone()),
     }
 }
 
 // TODO custom async_graphql implementation for uuid
 #[ComplexObject]
-impl Token {}
+impl Token {
+    async fn uuid(&self) -> String {
+        self.uuid.to_hyphenated().to_string()
+    }
+    // The member a MEMBER token belongs to
+    async fn user_uuid(&self) -> Option<String> {
+        self.user_uuid
+            .map(|user_uuid| user_uuid.to_hyphenated().to_string())
+    }
+    // Set for tokens from logging in
+    async fn account_uuid(&self) -> Option<String> {
+        self.account_uuid
+            .map(|account_uuid| account_uuid.to_hyphenated().to_string())
+    }
+    // Whether it was given out as a refresh token, which can be revoked. Other tokens work
+    // until they expire.
+    async fn refreshable(&self) -> bool {
+        self.refresh_token_hash.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alt_id_type(format: AltIdFormat, pattern: Option<&str>, case: AltIdCase) -> AltIdType {
+        AltIdType {
+            name: "rfid".to_string(),
+            description: String::new(),
+            format,
+            pattern: pattern.map(String::from),
+            case_normalization: case,
+            is_unique: false,
+            create_time: Utc::now(),
+        }
+    }
+
+    // Format, pattern, case, value, and what it normalizes to (None if it's rejected)
+    type NormalizeCase<'a> = (
+        AltIdFormat,
+        Option<&'a str>,
+        AltIdCase,
+        &'a str,
+        Option<&'a str>,
+    );
+
+    #[test]
+    fn normalize_alt_ids() {
+        use AltIdCase::*;
+        use AltIdFormat::*;
+
+        let cases: &[NormalizeCase] = &[
+            (Any, None, Preserve, "  Ab c-1 ", Some("Ab c-1")),
+            (Any, None, Lower, "ABC", Some("abc")),
+            (Any, None, Upper, " abc\n", Some("ABC")),
+            (Any, None, Preserve, "   ", None),
+            (Numeric, None, Preserve, "0123", Some("0123")),
+            (Numeric, None, Preserve, "12a", None),
+            (Numeric, None, Preserve, "-12", None),
+            (Hexadecimal, None, Upper, "04a1ff", Some("04A1FF")),
+            (Hexadecimal, None, Preserve, "04g1", None),
+            (Alphanumeric, None, Preserve, "Ab12", Some("Ab12")),
+            (Alphanumeric, None, Preserve, "ab 12", None),
+            (Alphanumeric, None, Preserve, "ab_12", None),
+            // The pattern is checked after the case is changed, and has to match all of it
+            (
+                Hexadecimal,
+                Some("[0-9A-F]{8}"),
+                Upper,
+                "deadbeef",
+                Some("DEADBEEF"),
+            ),
+            (Hexadecimal, Some("[0-9A-F]{8}"), Upper, "deadbeef00", None),
+            (Any, Some("a|b"), Preserve, "ab", None),
+            (Any, Some("a|b"), Preserve, "b", Some("b")),
+        ];
+        for (format, pattern, case, value, normalized) in cases {
+            let alt_id_type = alt_id_type(*format, *pattern, *case);
+            assert_eq!(
+                alt_id_type.normalize(value).ok().as_deref(),
+                *normalized,
+                "{:?} {:?} {:?} {:?}",
+                format,
+                pattern,
+                case,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn normalize_alt_id_errors() {
+        let numeric = alt_id_type(AltIdFormat::Numeric, None, AltIdCase::Preserve);
+        assert_eq!(
+            numeric.normalize(" "),
+            Err("A value for alt ID field 'rfid' cannot be empty".to_string())
+        );
+        assert_eq!(
+            numeric.normalize("x"),
+            Err("The value for alt ID field 'rfid' is not in the Numeric format".to_string())
+        );
+
+        let bad_pattern = alt_id_type(AltIdFormat::Any, Some("("), AltIdCase::Preserve);
+        assert!(bad_pattern
+            .normalize("x")
+            .unwrap_err()
+            .starts_with("Invalid alt ID pattern ("));
+    }
+}
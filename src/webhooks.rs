@@ -0,0 +1,326 @@
+// Sends the webhook outbox (webhook_deliveries). Triggers in the database fill it in; this signs
+// each delivery, POSTs it, and retries with backoff until it goes through or we give up.
+
+use crate::tables::WebhookDeliveryStatus;
+use actix_web::client::Client;
+use futures::future::join_all;
+use hmac::{Hmac, Mac};
+use log::{error, warn};
+use sha2::Sha256;
+use sqlx::{
+    postgres::PgPool,
+    types::chrono::{DateTime, Utc},
+};
+use std::{sync::Arc, time::Duration};
+
+// How often we look for deliveries that are due, when there weren't any last time
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const BATCH_SIZE: i64 = 20;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Deliveries being sent are pushed this many seconds into the future, so another server (or
+// this one, after a crash) doesn't pick them up at the same time
+const LEASE_SECONDS: i32 = 300;
+// Retries wait 30 seconds, then twice as long each time. Eight attempts covers about an hour.
+const MAX_ATTEMPTS: i32 = 8;
+const FIRST_RETRY_SECONDS: i64 = 30;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// The X-Attendance-Signature header is sha256= followed by this. Receivers should compute it
+// themselves and compare. Signing the timestamp too means an old delivery can't be replayed.
+pub fn sign(secret: &str, timestamp: &str, body: &str) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// Runs for as long as the server does
+pub async fn deliver_webhooks(pool: Arc<PgPool>) {
+    let client = Client::builder().timeout(REQUEST_TIMEOUT).finish();
+
+    loop {
+        match deliver_due(&client, &pool).await {
+            // There could be more waiting
+            Ok(sent) if sent > 0 => continue,
+            Ok(_) => {}
+            Err(e) => error!("Could not send webhooks: {}", e),
+        }
+        actix_web::rt::time::delay_for(POLL_INTERVAL).await;
+    }
+}
+
+struct DueDelivery {
+    id: i64,
+    event_type: String,
+    payload: serde_json::Value,
+    attempts: i32,
+    url: String,
+    secret: String,
+}
+
+async fn deliver_due(client: &Client, pool: &PgPool) -> Result<usize, sqlx::Error> {
+    let due = sqlx::query_as!(
+        DueDelivery,
+        "UPDATE webhook_deliveries d SET next_attempt_time = now() + $1::int * interval '1 second'
+        FROM webhooks w
+        WHERE w.id = d.webhook_id AND d.id IN (
+            SELECT id FROM webhook_deliveries WHERE status = 'pending' AND next_attempt_time <= now()
+            ORDER BY id LIMIT $2 FOR UPDATE SKIP LOCKED
+        )
+        RETURNING d.id, d.event_type, d.payload, d.attempts, w.url, w.secret",
+        LEASE_SECONDS,
+        BATCH_SIZE
+    )
+    .fetch_all(pool)
+    .await?;
+    let count = due.len();
+
+    for result in join_all(
+        due.into_iter()
+            .map(|delivery| attempt_delivery(client, pool, delivery)),
+    )
+    .await
+    {
+        result?;
+    }
+
+    Ok(count)
+}
+
+async fn attempt_delivery(
+    client: &Client,
+    pool: &PgPool,
+    delivery: DueDelivery,
+) -> Result<(), sqlx::Error> {
+    let (response_status, error) = send_delivery(client, &delivery).await;
+    let attempts = delivery.attempts + 1;
+
+    match error {
+        None => {
+            sqlx::query!(
+                "UPDATE webhook_deliveries SET (status, attempts, last_attempt_time, last_response_status, last_error, delivered_time)
+                = ($1, $2, now(), $3, NULL, now()) WHERE id=$4",
+                WebhookDeliveryStatus::Delivered as WebhookDeliveryStatus,
+                attempts,
+                response_status,
+                delivery.id
+            )
+            .execute(pool)
+            .await?;
+        }
+        Some(error) => {
+            warn!(
+                "Webhook delivery {} to {} failed on attempt {}: {}",
+                delivery.id, delivery.url, attempts, error
+            );
+            let (status, next_attempt_time) = after_failed_attempt(attempts);
+
+            sqlx::query!(
+                "UPDATE webhook_deliveries SET (status, attempts, last_attempt_time, last_response_status, last_error, next_attempt_time)
+                = ($1, $2, now(), $3, $4, $5) WHERE id=$6",
+                status as WebhookDeliveryStatus,
+                attempts,
+                response_status,
+                error,
+                next_attempt_time,
+                delivery.id
+            )
+            .execute(pool)
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+// POSTs one delivery. Returns the receiver's status code (if it answered) and what went wrong,
+// if anything did.
+async fn send_delivery(client: &Client, delivery: &DueDelivery) -> (Option<i32>, Option<String>) {
+    let body = delivery.payload.to_string();
+    let timestamp = Utc::now().timestamp().to_string();
+    let signature = sign(&delivery.secret, &timestamp, &body);
+
+    match client
+        .post(&delivery.url)
+        .header("Content-Type", "application/json")
+        .header("X-Attendance-Event", delivery.event_type.as_str())
+        .header("X-Attendance-Delivery", delivery.id.to_string())
+        .header("X-Attendance-Timestamp", timestamp.as_str())
+        .header("X-Attendance-Signature", format!("sha256={}", signature))
+        .send_body(body)
+        .await
+    {
+        Ok(response) if response.status().is_success() => {
+            (Some(i32::from(response.status().as_u16())), None)
+        }
+        Ok(response) => (
+            Some(i32::from(response.status().as_u16())),
+            Some(format!("The receiver answered {}", response.status())),
+        ),
+        Err(e) => (None, Some(e.to_string())),
+    }
+}
+
+// Whether to try again after a failure, and when
+fn after_failed_attempt(attempts: i32) -> (WebhookDeliveryStatus, DateTime<Utc>) {
+    if attempts >= MAX_ATTEMPTS {
+        (WebhookDeliveryStatus::Failed, Utc::now())
+    } else {
+        (WebhookDeliveryStatus::Pending, retry_time(attempts))
+    }
+}
+
+fn retry_time(attempts: i32) -> DateTime<Utc> {
+    Utc::now() + chrono::Duration::seconds(FIRST_RETRY_SECONDS << (attempts - 1).min(16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App, HttpRequest, HttpResponse};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn delivery(url: String) -> DueDelivery {
+        DueDelivery {
+            id: 1,
+            event_type: "ping".to_string(),
+            payload: serde_json::json!({ "event": "ping" }),
+            attempts: 0,
+            url,
+            secret: "It's a Secret to Everybody".to_string(),
+        }
+    }
+
+    struct Receiver {
+        failures: usize,
+        hits: Arc<AtomicUsize>,
+    }
+
+    // Answers 500 until it has been called `failures` times, then 204. Anything that isn't
+    // signed properly gets a 401.
+    async fn receive(
+        receiver: web::Data<Receiver>,
+        request: HttpRequest,
+        body: String,
+    ) -> HttpResponse {
+        let header = |name| request.headers().get(name).unwrap().to_str().unwrap();
+        let expected = format!(
+            "sha256={}",
+            sign(
+                "It's a Secret to Everybody",
+                header("X-Attendance-Timestamp"),
+                &body
+            )
+        );
+        if header("X-Attendance-Signature") != expected {
+            return HttpResponse::Unauthorized().finish();
+        }
+        if receiver.hits.fetch_add(1, Ordering::SeqCst) < receiver.failures {
+            HttpResponse::InternalServerError().finish()
+        } else {
+            HttpResponse::NoContent().finish()
+        }
+    }
+
+    fn receiver(failures: usize, hits: Arc<AtomicUsize>) -> test::TestServer {
+        test::start(move || {
+            App::new()
+                .data(Receiver {
+                    failures,
+                    hits: Arc::clone(&hits),
+                })
+                .route("/", web::post().to(receive))
+        })
+    }
+
+    #[test]
+    fn sign_known_vector() {
+        assert_eq!(
+            sign("It's a Secret to Everybody", "1700000000", "Hello, World!"),
+            "76c83fd0acdf22faed320674fe8e04d528cfe8a17905e720a9611e40677c03b7"
+        );
+        // The timestamp is part of what's signed
+        assert_ne!(
+            sign("It's a Secret to Everybody", "1700000001", "Hello, World!"),
+            sign("It's a Secret to Everybody", "1700000000", "Hello, World!")
+        );
+    }
+
+    #[test]
+    fn retries_back_off_then_give_up() {
+        let (status, next) = after_failed_attempt(1);
+        assert_eq!(status, WebhookDeliveryStatus::Pending);
+        assert!(
+            (next - Utc::now() - chrono::Duration::seconds(30))
+                .num_seconds()
+                .abs()
+                <= 1
+        );
+
+        let (_, next) = after_failed_attempt(3);
+        assert!(
+            (next - Utc::now() - chrono::Duration::seconds(120))
+                .num_seconds()
+                .abs()
+                <= 1
+        );
+
+        assert_eq!(
+            after_failed_attempt(MAX_ATTEMPTS).0,
+            WebhookDeliveryStatus::Failed
+        );
+    }
+
+    #[actix_rt::test]
+    async fn delivers_on_2xx() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let server = receiver(0, Arc::clone(&hits));
+
+        let (status, error) = send_delivery(&Client::default(), &delivery(server.url("/"))).await;
+        assert_eq!((status, error), (Some(204), None));
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[actix_rt::test]
+    async fn retries_after_5xx() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let server = receiver(1, Arc::clone(&hits));
+        let client = Client::default();
+        let mut delivery = delivery(server.url("/"));
+
+        let (status, error) = send_delivery(&client, &delivery).await;
+        assert_eq!(status, Some(500));
+        assert!(error.is_some());
+        delivery.attempts += 1;
+        assert_eq!(
+            after_failed_attempt(delivery.attempts).0,
+            WebhookDeliveryStatus::Pending
+        );
+
+        assert_eq!(send_delivery(&client, &delivery).await, (Some(204), None));
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_rt::test]
+    async fn gives_up_after_max_attempts() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let server = receiver(usize::MAX, Arc::clone(&hits));
+        let client = Client::default();
+        let mut delivery = delivery(server.url("/"));
+
+        loop {
+            let (status, error) = send_delivery(&client, &delivery).await;
+            assert_eq!(status, Some(500));
+            assert!(error.is_some());
+            delivery.attempts += 1;
+            if after_failed_attempt(delivery.attempts).0 == WebhookDeliveryStatus::Failed {
+                break;
+            }
+        }
+        assert_eq!(delivery.attempts, MAX_ATTEMPTS);
+        assert_eq!(hits.load(Ordering::SeqCst), MAX_ATTEMPTS as usize);
+    }
+}